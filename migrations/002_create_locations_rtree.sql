@@ -0,0 +1,29 @@
+-- Spatial index over locations, kept in sync with triggers
+CREATE VIRTUAL TABLE if not exists locations_rtree USING rtree(
+    id,
+    min_lat,        max_lat,
+    min_lon,        max_lon
+);
+
+INSERT INTO locations_rtree
+                (id, min_lat, max_lat, min_lon, max_lon)
+        SELECT   id, lat, lat, lon, lon FROM locations;
+
+CREATE TRIGGER if not exists locations_rtree_insert AFTER INSERT ON locations
+BEGIN
+    INSERT INTO locations_rtree
+                    (id, min_lat, max_lat, min_lon, max_lon)
+            VALUES  (new.id, new.lat, new.lat, new.lon, new.lon);
+END;
+
+CREATE TRIGGER if not exists locations_rtree_update AFTER UPDATE OF lat, lon ON locations
+BEGIN
+    UPDATE locations_rtree
+            SET min_lat=new.lat, max_lat=new.lat, min_lon=new.lon, max_lon=new.lon
+            WHERE id=new.id;
+END;
+
+CREATE TRIGGER if not exists locations_rtree_delete AFTER DELETE ON locations
+BEGIN
+    DELETE FROM locations_rtree WHERE id=old.id;
+END;
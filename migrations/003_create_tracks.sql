@@ -0,0 +1,18 @@
+CREATE TABLE if not exists tracks (
+    id              INTEGER PRIMARY KEY NOT NULL,
+    label           TEXT NOT NULL,
+    kind            TEXT NOT NULL,
+    owner_id        INTEGER NOT NULL
+);
+
+CREATE TABLE if not exists track_points (
+    id              INTEGER PRIMARY KEY NOT NULL,
+    track_id        INTEGER NOT NULL,
+    seq             INTEGER NOT NULL,
+    lat             REAL NOT NULL,
+    lon             REAL NOT NULL,
+    ele             REAL,
+    time            REAL
+);
+
+CREATE INDEX if not exists track_points_track ON track_points (track_id, seq);
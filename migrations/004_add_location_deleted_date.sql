@@ -0,0 +1,2 @@
+-- Soft-deleted locations keep their row (and files/comments) but are hidden, -1 if not deleted
+ALTER TABLE locations ADD COLUMN deleted_date REAL NOT NULL DEFAULT -1;
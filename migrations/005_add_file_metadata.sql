@@ -0,0 +1,7 @@
+-- Metadata read from a photo's EXIF on upload, NULL when not present
+ALTER TABLE files ADD COLUMN taken_date REAL;
+ALTER TABLE files ADD COLUMN camera_model TEXT;
+ALTER TABLE files ADD COLUMN orientation INTEGER;
+ALTER TABLE files ADD COLUMN gps_lat REAL;
+ALTER TABLE files ADD COLUMN gps_lon REAL;
+ALTER TABLE files ADD COLUMN gps_alt REAL;
@@ -0,0 +1,3 @@
+-- Filenames of the resized copies of an image, NULL until generated
+ALTER TABLE files ADD COLUMN thumb_256 TEXT;
+ALTER TABLE files ADD COLUMN thumb_1024 TEXT;
@@ -0,0 +1,13 @@
+-- Server-side login sessions, the identity cookie only holds the token
+CREATE TABLE if not exists sessions (
+    id              INTEGER PRIMARY KEY NOT NULL,
+    token_hash      TEXT NOT NULL,
+    user_id         INTEGER NOT NULL,
+    created_date    REAL NOT NULL,
+    last_seen_date  REAL NOT NULL,
+    ip              TEXT NOT NULL,
+    user_agent      TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX if not exists sessions_token_hash ON sessions (token_hash);
+CREATE INDEX if not exists sessions_user ON sessions (user_id);
@@ -0,0 +1,9 @@
+-- Single-use codes to get back in without the TOTP device, only hashes are kept
+CREATE TABLE if not exists recovery_codes (
+    id              INTEGER PRIMARY KEY NOT NULL,
+    user_id         INTEGER NOT NULL,
+    code_hash       TEXT NOT NULL,
+    used_date       REAL NOT NULL DEFAULT -1
+);
+
+CREATE INDEX if not exists recovery_codes_user ON recovery_codes (user_id);
@@ -0,0 +1,2 @@
+-- Last TOTP time step a user logged in with, a code is only accepted for a later step
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT -1;
@@ -0,0 +1,7 @@
+-- Failed login attempts per ip/account, only kept when the server persists lockouts
+CREATE TABLE if not exists login_attempts (
+    key                 TEXT PRIMARY KEY NOT NULL,
+    failures            INTEGER NOT NULL,
+    last_failure_date   INTEGER NOT NULL,
+    locked_until        INTEGER NOT NULL
+);
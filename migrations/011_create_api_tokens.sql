@@ -0,0 +1,15 @@
+-- Personal API tokens, sent as "Authorization: Bearer <token>"
+-- scopes is a comma separated list of permissions, expires_date is -1 for never
+CREATE TABLE if not exists api_tokens (
+    id              INTEGER PRIMARY KEY NOT NULL,
+    user_id         INTEGER NOT NULL,
+    name            TEXT NOT NULL,
+    token_hash      TEXT NOT NULL,
+    scopes          TEXT NOT NULL,
+    created_date    REAL NOT NULL,
+    expires_date    REAL NOT NULL,
+    last_used_date  REAL NOT NULL DEFAULT -1
+);
+
+CREATE UNIQUE INDEX if not exists api_tokens_token_hash ON api_tokens (token_hash);
+CREATE INDEX if not exists api_tokens_user ON api_tokens (user_id);
@@ -0,0 +1,46 @@
+-- One row per permission a group has, replacing the free-text user_groups.permissions
+CREATE TABLE if not exists group_permissions (
+    group_id        INTEGER NOT NULL,
+    permission      TEXT NOT NULL,
+    PRIMARY KEY (group_id, permission)
+);
+
+-- Split the old comma/space separated permissions into rows
+-- Unknown permissions are dropped, canComment was never checked and becomes addComment + editComment
+WITH RECURSIVE split(group_id, permission, rest) AS (
+    SELECT id, '', replace(permissions, ' ', ',') || ',' FROM user_groups
+    UNION ALL
+    SELECT  group_id,
+            substr(rest, 1, instr(rest, ',') - 1),
+            substr(rest, instr(rest, ',') + 1)
+    FROM split
+    WHERE rest <> ''
+),
+permission_map(old, new) AS (
+    VALUES  ('*', '*'),
+            ('canComment', 'addComment'),
+            ('canComment', 'editComment'),
+            ('addLocation', 'addLocation'),
+            ('editLocation', 'editLocation'),
+            ('editOtherLocation', 'editOtherLocation'),
+            ('deleteLocation', 'deleteLocation'),
+            ('deleteOtherLocation', 'deleteOtherLocation'),
+            ('addComment', 'addComment'),
+            ('editComment', 'editComment'),
+            ('editOtherComment', 'editOtherComment'),
+            ('saveFile', 'saveFile')
+)
+INSERT OR IGNORE INTO group_permissions (group_id, permission)
+    SELECT split.group_id, permission_map.new
+    FROM split
+    INNER JOIN permission_map ON permission_map.old=split.permission;
+
+-- SQLite can't drop a column on older versions, so the table is rebuilt without it
+CREATE TABLE user_groups_new (
+    id              INTEGER PRIMARY KEY NOT NULL, 
+    group_name      TEXT NOT NULL
+);
+
+INSERT INTO user_groups_new (id, group_name) SELECT id, group_name FROM user_groups;
+DROP TABLE user_groups;
+ALTER TABLE user_groups_new RENAME TO user_groups;
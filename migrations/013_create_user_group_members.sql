@@ -0,0 +1,21 @@
+-- Users can be in any number of groups, replacing users.group_id
+CREATE TABLE if not exists user_group_members (
+    user_id         INTEGER NOT NULL,
+    group_id        INTEGER NOT NULL,
+    PRIMARY KEY (user_id, group_id)
+);
+
+CREATE INDEX if not exists user_group_members_group ON user_group_members (group_id);
+
+INSERT OR IGNORE INTO user_group_members (user_id, group_id)
+    SELECT users.id, users.group_id
+    FROM users
+    INNER JOIN user_groups ON user_groups.id=users.group_id;
+
+-- Per-user overrides on top of the groups, allow is 1 for a grant and 0 for a deny
+CREATE TABLE if not exists user_permissions (
+    user_id         INTEGER NOT NULL,
+    permission      TEXT NOT NULL,
+    allow           INTEGER NOT NULL,
+    PRIMARY KEY (user_id, permission)
+);
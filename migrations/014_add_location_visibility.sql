@@ -0,0 +1,16 @@
+-- Who can see a location (and its files and comments):
+-- private: only the owner, group: also the users/groups in location_shares,
+-- link: also anyone with share_token, public: everyone
+-- Existing locations were world-readable, so they stay public
+ALTER TABLE locations ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public';
+ALTER TABLE locations ADD COLUMN share_token TEXT;
+
+-- A share is with either a user or a group, the other id is -1
+CREATE TABLE if not exists location_shares (
+    id              INTEGER PRIMARY KEY NOT NULL,
+    location_id     INTEGER NOT NULL,
+    user_id         INTEGER NOT NULL DEFAULT -1,
+    group_id        INTEGER NOT NULL DEFAULT -1
+);
+
+CREATE INDEX if not exists location_shares_location ON location_shares (location_id);
@@ -0,0 +1,2 @@
+-- Disabled users can't log in or use their API tokens, but keep their account and content
+ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use clap::ArgMatches;
+use serde_json::json;
+
+use crate::cli::{CLICommands, CliResult};
+
+impl CLICommands {
+    pub(super) async fn run_db_command(&self, args: &ArgMatches) -> CliResult {
+        match args.subcommand() {
+            Some(("migrate", _)) => self.migrate_db().await,
+            Some(("backup", args)) => self.backup_db(args.value_of("FILE").unwrap()).await,
+            Some(("check", _)) => self.check_db().await,
+            _ => Ok(()),
+        }
+    }
+
+    // Opening the db runs any new migrations
+    async fn migrate_db(&self) -> CliResult {
+        let db = self.open_db().await?;
+        let migrations = db.get_applied_migrations().await?;
+
+        let mut text = format!("'{}' is up to date, applied migrations:", self.settings.db_path);
+
+        for migration in &migrations {
+            text.push_str(&format!("\n{:03} {} ({})", migration.version, migration.description, migration.installed_on));
+        }
+
+        self.output(&migrations, &text);
+        Ok(())
+    }
+
+    async fn backup_db(&self, filename: &str) -> CliResult {
+        if Path::new(filename).exists() {
+            return Err(format!("'{}' already exists", filename).into());
+        }
+
+        let db = self.open_db().await?;
+        db.backup(filename).await?;
+
+        self.output(
+            &json!({ "backup": filename }),
+            &format!("Backed up '{}' to '{}'", self.settings.db_path, filename),
+        );
+        Ok(())
+    }
+
+    async fn check_db(&self) -> CliResult {
+        let db = self.open_db().await?;
+        let problems = db.check_integrity().await?;
+
+        if !problems.is_empty() {
+            return Err(format!("'{}' failed the integrity check:\n{}", self.settings.db_path, problems.join("\n")).into());
+        }
+
+        self.output(&json!({ "ok": true }), &format!("'{}' passed the integrity check", self.settings.db_path));
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use clap::ArgMatches;
+use serde_json::json;
+
+use crate::cli::{CLICommands, CliResult};
+use crate::db::MapDB;
+use crate::db::permissions::Permission;
+
+impl CLICommands {
+    pub(super) async fn run_group_command(&self, args: &ArgMatches) -> CliResult {
+        match args.subcommand() {
+            Some(("add", args)) => self.add_user_group(args).await,
+            Some(("edit", args)) => self.edit_user_group(args).await,
+            Some(("delete", args)) => self.delete_user_group(args).await,
+            Some(("list", _)) => self.list_user_groups().await,
+            Some(("grant", args)) => self.set_group_permission(args, true).await,
+            Some(("revoke", args)) => self.set_group_permission(args, false).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn add_user_group(&self, args: &ArgMatches) -> CliResult {
+        let group_name = args.value_of("GROUP").unwrap();
+        let permissions = Permission::parse_list(args.value_of("permissions").unwrap())?;
+        let db = self.open_db().await?;
+
+        if db.is_user_group(group_name).await? {
+            return Err(format!("'{}' is already a group!", group_name).into());
+        }
+
+        let group_id = db.add_user_group(group_name, &permissions).await?;
+
+        self.output(
+            &json!({ "id": group_id, "group": group_name, "permissions": permissions }),
+            &format!("Added group '{}'", group_name),
+        );
+        Ok(())
+    }
+
+    async fn edit_user_group(&self, args: &ArgMatches) -> CliResult {
+        let group_name = args.value_of("GROUP").unwrap();
+        let permissions = Permission::parse_list(args.value_of("permissions").unwrap())?;
+        let db = self.open_db().await?;
+
+        if !db.is_user_group(group_name).await? {
+            return Err(format!("'{}' must already be a group to edit it.", group_name).into());
+        }
+
+        db.edit_user_group(group_name, &permissions).await?;
+
+        self.output(
+            &json!({ "group": group_name, "permissions": permissions }),
+            &format!("Edited group '{}', new permissions: {}", group_name, Permission::join(&permissions)),
+        );
+        Ok(())
+    }
+
+    async fn delete_user_group(&self, args: &ArgMatches) -> CliResult {
+        let group_name = args.value_of("GROUP").unwrap();
+
+        if MapDB::is_default_user_group(group_name) {
+            return Err(format!("'{}' is a default group and can't be deleted", group_name).into());
+        }
+
+        let db = self.open_db().await?;
+        let group_id = db.get_user_group_id(group_name).await?;
+
+        db.delete_user_group(group_id).await?;
+
+        self.output(&json!({ "group": group_name }), &format!("Deleted group '{}'", group_name));
+        Ok(())
+    }
+
+    async fn list_user_groups(&self) -> CliResult {
+        let db = self.open_db().await?;
+        let groups = db.get_all_user_groups().await?;
+
+        let mut text = String::from("All groups:");
+
+        for group in &groups {
+            text.push_str(&format!("\n{}: Permissions: '{}'", group.group_name, Permission::join(&group.permissions)));
+        }
+
+        self.output(&groups, &text);
+        Ok(())
+    }
+
+    // Grants (or revokes) a single permission of a group
+    async fn set_group_permission(&self, args: &ArgMatches, grant: bool) -> CliResult {
+        let group_name = args.value_of("GROUP").unwrap();
+        let permission = args.value_of("PERMISSION").unwrap().parse::<Permission>()?;
+        let db = self.open_db().await?;
+        let group_id = db.get_user_group_id(group_name).await?;
+
+        let text = if grant {
+            db.grant_group_permission(group_id, permission).await?;
+            format!("Granted '{}' to group '{}'", permission, group_name)
+        } else if db.revoke_group_permission(group_id, permission).await? {
+            format!("Revoked '{}' from group '{}'", permission, group_name)
+        } else {
+            return Err(format!("Group '{}' does not have '{}'", group_name, permission).into());
+        };
+
+        self.output(&json!({ "group": group_name, "permission": permission, "granted": grant }), &text);
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use clap::ArgMatches;
+use serde_json::json;
+
+use crate::cli::{CLICommands, CliResult};
+use crate::db::locations::Viewer;
+use crate::formats;
+
+impl CLICommands {
+    pub(super) async fn import_file(&self, args: &ArgMatches) -> CliResult {
+        let format = args.value_of("FORMAT").unwrap();
+        let filename = args.value_of("FILE").unwrap();
+        let username = args.value_of("user").unwrap();
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        let contents = std::fs::read(filename)
+            .map_err(|e| format!("Could not read '{}': {}", filename, e))?;
+        let data = formats::parse_import(format, &contents)?;
+
+        let visibility = args.value_of("visibility").unwrap();
+        let report = formats::import(&db, &data, user_id, visibility, args.is_present("dry-run")).await?;
+
+        if self.json {
+            self.output(&report, "");
+        } else {
+            report.print();
+        }
+        Ok(())
+    }
+
+    pub(super) async fn export_file(&self, args: &ArgMatches) -> CliResult {
+        let format = args.value_of("FORMAT").unwrap();
+        let filename = args.value_of("FILE").unwrap();
+        let db = self.open_db().await?;
+
+        let contents = formats::export(format, &db, &Viewer::all()).await?;
+
+        std::fs::write(filename, contents)
+            .map_err(|e| format!("Could not write '{}': {}", filename, e))?;
+
+        self.output(&json!({ "format": format, "file": filename }), &format!("Exported to '{}'", filename));
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use clap::{App, AppSettings, Arg, ArgMatches};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::db::MapDB;
+use crate::db::locations::NEW_VISIBILITIES;
+use crate::db::error::DbError;
+use crate::media::s3::S3Store;
+use crate::media::store::SharedMediaStore;
+use crate::settings::Settings;
+use crate::web_srv::APIServer;
+use crate::web_srv::cookies;
+
+mod database;
+mod groups;
+mod import_export;
+mod users;
+
+// Flags of serve that override the setting of the same meaning, see settings.rs
+const SETTING_FLAGS: [(&str, &str); 10] = [
+    ("address", "address"),
+    ("port", "port"),
+    ("media-dir", "media_dir"),
+    ("s3-endpoint", "s3_endpoint"),
+    ("s3-bucket", "s3_bucket"),
+    ("s3-region", "s3_region"),
+    ("cookie-key-file", "cookie_key_file"),
+    ("totp-window", "totp_window"),
+    ("max-file-size", "max_file_size_mb"),
+    ("max-request-size", "max_request_size_mb"),
+];
+
+// Why a command failed, printed as "Error: <message>", or {"error": <message>} with --json
+#[derive(Debug)]
+pub struct CliError(String);
+
+pub type CliResult<T = ()> = Result<T, CliError>;
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for CliError {
+    fn from(e: String) -> CliError {
+        CliError(e)
+    }
+}
+
+impl From<&str> for CliError {
+    fn from(e: &str) -> CliError {
+        CliError(e.to_string())
+    }
+}
+
+impl From<DbError> for CliError {
+    fn from(e: DbError) -> CliError {
+        CliError(e.to_string())
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> CliError {
+        CliError(e.to_string())
+    }
+}
+
+pub struct CLICommands {
+    settings:   Settings,
+    json:       bool, // Machine readable output, for scripting
+}
+
+// Positional argument of a subcommand, ie. the USERNAME of "user add <USERNAME>"
+fn positional(name: &'static str, index: usize) -> Arg<'static> {
+    Arg::new(name)
+        .index(index)
+        .required(true)
+        .value_name(name)
+}
+
+impl CLICommands {
+    pub fn cli_arg_parse() -> ArgMatches {
+        // Parse and return CLI arguments
+        CLICommands::app().get_matches()
+    }
+
+    fn app() -> App<'static> {
+        App::new("mymap")
+            .version("0.0.1")
+            .author("James Danielson")
+            .about("MyMap server and administration. Runs the server when no command is given.")
+            .setting(AppSettings::PropagateVersion)
+            .arg(
+                Arg::new("config")
+                    .long("config")
+                    .global(true)
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("Settings file (Default: ./mymap.toml if it exists, or MYMAP_CONFIG). \
+                           Any setting can also be set with a MYMAP_<NAME> env var"),
+            )
+            .arg(
+                Arg::new("set")
+                    .long("set")
+                    .global(true)
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_name("NAME=VALUE")
+                    .help("Overrides a setting from the settings file or env, ie. --set db_path=./map.sqlite"),
+            )
+            .arg(
+                Arg::new("print-config")
+                    .long("print-config")
+                    .global(true)
+                    .takes_value(false)
+                    .help("Prints the effective settings and where each value came from, instead of running the command"),
+            )
+            .arg(
+                Arg::new("json")
+                    .long("json")
+                    .global(true)
+                    .takes_value(false)
+                    .help("Prints results (and errors) as json, for scripting"),
+            )
+            .subcommand(CLICommands::serve_app())
+            .subcommand(
+                App::new("user")
+                    .about("Manages users")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(App::new("add")
+                        .about("Adds a user, asks for their password and shows their TOTP QR code")
+                        .arg(positional("USERNAME", 1))
+                        .args(CLICommands::password_args())
+                        .arg(CLICommands::totp_qr_file_arg()))
+                    .subcommand(App::new("list")
+                        .about("Lists all users")
+                        .arg(Arg::new("guests")
+                            .long("guests")
+                            .takes_value(false)
+                            .help("Only lists guest accounts")))
+                    .subcommand(App::new("delete")
+                        .about("Deletes a user, refused while they own anything unless it is reassigned")
+                        .arg(positional("USERNAME", 1))
+                        .arg(Arg::new("reassign-to")
+                            .long("reassign-to")
+                            .takes_value(true)
+                            .value_name("USERNAME")
+                            .help("Gives the user's locations, files, comments and tracks to this user")))
+                    .subcommand(App::new("set-password")
+                        .about("Replaces a user's password, asks for the new one and ends their sessions and API tokens")
+                        .arg(positional("USERNAME", 1))
+                        .args(CLICommands::password_args()))
+                    .subcommand(App::new("reset-totp")
+                        .about("Gives a user a new TOTP secret and recovery codes, ending their sessions and API tokens")
+                        .arg(positional("USERNAME", 1))
+                        .arg(CLICommands::totp_qr_file_arg()))
+                    .subcommand(App::new("disable")
+                        .about("Stops a user from logging in or using API tokens, ending their sessions")
+                        .arg(positional("USERNAME", 1)))
+                    .subcommand(App::new("enable")
+                        .about("Lets a disabled user log in again")
+                        .arg(positional("USERNAME", 1)))
+                    .subcommand(App::new("revoke-sessions")
+                        .about("Logs a user out everywhere by revoking all of their sessions")
+                        .arg(positional("USERNAME", 1)))
+                    .subcommand(App::new("add-to-group")
+                        .about("Adds a user to a group")
+                        .arg(positional("USERNAME", 1))
+                        .arg(positional("GROUP", 2)))
+                    .subcommand(App::new("remove-from-group")
+                        .about("Removes a user from a group")
+                        .arg(positional("USERNAME", 1))
+                        .arg(positional("GROUP", 2)))
+                    .subcommand(App::new("grant")
+                        .about("Grants a permission, ie. addLocation, to just this user")
+                        .arg(positional("USERNAME", 1))
+                        .arg(positional("PERMISSION", 2)))
+                    .subcommand(App::new("deny")
+                        .about("Denies a user a permission even if their groups have it")
+                        .arg(positional("USERNAME", 1))
+                        .arg(positional("PERMISSION", 2)))
+                    .subcommand(App::new("clear")
+                        .about("Clears a user's grant or deny of a permission")
+                        .arg(positional("USERNAME", 1))
+                        .arg(positional("PERMISSION", 2))),
+            )
+            .subcommand(
+                App::new("group")
+                    .about("Manages groups and their permissions")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(App::new("add")
+                        .about("Adds a group")
+                        .arg(positional("GROUP", 1))
+                        .arg(CLICommands::permissions_arg()))
+                    .subcommand(App::new("edit")
+                        .about("Replaces a group's permissions")
+                        .arg(positional("GROUP", 1))
+                        .arg(CLICommands::permissions_arg()))
+                    .subcommand(App::new("delete")
+                        .about("Deletes a group, members left without a group become guests")
+                        .arg(positional("GROUP", 1)))
+                    .subcommand(App::new("list")
+                        .about("Lists all groups"))
+                    .subcommand(App::new("grant")
+                        .about("Grants a group a permission, ie. addLocation")
+                        .arg(positional("GROUP", 1))
+                        .arg(positional("PERMISSION", 2)))
+                    .subcommand(App::new("revoke")
+                        .about("Revokes a permission of a group")
+                        .arg(positional("GROUP", 1))
+                        .arg(positional("PERMISSION", 2))),
+            )
+            .subcommand(
+                App::new("db")
+                    .about("Database maintenance")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(App::new("migrate")
+                        .about("Runs any new migrations and lists the applied ones"))
+                    .subcommand(App::new("backup")
+                        .about("Writes a copy of the database, safe while the server is running")
+                        .arg(positional("FILE", 1)))
+                    .subcommand(App::new("check")
+                        .about("Checks the database for corruption")),
+            )
+            .subcommand(
+                App::new("lockouts")
+                    .about("Failed logins and lockouts, only kept between restarts with persist_lockouts")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(App::new("list")
+                        .about("Lists failed logins and lockouts"))
+                    .subcommand(App::new("clear")
+                        .about("Clears all failed logins and lockouts, or only those of --user")
+                        .arg(Arg::new("user")
+                            .long("user")
+                            .takes_value(true)
+                            .value_name("USERNAME"))),
+            )
+            .subcommand(
+                App::new("import")
+                    .about("Imports GeoJSON points, GPX waypoints/tracks or KML placemarks as locations and tracks")
+                    .arg(positional("FORMAT", 1).possible_values(["geojson", "gpx", "kml"]))
+                    .arg(positional("FILE", 2))
+                    .arg(Arg::new("user")
+                        .long("user")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("USERNAME")
+                        .help("Owner of the imported locations and tracks"))
+                    .arg(Arg::new("visibility")
+                        .long("visibility")
+                        .takes_value(true)
+                        .possible_values(NEW_VISIBILITIES)
+                        .default_value("private")
+                        .help("Who can see the imported locations"))
+                    .arg(Arg::new("dry-run")
+                        .long("dry-run")
+                        .takes_value(false)
+                        .help("Reports what the import would do without saving anything")),
+            )
+            .subcommand(
+                App::new("export")
+                    .about("Exports all locations (and for gpx and kml all tracks) to a file")
+                    .arg(positional("FORMAT", 1).possible_values(["geojson", "gpx", "kml"]))
+                    .arg(positional("FILE", 2)),
+            )
+            .subcommand(
+                App::new("rotate-cookie-key")
+                    .about("Adds a new cookie signing key, previous keys are still accepted until rotated out"),
+            )
+    }
+
+    fn permissions_arg() -> Arg<'static> {
+        Arg::new("permissions")
+            .long("permissions")
+            .takes_value(true)
+            .required(true)
+            .help("Comma separated permissions, ie. addLocation,saveFile or *")
+    }
+
+    // Where a new password comes from instead of asking on the terminal, for scripting
+    fn password_args() -> [Arg<'static>; 2] {
+        [
+            Arg::new("password-stdin")
+                .long("password-stdin")
+                .takes_value(false)
+                .conflicts_with("password-file")
+                .help("Reads the password from the first line of stdin"),
+            Arg::new("password-file")
+                .long("password-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Reads the password from the first line of FILE"),
+        ]
+    }
+
+    fn totp_qr_file_arg() -> Arg<'static> {
+        Arg::new("totp-qr-file")
+            .long("totp-qr-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Saves the TOTP QR code as a PNG, instead of showing it in the terminal")
+    }
+
+    fn serve_app() -> App<'static> {
+        App::new("serve")
+            .about("Runs the server")
+            .arg(
+                Arg::new("address")
+                    .short('a')
+                    .long("addr")
+                    .takes_value(true)
+                    .help("Address to listen on (Default: 0.0.0.0)"),
+            )
+            .arg(
+                Arg::new("port")
+                    .short('p')
+                    .long("port")
+                    .takes_value(true)
+                    .help("Port to listen on (Default: 8080)"),
+            )
+            .arg(
+                Arg::new("no-auth-api")
+                    .long("no-auth-api")
+                    .takes_value(false)
+                    .help("Disables all authentication and all write access."),
+            )
+            .arg(
+                Arg::new("max-file-size")
+                    .long("max-file-size")
+                    .takes_value(true)
+                    .value_name("MB")
+                    .help("Largest file that can be uploaded, in megabytes (Default: 25)"),
+            )
+            .arg(
+                Arg::new("max-request-size")
+                    .long("max-request-size")
+                    .takes_value(true)
+                    .value_name("MB")
+                    .help("Largest total upload in one request, in megabytes (Default: 100)"),
+            )
+            .arg(
+                Arg::new("media-dir")
+                    .long("media-dir")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("Directory uploads are kept in (Default: ./media)"),
+            )
+            .arg(
+                Arg::new("s3-endpoint")
+                    .long("s3-endpoint")
+                    .takes_value(true)
+                    .value_name("URL")
+                    .help("Keep uploads in an S3-compatible bucket instead, ie. http://localhost:9000. \
+                           Requires the s3_bucket, s3_access_key and s3_secret_key settings"),
+            )
+            .arg(
+                Arg::new("s3-bucket")
+                    .long("s3-bucket")
+                    .takes_value(true)
+                    .help("Bucket to keep uploads in"),
+            )
+            .arg(
+                Arg::new("s3-region")
+                    .long("s3-region")
+                    .takes_value(true)
+                    .help("Region of the bucket (Default: us-east-1)"),
+            )
+            .arg(
+                Arg::new("cookie-key-file")
+                    .long("cookie-key-file")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("File with the cookie signing keys, generated if missing (Default: ./cookie_keys)"),
+            )
+            .arg(
+                Arg::new("totp-window")
+                    .long("totp-window")
+                    .takes_value(true)
+                    .value_name("STEPS")
+                    .help("TOTP codes are accepted this many 30s steps before or after now, for clock skew (Default: 1)"),
+            )
+            .arg(
+                Arg::new("persist-lockouts")
+                    .long("persist-lockouts")
+                    .takes_value(false)
+                    .help("Keeps failed logins and lockouts in the database so they survive restarts"),
+            )
+    }
+
+    // Runs the command in args, returns false if it failed
+    pub async fn cli_run(args: ArgMatches) -> bool {
+        let json = args.is_present("json");
+
+        let result = match CLICommands::settings_from_args(&args) {
+            Ok(settings) => {
+                let commands = CLICommands { settings, json };
+                commands.run_command(&args).await
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                if json {
+                    println!("{}", json!({ "error": e.0 }));
+                } else {
+                    println!("Error: {}", e);
+                }
+                false
+            }
+        }
+    }
+
+    // Loads the settings, with the serve flags and any --set overriding the settings file and env
+    fn settings_from_args(args: &ArgMatches) -> CliResult<Settings> {
+        let mut overrides = Vec::new();
+
+        if let Some(serve_args) = args.subcommand_matches("serve") {
+            for (flag, name) in SETTING_FLAGS.iter() {
+                if let Some(value) = serve_args.value_of(flag) {
+                    overrides.push((name.to_string(), value.to_string()));
+                }
+            }
+
+            if serve_args.is_present("persist-lockouts") {
+                overrides.push(("persist_lockouts".to_string(), "true".to_string()));
+            }
+
+            if serve_args.is_present("no-auth-api") {
+                overrides.push(("auth_api".to_string(), "false".to_string()));
+            }
+        }
+
+        for value in args.values_of("set").into_iter().flatten() {
+            match value.split_once('=') {
+                Some((name, value)) => overrides.push((name.trim().to_string(), value.to_string())),
+                None => return Err(format!("--set expects NAME=VALUE, got '{}'", value).into()),
+            }
+        }
+
+        Ok(Settings::load(args.value_of("config"), &overrides)?)
+    }
+
+    async fn run_command(&self, args: &ArgMatches) -> CliResult {
+        if args.is_present("print-config") {
+            self.settings.print();
+            return Ok(());
+        }
+
+        match args.subcommand() {
+            Some(("user", args)) => self.run_user_command(args).await,
+            Some(("group", args)) => self.run_group_command(args).await,
+            Some(("db", args)) => self.run_db_command(args).await,
+            Some(("lockouts", args)) => self.run_lockouts_command(args).await,
+            Some(("import", args)) => self.import_file(args).await,
+            Some(("export", args)) => self.export_file(args).await,
+            Some(("rotate-cookie-key", _)) => self.rotate_cookie_key(),
+            _ => self.serve().await,
+        }
+    }
+
+    // Prints value as json with --json, otherwise text for people
+    fn output<T: Serialize>(&self, value: &T, text: &str) {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    async fn open_db(&self) -> CliResult<MapDB> {
+        Ok(MapDB::new(&self.settings).await?)
+    }
+
+    async fn serve(&self) -> CliResult {
+        let mut server = APIServer::new(self.settings.clone()).await;
+
+        if let Some(media_store) = self.media_store_from_settings()? {
+            server.set_media_store(media_store);
+        }
+
+        server.launch_server().await?;
+        Ok(())
+    }
+
+    // Builds the media store picked by the s3_endpoint setting, None for a LocalStore in the media_dir setting
+    fn media_store_from_settings(&self) -> CliResult<Option<SharedMediaStore>> {
+        let settings = &self.settings;
+
+        if settings.s3_endpoint.is_empty() {
+            return Ok(None);
+        }
+
+        for (name, value) in [("s3_bucket", &settings.s3_bucket), ("s3_access_key", &settings.s3_access_key), ("s3_secret_key", &settings.s3_secret_key)] {
+            if value.is_empty() {
+                return Err(format!("s3_endpoint is set but {} is not", name).into());
+            }
+        }
+
+        Ok(Some(Arc::new(S3Store::new(
+            &settings.s3_endpoint,
+            &settings.s3_bucket,
+            &settings.s3_region,
+            &settings.s3_access_key,
+            &settings.s3_secret_key,
+        ))))
+    }
+
+    fn rotate_cookie_key(&self) -> CliResult {
+        let key_file = &self.settings.cookie_key_file;
+
+        cookies::rotate_key_file(key_file)
+            .map_err(|e| format!("Could not rotate cookie key in '{}': {}", key_file, e))?;
+
+        self.output(
+            &json!({ "key_file": key_file }),
+            &format!("Rotated cookie key in '{}', restart the server to use it", key_file),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use std::io;
+
+use chrono::Utc;
+use clap::ArgMatches;
+use qrcode::QrCode;
+use qrcode::render::unicode;
+use serde_json::json;
+
+use crate::cli::{CLICommands, CliResult};
+use crate::db::MapDB;
+use crate::db::permissions::Permission;
+use crate::db::users::{TotpSetup, UserInfo};
+use crate::web_srv::LoginLimiter;
+
+// ie. "james, groups: 'editors,photographers' (grants: 'saveFile', denies: '')"
+fn describe_user(user: &UserInfo) -> String {
+    let group_names: Vec<&str> = user.groups.iter().map(|group| group.group_name.as_str()).collect();
+
+    format!("{}, groups: '{}' (grants: '{}', denies: '{}'){}",
+        user.username,
+        group_names.join(","),
+        Permission::join(&user.grants),
+        Permission::join(&user.denies),
+        if user.disabled { ", disabled" } else { "" }
+    )
+}
+
+fn describe_recovery_codes(recovery_codes: &[String]) -> String {
+    let mut text = String::from("Recovery codes, each can be used once in place of a TOTP code:");
+
+    for code in recovery_codes {
+        text.push_str(&format!("\n  {}", code));
+    }
+
+    text
+}
+
+// Shows the TOTP QR code in the terminal, or with qr_file saves it there as a PNG instead
+// The provisioning URI is always shown too, for authenticator apps that can't scan
+fn describe_totp(totp: &TotpSetup, qr_file: Option<&str>) -> CliResult<String> {
+    let code = QrCode::new(totp.url.as_bytes()).map_err(|e| format!("Could not create the TOTP QR code: {}", e))?;
+
+    let qr = match qr_file {
+        Some(filename) => {
+            code.render::<image::Luma<u8>>()
+                .build()
+                .save(filename)
+                .map_err(|e| format!("Could not write '{}': {}", filename, e))?;
+
+            format!("TOTP QR code saved to '{}'", filename)
+        }
+        None => code.render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build(),
+    };
+
+    Ok(format!("Scan this TOTP QR code with an authenticator app, then verify it on next login:\n{}\nOr enter: {}", qr, totp.url))
+}
+
+// The password, without its line ending, from --password-stdin or --password-file
+fn first_line(input: &str) -> String {
+    input.lines().next().unwrap_or("").to_string()
+}
+
+impl CLICommands {
+    pub(super) async fn run_user_command(&self, args: &ArgMatches) -> CliResult {
+        match args.subcommand() {
+            Some(("add", args)) => self.add_user(args).await,
+            Some(("list", args)) => self.list_users(args).await,
+            Some(("delete", args)) => self.delete_user(args).await,
+            Some(("set-password", args)) => self.set_password(args).await,
+            Some(("reset-totp", args)) => self.reset_totp(args).await,
+            Some(("disable", args)) => self.set_user_disabled(args, true).await,
+            Some(("enable", args)) => self.set_user_disabled(args, false).await,
+            Some(("revoke-sessions", args)) => self.revoke_sessions(args).await,
+            Some(("add-to-group", args)) => self.add_user_to_group(args).await,
+            Some(("remove-from-group", args)) => self.remove_user_from_group(args).await,
+            Some(("grant", args)) => self.set_user_permission(args, Some(true)).await,
+            Some(("deny", args)) => self.set_user_permission(args, Some(false)).await,
+            Some(("clear", args)) => self.set_user_permission(args, None).await,
+            _ => Ok(()),
+        }
+    }
+
+    // Reads a new password from --password-file, --password-stdin, or else asks twice on the terminal
+    // without echoing it, then checks it against the password policy
+    fn read_password(db: &MapDB, args: &ArgMatches) -> CliResult<String> {
+        let password = if let Some(filename) = args.value_of("password-file") {
+            let contents = std::fs::read_to_string(filename)
+                .map_err(|e| format!("Could not read '{}': {}", filename, e))?;
+            first_line(&contents)
+        } else if args.is_present("password-stdin") {
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            first_line(&input)
+        } else if atty::is(atty::Stream::Stdin) {
+            let password = rpassword::read_password_from_tty(Some("Password: "))?;
+            let confirmation = rpassword::read_password_from_tty(Some("Confirm password: "))?;
+
+            if password != confirmation {
+                return Err("Passwords do not match".into());
+            }
+            password
+        } else {
+            return Err("Not a terminal to ask for a password on, use --password-stdin or --password-file".into());
+        };
+
+        db.password_policy.check(&password)?;
+        Ok(password)
+    }
+
+    async fn add_user(&self, args: &ArgMatches) -> CliResult {
+        // Adds username to db, asks for password from CLI
+        let username = args.value_of("USERNAME").unwrap();
+        let db = self.open_db().await?;
+
+        if db.is_user(username).await? {
+            return Err(format!("User '{}' already exists!", username).into());
+        }
+
+        let password = CLICommands::read_password(&db, args)?;
+        let (user_id, totp) = db.add_user(username, &password).await?;
+        let recovery_codes = db.add_recovery_codes(user_id).await?;
+        let totp_text = describe_totp(&totp, args.value_of("totp-qr-file"))?;
+
+        self.output(
+            &json!({ "id": user_id, "username": username, "totp_qr": totp.qr_code, "totp_url": totp.url, "recovery_codes": recovery_codes }),
+            &format!("User added: {}\n{}\n{}", username, totp_text, describe_recovery_codes(&recovery_codes)),
+        );
+        Ok(())
+    }
+
+    async fn list_users(&self, args: &ArgMatches) -> CliResult {
+        // List all users in the database, or only the guests
+        let guests = args.is_present("guests");
+        let db = self.open_db().await?;
+
+        let users: Vec<UserInfo> = db.get_all_users().await?
+            .into_iter()
+            .filter(|user| !guests || user.is_in_group("guest"))
+            .collect();
+
+        let mut text = String::from(if guests { "All guest users:" } else { "All users:" });
+
+        for user in &users {
+            text.push_str(&format!("\n{}", describe_user(user)));
+        }
+
+        self.output(&users, &text);
+        Ok(())
+    }
+
+    async fn delete_user(&self, args: &ArgMatches) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        match args.value_of("reassign-to") {
+            Some(reassign_to) => {
+                let reassign_to_id = db.get_user_id(reassign_to).await?;
+
+                if reassign_to_id == user_id {
+                    return Err("--reassign-to must be another user".into());
+                }
+
+                db.move_user_content(user_id, reassign_to_id).await?;
+            }
+            None => {
+                if db.get_user_content_count(user_id).await? > 0 {
+                    return Err(format!("User '{}' still owns locations, files, comments or tracks, use --reassign-to <USERNAME>", username).into());
+                }
+            }
+        }
+
+        db.delete_user(user_id).await?;
+        db.delete_login_attempts(&LoginLimiter::account_key(username)).await?;
+
+        self.output(
+            &json!({ "username": username, "reassigned_to": args.value_of("reassign-to") }),
+            &format!("Deleted user '{}'", username),
+        );
+        Ok(())
+    }
+
+    async fn set_password(&self, args: &ArgMatches) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        let password = CLICommands::read_password(&db, args)?;
+        db.set_user_password(user_id, &password).await?;
+        let count = db.delete_user_sessions(user_id).await?;
+        let token_count = db.delete_user_api_tokens(user_id).await?;
+
+        self.output(
+            &json!({ "username": username, "revoked_sessions": count, "revoked_api_tokens": token_count }),
+            &format!("Changed the password of '{}' and revoked {} session(s) and {} API token(s)", username, count, token_count),
+        );
+        Ok(())
+    }
+
+    async fn reset_totp(&self, args: &ArgMatches) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        db.delete_user_sessions(user_id).await?;
+        db.delete_user_api_tokens(user_id).await?;
+
+        let totp = db.reset_user_totp(user_id, username).await?;
+        let recovery_codes = db.add_recovery_codes(user_id).await?;
+        let totp_text = describe_totp(&totp, args.value_of("totp-qr-file"))?;
+
+        self.output(
+            &json!({ "username": username, "totp_qr": totp.qr_code, "totp_url": totp.url, "recovery_codes": recovery_codes }),
+            &format!("Reset TOTP of '{}'\n{}\n{}", username, totp_text, describe_recovery_codes(&recovery_codes)),
+        );
+        Ok(())
+    }
+
+    async fn set_user_disabled(&self, args: &ArgMatches, disabled: bool) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        db.set_user_disabled(user_id, disabled).await?;
+
+        self.output(
+            &json!({ "username": username, "disabled": disabled }),
+            &format!("{} user '{}'", if disabled { "Disabled" } else { "Enabled" }, username),
+        );
+        Ok(())
+    }
+
+    async fn revoke_sessions(&self, args: &ArgMatches) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        let count = db.delete_user_sessions(user_id).await?;
+
+        self.output(
+            &json!({ "username": username, "revoked_sessions": count }),
+            &format!("Revoked {} session(s) of '{}'", count, username),
+        );
+        Ok(())
+    }
+
+    async fn add_user_to_group(&self, args: &ArgMatches) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let group_name = args.value_of("GROUP").unwrap();
+        let db = self.open_db().await?;
+
+        let user_id = db.get_user_id(username).await?;
+        let group_id = db.get_user_group_id(group_name).await?;
+
+        db.add_user_to_group(user_id, group_id).await?;
+
+        self.output(
+            &json!({ "username": username, "group": group_name }),
+            &format!("User '{}' added to group '{}'", username, group_name),
+        );
+        Ok(())
+    }
+
+    async fn remove_user_from_group(&self, args: &ArgMatches) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let group_name = args.value_of("GROUP").unwrap();
+        let db = self.open_db().await?;
+
+        let user_id = db.get_user_id(username).await?;
+        let group_id = db.get_user_group_id(group_name).await?;
+
+        if !db.remove_user_from_group(user_id, group_id).await? {
+            return Err(format!("User '{}' is not in group '{}'", username, group_name).into());
+        }
+
+        self.output(
+            &json!({ "username": username, "group": group_name }),
+            &format!("User '{}' removed from group '{}'", username, group_name),
+        );
+        Ok(())
+    }
+
+    // Grants or denies a permission for just this user, or with allow None clears the override
+    async fn set_user_permission(&self, args: &ArgMatches, allow: Option<bool>) -> CliResult {
+        let username = args.value_of("USERNAME").unwrap();
+        let permission = args.value_of("PERMISSION").unwrap().parse::<Permission>()?;
+        let db = self.open_db().await?;
+        let user_id = db.get_user_id(username).await?;
+
+        let text = match allow {
+            Some(true) => {
+                db.set_user_permission(user_id, permission, true).await?;
+                format!("Granted '{}' to user '{}'", permission, username)
+            }
+            Some(false) => {
+                db.set_user_permission(user_id, permission, false).await?;
+                format!("Denied '{}' to user '{}'", permission, username)
+            }
+            None => {
+                if !db.clear_user_permission(user_id, permission).await? {
+                    return Err(format!("User '{}' has no grant or deny of '{}'", username, permission).into());
+                }
+                format!("Cleared '{}' of user '{}'", permission, username)
+            }
+        };
+
+        self.output(&json!({ "username": username, "permission": permission, "allow": allow }), &text);
+        Ok(())
+    }
+
+    pub(super) async fn run_lockouts_command(&self, args: &ArgMatches) -> CliResult {
+        match args.subcommand() {
+            Some(("list", _)) => self.list_lockouts().await,
+            Some(("clear", args)) => self.clear_lockouts(args.value_of("user")).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn list_lockouts(&self) -> CliResult {
+        let db = self.open_db().await?;
+        let now = Utc::now().timestamp();
+        let all_attempts = db.get_all_login_attempts().await?;
+
+        let mut text = String::from("Failed logins:");
+
+        for attempts in &all_attempts {
+            if attempts.locked_until > now {
+                text.push_str(&format!("\n{}: {} failures, locked for {}s", attempts.key, attempts.failures, attempts.locked_until - now));
+            } else {
+                text.push_str(&format!("\n{}: {} failures", attempts.key, attempts.failures));
+            }
+        }
+
+        self.output(&all_attempts, &text);
+        Ok(())
+    }
+
+    async fn clear_lockouts(&self, username: Option<&str>) -> CliResult {
+        let db = self.open_db().await?;
+
+        if let Some(username) = username {
+            if !db.delete_login_attempts(&LoginLimiter::account_key(username)).await? {
+                return Err(format!("'{}' has no failed logins", username).into());
+            }
+
+            self.output(&json!({ "cleared": 1 }), &format!("Cleared lockout of '{}'", username));
+            return Ok(());
+        }
+
+        let count = db.delete_all_login_attempts().await?;
+
+        self.output(&json!({ "cleared": count }), &format!("Cleared {} lockout(s)", count));
+        Ok(())
+    }
+}
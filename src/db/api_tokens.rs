@@ -0,0 +1,132 @@
+use serde::Serialize;
+use chrono::Utc;
+
+use crate::db::MapDB;
+use crate::db::crypto::DbCrypto;
+use crate::db::error::DbResult;
+use crate::db::permissions::Permission;
+
+// An API token, as listed to its user (never includes the token)
+#[derive(Serialize)]
+pub struct ApiTokenInfo {
+    pub id:             i64,
+    pub name:           String,
+    pub scopes:         Vec<Permission>,
+    pub created_date:   f64,
+    pub expires_date:   f64,
+    pub last_used_date: f64,
+}
+
+// Who a valid token belongs to and what it may do
+pub struct ApiTokenUser {
+    pub user_id:    i64,
+    pub username:   String,
+    pub scopes:     Vec<Permission>,
+}
+
+// Scopes no longer known to this version are skipped
+fn split_scopes(scopes: &str) -> Vec<Permission> {
+    scopes.split(',')
+        .filter_map(|scope| scope.parse::<Permission>().ok())
+        .collect()
+}
+
+impl MapDB {
+    // Adds a token for the user, expires_date is -1 for never
+    // Returns the id and the token to hand to the user, only a hash of it is stored
+    pub async fn add_api_token(&self, user_id: i64, name: &str, scopes: &[Permission], expires_date: i64) -> DbResult<(i64, String)> {
+        let token = DbCrypto::gen_token();
+
+        let id = sqlx::query("INSERT INTO api_tokens 
+                                    (user_id, name, token_hash, scopes, created_date, expires_date) 
+                            VALUES  (?, ?, ?, ?, ?, ?);")
+                .bind(user_id)
+                .bind(&name)
+                .bind(DbCrypto::hash_token(&token))
+                .bind(Permission::join(scopes))
+                .bind(Utc::now().timestamp())
+                .bind(expires_date)
+                .execute(&self.pool)
+                .await?
+                .last_insert_rowid();
+
+        Ok((id, token))
+    }
+
+    // Returns who the token belongs to, or None if it is unknown or expired
+    // Also records that the token was used
+    pub async fn get_api_token_user(&self, token: &str) -> DbResult<Option<ApiTokenUser>> {
+        let token_hash = DbCrypto::hash_token(token);
+        let now = Utc::now().timestamp();
+
+        let row: Option<(i64, i64, String, String)> = sqlx::query_as("SELECT api_tokens.id, users.id, users.username, api_tokens.scopes 
+                                            FROM api_tokens
+                                            INNER JOIN users ON users.id=api_tokens.user_id
+                                            WHERE api_tokens.token_hash=? AND users.disabled=0
+                                            AND (api_tokens.expires_date=-1 OR api_tokens.expires_date>?);")
+                .bind(&token_hash)
+                .bind(now)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        sqlx::query("UPDATE api_tokens 
+                            SET last_used_date=?
+                            WHERE id=?")
+                .bind(now)
+                .bind(row.0)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(Some(ApiTokenUser {
+            user_id:    row.1,
+            username:   row.2,
+            scopes:     split_scopes(&row.3),
+        }))
+    }
+
+    pub async fn get_user_api_tokens(&self, user_id: i64) -> DbResult<Vec<ApiTokenInfo>> {
+        let rows: Vec<(i64, String, String, f64, f64, f64)> =
+            sqlx::query_as("SELECT id, name, scopes, created_date, expires_date, last_used_date 
+                            FROM api_tokens
+                            WHERE user_id=?
+                            ORDER BY created_date DESC")
+                .bind(user_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter()
+            .map(|(id, name, scopes, created_date, expires_date, last_used_date)| ApiTokenInfo {
+                id,
+                name,
+                scopes: split_scopes(&scopes),
+                created_date,
+                expires_date,
+                last_used_date,
+            })
+            .collect())
+    }
+
+    // Revokes all of the user's tokens, ie. when their credentials are reset, returns how many
+    pub async fn delete_user_api_tokens(&self, user_id: i64) -> DbResult<u64> {
+        Ok(sqlx::query("DELETE FROM api_tokens WHERE user_id=?")
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected())
+    }
+
+    // Revokes one of the user's tokens, returns false if it is not theirs
+    pub async fn delete_user_api_token(&self, user_id: i64, token_id: i64) -> DbResult<bool> {
+        Ok(sqlx::query("DELETE FROM api_tokens WHERE id=? AND user_id=?")
+                .bind(token_id)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected() > 0)
+    }
+}
@@ -3,10 +3,13 @@ use serde::Serialize;
 use crate::db::MapDB;
 use chrono::Utc;
 
-use crate::db::users::UserInfo;
+use crate::db::error::{DbError, DbResult};
+
+use crate::db::locations::{visible_location_ids_sql, Viewer};
+use crate::db::users::PublicUserInfo;
 
 // Location Data stored in the locations table
-#[derive(Serialize)]
+#[derive(Serialize, sqlx::FromRow)]
 pub struct CommentData {
     pub id: i64,
     pub comment: String,
@@ -20,7 +23,7 @@ pub struct CommentData {
 
 #[derive(Serialize)]
 pub struct CommentDataForClient {
-    pub user: UserInfo,
+    pub user: PublicUserInfo,
     pub id: i64,
     pub comment: String,
     pub reply_to_id: i64,
@@ -30,22 +33,25 @@ pub struct CommentDataForClient {
 
 impl CommentData {
     // Data to return to web client
-    pub async fn for_client(&self, db: &MapDB) -> CommentDataForClient {
-        println!("owner: {}", self.owner_id);
-        CommentDataForClient {
-            user:           db.get_user_by_id(self.owner_id).await.expect("Bad owner_id on comment"),
+    pub async fn for_client(&self, db: &MapDB) -> DbResult<CommentDataForClient> {
+        let user = db.get_user_by_id(self.owner_id).await?
+            .ok_or_else(|| DbError::NotFound(format!("owner {} of comment {}", self.owner_id, self.id)))?
+            .public();
+
+        Ok(CommentDataForClient {
+            user,
             id:             self.id,
             comment:        self.comment.to_string(),
             reply_to_id:    self.reply_to_id,
             posted_date:    self.posted_date,
             last_edit_date: self.last_edit_date,
-        }
+        })
     }
 }
 
 impl MapDB {
-    pub async fn add_comment(&self, comment: &str, location_id: i64, file_id: i64, owner_id: i64) -> i64 {
-        sqlx::query("INSERT INTO comments 
+    pub async fn add_comment(&self, comment: &str, location_id: i64, file_id: i64, owner_id: i64) -> DbResult<i64> {
+        Ok(sqlx::query("INSERT INTO comments 
                                     (comment, location_id, file_id, owner_id, reply_to_id, posted_date, last_edit_date)  
                             VALUES  (?, ?, ?, ?, ?, ?, ?);")
                 .bind(&comment)
@@ -56,13 +62,12 @@ impl MapDB {
                 .bind(Utc::now().timestamp())
                 .bind(-1)
                 .execute(&self.pool)
-                .await
-                .expect("Inserting new comment into db")
-                .last_insert_rowid()
+                .await?
+                .last_insert_rowid())
     }
 
-    pub async fn add_reply(&self, comment: &str, location_id: i64, file_id: i64, owner_id: i64, reply_to_id: i64) -> i64 {
-        sqlx::query("INSERT INTO comments 
+    pub async fn add_reply(&self, comment: &str, location_id: i64, file_id: i64, owner_id: i64, reply_to_id: i64) -> DbResult<i64> {
+        Ok(sqlx::query("INSERT INTO comments 
                                     (comment, location_id, file_id, owner_id, reply_to_id, posted_date, last_edit_date)  
                             VALUES  (?, ?, ?, ?, ?, ?, ?);")
                 .bind(&comment)
@@ -73,12 +78,11 @@ impl MapDB {
                 .bind(Utc::now().timestamp())
                 .bind(-1)
                 .execute(&self.pool)
-                .await
-                .expect("Inserting new reply into db")
-                .last_insert_rowid()
+                .await?
+                .last_insert_rowid())
     }
 
-    pub async fn edit_comment(&self, comment_id: i64, comment: &str) {
+    pub async fn edit_comment(&self, comment_id: i64, comment: &str) -> DbResult<()> {
         sqlx::query("UPDATE comments 
                             SET comment=?, last_edit_date=?
                             WHERE id=?")
@@ -86,61 +90,80 @@ impl MapDB {
                 .bind(Utc::now().timestamp())
                 .bind(comment_id)
                 .execute(&self.pool)
-                .await
-                .expect("Updating comment in db");
+                .await?;
+        Ok(())
     }
 
-    async fn comment_data_for_client(&self, rows: &Vec<CommentData>) -> Vec<CommentDataForClient> {
+    async fn comment_data_for_client(&self, rows: &Vec<CommentData>) -> DbResult<Vec<CommentDataForClient>> {
         let mut comments = Vec::new();
 
         for i in 0..rows.len() {
-            comments.push(rows[i].for_client(self).await);
+            comments.push(rows[i].for_client(self).await?);
         }
 
-        comments
+        Ok(comments)
     }
 
-    pub async fn get_comments_on_location(&self, location_id: i64) -> Vec<CommentDataForClient> {
-        // Gets all top-level comments on this location
+    pub async fn get_comments_on_location(&self, location_id: i64, viewer: &Viewer) -> DbResult<Vec<CommentDataForClient>> {
+        // Gets all top-level comments on this location, if viewer can see it
+        let sql = format!("SELECT * FROM comments
+                           WHERE file_id=-1 AND location_id=? AND reply_to_id=-1
+                             AND location_id IN ({});", visible_location_ids_sql());
+
         self.comment_data_for_client(
-            &sqlx::query_as!(CommentData,
-                        "SELECT * FROM comments
-                         WHERE file_id=-1 AND location_id=? AND reply_to_id=-1;",
-                        location_id)
+            &viewer.bind(sqlx::query_as::<_, CommentData>(&sql).bind(location_id))
                     .fetch_all(&self.pool)
-                    .await.ok().expect("Getting comments on location")//.unwrap_or(Vec::new())
+                    .await?
         ).await
     }
 
-    pub async fn get_comments_on_file(&self, file_id: i64) -> Vec<CommentDataForClient> {
-        // Gets all top-level comments on this file
+    pub async fn get_comments_on_file(&self, file_id: i64, viewer: &Viewer) -> DbResult<Vec<CommentDataForClient>> {
+        // Gets all top-level comments on this file, if viewer can see its location
+        let sql = format!("SELECT * FROM comments
+                           WHERE file_id=? AND location_id=-1 AND reply_to_id=-1
+                             AND file_id IN (SELECT id FROM files WHERE location_id IN ({}));", visible_location_ids_sql());
+
         self.comment_data_for_client(
-            &sqlx::query_as!(CommentData,
-                        "SELECT * FROM comments
-                         WHERE file_id=? AND location_id=-1 AND reply_to_id=-1;",
-                        file_id)
+            &viewer.bind(sqlx::query_as::<_, CommentData>(&sql).bind(file_id))
                     .fetch_all(&self.pool)
-                    .await.ok().expect("Getting comments on file")//.unwrap_or(Vec::new())
+                    .await?
         ).await
     }
 
-    pub async fn get_comment(&self, comment_id: i64) -> Option<CommentData> {
-        sqlx::query_as!(CommentData,
+    pub async fn get_comment_count_on_location(&self, location_id: i64) -> DbResult<i64> {
+        // Counts all comments (including replies) on this location
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM comments
+                                            WHERE location_id=?;")
+                .bind(location_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(row.0)
+    }
+
+    pub async fn get_comment(&self, comment_id: i64) -> DbResult<Option<CommentData>> {
+        Ok(sqlx::query_as!(CommentData,
             "SELECT * FROM comments
              WHERE id=?;",
             comment_id)
-        .fetch_one(&self.pool)
-        .await.ok()
+        .fetch_optional(&self.pool)
+        .await?)
     }
 
-    pub async fn get_replies(&self, comment_id: i64) -> Vec<CommentDataForClient> {
+    pub async fn get_replies(&self, comment_id: i64, viewer: &Viewer) -> DbResult<Vec<CommentDataForClient>> {
+        // Replies are on the same location or file as what they reply to
+        let sql = format!("SELECT * FROM comments
+                           WHERE reply_to_id=?
+                             AND (location_id IN ({visible})
+                                  OR file_id IN (SELECT id FROM files WHERE location_id IN ({visible})));",
+                          visible = visible_location_ids_sql());
+
+        let query = viewer.bind(sqlx::query_as::<_, CommentData>(&sql).bind(comment_id));
+
         self.comment_data_for_client(
-            &sqlx::query_as!(CommentData,
-                        "SELECT * FROM comments
-                         WHERE reply_to_id=?;",
-                        comment_id)
+            &viewer.bind(query)
                     .fetch_all(&self.pool)
-                    .await.ok().expect("Getting replies")//.unwrap_or(Vec::new())
+                    .await?
         ).await
     }
 }
@@ -1,62 +1,161 @@
 use std::time::SystemTime;
+use argon2::password_hash::{rand_core::OsRng, SaltString};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use rand::Rng;
 use sha2::{Digest, Sha256};
 use totp_rs::{Algorithm, TOTP};
 
-// Default length of salt & totp secret
-const SALT_LENGTH: usize    = 32;
+// Default length of totp secret
 const SECRET_LENGTH: usize  = 16;
 
-// Would be nice to probably move some of this stuff to a settings.json
-const WEBSITE_URL: &str = "gekinzuku.github.io";
+// Seconds per totp code
+const TOTP_STEP: u64        = 30;
+
+// Random bytes in a token
+const TOKEN_LENGTH: usize   = 32;
+
+// Random bytes in a recovery code, shown as groups of 4 hex digits
+const RECOVERY_CODE_LENGTH: usize = 8;
 
 pub struct DbCrypto {}
 
 impl DbCrypto {
-    // Generates a random string used as a salt for hashing password
-    pub fn gen_rand_salt() -> String {
-        DbCrypto::gen_rand_string(SALT_LENGTH)
-    }
-
     // Generates a random string used as a totp secret for a user
     pub fn gen_rand_secret() -> String {
         DbCrypto::gen_rand_string(SECRET_LENGTH)
     }
 
+    // Generates a random hex token, used for sessions
+    pub fn gen_token() -> String {
+        let mut token = [0u8; TOKEN_LENGTH];
+        rand::thread_rng().fill(&mut token);
+        hex::encode(token)
+    }
+
+    // Tokens are random enough that a plain SHA-256 is fine to store them
+    pub fn hash_token(token: &str) -> String {
+        DbCrypto::get_sha256_hash(token)
+    }
+
+    // Generates a recovery code, ie. 1a2b-3c4d-5e6f-7a8b
+    pub fn gen_recovery_code() -> String {
+        let mut code = [0u8; RECOVERY_CODE_LENGTH];
+        rand::thread_rng().fill(&mut code);
+
+        code.chunks(2)
+            .map(|group| hex::encode(group))
+            .collect::<Vec<String>>()
+            .join("-")
+    }
+
+    // Recovery codes are hashed without dashes, spaces or case so they can be typed loosely
+    pub fn hash_recovery_code(code: &str) -> String {
+        let code: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+
+        DbCrypto::get_sha256_hash(&code)
+    }
+
     // Generates a Base64 encoding of a QR code containing
     // the totp secret and user/website information
-    pub fn gen_totp_qr(username: &str, totp_secret: &str) -> String {
-        let totp = TOTP::new(Algorithm::SHA1, 6, 1, 30, totp_secret);
+    pub fn gen_totp_qr(username: &str, totp_secret: &str, website_url: &str) -> String {
+        let totp = TOTP::new(Algorithm::SHA1, 6, 1, TOTP_STEP, totp_secret);
 
-        let label = format!("{}@{}", username, WEBSITE_URL);
-        let issuer = WEBSITE_URL;
+        let label = format!("{}@{}", username, website_url);
+        let issuer = website_url;
 
         totp.get_qr(&label, issuer).expect("creating qr code")
     }
 
-    // Checks if the provided totp_code matches for the secret
-    pub fn is_valid_totp(totp_secret: &str, totp_code: &str) -> bool {
-        let totp = TOTP::new(Algorithm::SHA1, 6, 1, 30, totp_secret);
-        let time = SystemTime::now()
+    // Generates the otpauth:// provisioning URI the QR code contains,
+    // for entering into an authenticator app by hand
+    pub fn gen_totp_url(username: &str, totp_secret: &str, website_url: &str) -> String {
+        let totp = TOTP::new(Algorithm::SHA1, 6, 1, TOTP_STEP, totp_secret);
+
+        let label = format!("{}@{}", username, website_url);
+        let issuer = website_url;
+
+        totp.get_url(&label, issuer)
+    }
+
+    // Returns the time step totp_code is valid for, checking window steps either side of now
+    // to allow for clock skew, or None if it does not match
+    pub fn get_totp_step(totp_secret: &str, totp_code: &str, window: u64) -> Option<i64> {
+        let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH).unwrap()
             .as_secs();
 
-        let token = totp.generate(time);
+        DbCrypto::get_totp_step_at(totp_secret, totp_code, window, now)
+    }
+
+    fn get_totp_step_at(totp_secret: &str, totp_code: &str, window: u64, now: u64) -> Option<i64> {
+        let now_step = now / TOTP_STEP;
+        let first_step = now_step.saturating_sub(window);
+
+        for step in first_step..=now_step + window {
+            let token = DbCrypto::gen_totp_code(totp_secret, step * TOTP_STEP);
+
+            if DbCrypto::constant_time_eq(token.as_bytes(), totp_code.as_bytes()) {
+                return Some(step as i64);
+            }
+        }
 
-        println!("TOTP secret: {}", totp_secret);
-        println!("Token is: {}", totp_code);
-        println!("Token should be: {}", token);
+        None
+    }
 
-        token.eq(totp_code)
+    // The code an authenticator app shows at time (unix seconds)
+    pub fn gen_totp_code(totp_secret: &str, time: u64) -> String {
+        TOTP::new(Algorithm::SHA1, 6, 1, TOTP_STEP, totp_secret).generate(time)
     }
 
-    // Generates hash of password + salt
-    pub fn password_to_hash(password: &str, salt: &str) -> String {
-        DbCrypto::get_sha256_hash(&(password.to_owned() + salt))
+    // Generates an Argon2id hash of password, in PHC format (salt and parameters included)
+    pub fn hash_password(password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("hashing password")
+            .to_string()
+    }
+
+    // Checks password against a stored hash, either Argon2id (PHC) or
+    // a legacy SHA-256 of password + salt
+    pub fn verify_password(password: &str, stored_hash: &str, salt: &str) -> bool {
+        if DbCrypto::is_legacy_hash(stored_hash) {
+            let hash = DbCrypto::legacy_password_to_hash(password, salt);
+            return DbCrypto::constant_time_eq(hash.as_bytes(), stored_hash.as_bytes());
+        }
+
+        match PasswordHash::new(stored_hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    // Is this hash from before Argon2id, and so should be replaced on next login?
+    pub fn is_legacy_hash(stored_hash: &str) -> bool {
+        !stored_hash.starts_with("$argon2")
     }
 
     // Private helpers:
 
+    // Legacy hash of password + salt, only used to verify old hashes
+    fn legacy_password_to_hash(password: &str, salt: &str) -> String {
+        DbCrypto::get_sha256_hash(&(password.to_owned() + salt))
+    }
+
+    // Compares without returning early, so timing does not leak how much matched
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+
+        a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
     // Generates a random string of size length
     fn gen_rand_string(length: usize) -> String {
         let mut rng = rand::thread_rng();
@@ -87,3 +186,48 @@ impl DbCrypto {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXP";
+    const NOW: u64 = 1_600_000_005; // A few seconds into a step
+
+    // Code for the step offset steps away from the one NOW is in
+    fn code_at(offset: i64) -> String {
+        DbCrypto::gen_totp_code(SECRET, (NOW as i64 + offset * TOTP_STEP as i64) as u64)
+    }
+
+    #[test]
+    fn codes_within_the_window_are_accepted() {
+        let now_step = (NOW / TOTP_STEP) as i64;
+
+        for window in 0..=2i64 {
+            for offset in -window..=window {
+                assert_eq!(DbCrypto::get_totp_step_at(SECRET, &code_at(offset), window as u64, NOW), Some(now_step + offset),
+                           "window {}, offset {}", window, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn codes_outside_the_window_are_rejected() {
+        for window in 0..=2i64 {
+            for offset in &[-window - 1, window + 1] {
+                assert_eq!(DbCrypto::get_totp_step_at(SECRET, &code_at(*offset), window as u64, NOW), None,
+                           "window {}, offset {}", window, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_codes_are_rejected() {
+        let code = code_at(0);
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+
+        assert_eq!(DbCrypto::get_totp_step_at(SECRET, &wrong, 1, NOW), None);
+        assert_eq!(DbCrypto::get_totp_step_at(SECRET, &code[..5], 1, NOW), None);
+        assert_eq!(DbCrypto::get_totp_step_at(SECRET, "", 1, NOW), None);
+    }
+}
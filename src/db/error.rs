@@ -0,0 +1,62 @@
+use sqlx::migrate::MigrateError;
+
+use std::fmt;
+
+// SQLITE_CONSTRAINT, the low byte of every constraint failure's extended result code
+const SQLITE_CONSTRAINT: i32 = 19;
+
+// Errors returned by MapDB methods instead of panicking
+#[derive(Debug)]
+pub enum DbError {
+    NotFound(String),               // What was looked up
+    ConstraintViolation(String),    // ie. a duplicate unique value
+    PoolTimeout,                    // No connection became free in time
+    Migration(String),
+    Other(String),                  // Anything else sqlx reports
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::NotFound(what) => write!(f, "{} not found", what),
+            DbError::ConstraintViolation(e) => write!(f, "constraint violation: {}", e),
+            DbError::PoolTimeout => write!(f, "timed out waiting for a db connection"),
+            DbError::Migration(e) => write!(f, "migration failed: {}", e),
+            DbError::Other(e) => write!(f, "db error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> DbError {
+        match e {
+            sqlx::Error::RowNotFound => DbError::NotFound("row".to_string()),
+            sqlx::Error::PoolTimedOut => DbError::PoolTimeout,
+            sqlx::Error::Database(db_error) => {
+                let is_constraint = db_error
+                    .code()
+                    .and_then(|code| code.parse::<i32>().ok())
+                    .map(|code| code & 0xff == SQLITE_CONSTRAINT)
+                    .unwrap_or(false);
+
+                if is_constraint {
+                    DbError::ConstraintViolation(db_error.message().to_string())
+                } else {
+                    DbError::Other(db_error.message().to_string())
+                }
+            }
+            sqlx::Error::Migrate(e) => DbError::Migration(e.to_string()),
+            e => DbError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<MigrateError> for DbError {
+    fn from(e: MigrateError) -> DbError {
+        DbError::Migration(e.to_string())
+    }
+}
@@ -1,8 +1,11 @@
 use serde::Serialize;
 
 use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::locations::{visible_location_ids_sql, Viewer};
+use crate::media::metadata::PhotoMetadata;
 
-#[derive(Serialize)]
+#[derive(Serialize, sqlx::FromRow)]
 pub struct FileInfo {
     pub id: i64,
     pub filename: String,
@@ -10,11 +13,19 @@ pub struct FileInfo {
     pub description: String,
     pub location_id: i64,
     pub owner_id: i64,
+    pub taken_date: Option<f64>,
+    pub camera_model: Option<String>,
+    pub orientation: Option<i64>,
+    pub gps_lat: Option<f64>,
+    pub gps_lon: Option<f64>,
+    pub gps_alt: Option<f64>,
+    pub thumb_256: Option<String>,
+    pub thumb_1024: Option<String>,
 }
 
 impl MapDB { 
-    pub async fn add_file(&self, location_id: i64, filename: &String, title: &String, description: &String, owner_id: i64) -> i64 {
-        sqlx::query("INSERT INTO files 
+    pub async fn add_file(&self, location_id: i64, filename: &String, title: &String, description: &String, owner_id: i64) -> DbResult<i64> {
+        Ok(sqlx::query("INSERT INTO files 
                                     (location_id, filename, title, description, owner_id)  
                             VALUES  (?, ?, ?, ?, ?);")
                 .bind(location_id)
@@ -23,17 +34,71 @@ impl MapDB {
                 .bind(&description)
                 .bind(owner_id)
                 .execute(&self.pool)
-                .await
-                .expect("Inserting new user into db")
-                .last_insert_rowid()
+                .await?
+                .last_insert_rowid())
     }
 
-    pub async fn get_location_filenames(&self, location_id: i64) -> Vec<String> {
+    // Removes the record of a file that could not be processed, the stored file is deleted by the caller
+    pub async fn delete_file(&self, file_id: i64) -> DbResult<()> {
+        sqlx::query("DELETE FROM files WHERE id=?")
+                .bind(file_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Saves the EXIF metadata read from an uploaded photo
+    pub async fn set_file_metadata(&self, file_id: i64, metadata: &PhotoMetadata) -> DbResult<()> {
+        sqlx::query("UPDATE files 
+                            SET taken_date=?, camera_model=?, orientation=?, gps_lat=?, gps_lon=?, gps_alt=?
+                            WHERE id=?")
+                .bind(metadata.taken_date)
+                .bind(&metadata.camera_model)
+                .bind(metadata.orientation)
+                .bind(metadata.gps_lat)
+                .bind(metadata.gps_lon)
+                .bind(metadata.gps_alt)
+                .bind(file_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Saves the names of the generated thumbnails of a file
+    pub async fn set_file_thumbnails(&self, file_id: i64, thumb_256: &str, thumb_1024: &str) -> DbResult<()> {
+        sqlx::query("UPDATE files 
+                            SET thumb_256=?, thumb_1024=?
+                            WHERE id=?")
+                .bind(&thumb_256)
+                .bind(&thumb_1024)
+                .bind(file_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Looks up a file by the uuid part of its filename, if viewer can see its location
+    pub async fn get_file_by_uuid(&self, uuid: &str, viewer: &Viewer) -> DbResult<Option<FileInfo>> {
+        let pattern = format!("{}.%", uuid);
+        let sql = format!("SELECT * FROM files
+                           WHERE filename LIKE ? AND location_id IN ({})", visible_location_ids_sql());
+
+        Ok(viewer.bind(sqlx::query_as::<_, FileInfo>(&sql).bind(pattern))
+                    .fetch_optional(&self.pool)
+                    .await?)
+    }
+
+    // Returns the filenames of a location's files, oldest photo first
+    // Empty if viewer cannot see the location
+    pub async fn get_location_filenames(&self, location_id: i64, viewer: &Viewer) -> DbResult<Vec<String>> {
+        let sql = format!("SELECT filename FROM files
+                           WHERE location_id=? AND location_id IN ({})
+                           ORDER BY taken_date IS NULL, taken_date, id", visible_location_ids_sql());
+
         let rows: Vec<(String,)> = 
-            sqlx::query_as("SELECT filename FROM files WHERE location_id=?")
-                .bind(location_id)
+            viewer.bind(sqlx::query_as(&sql).bind(location_id))
                 .fetch_all(&self.pool)
-                .await.ok().unwrap_or(Vec::new());
+                .await?;
 
         let mut filenames = Vec::new();
 
@@ -42,15 +107,44 @@ impl MapDB {
             filenames.push(rows[i].0.to_string());
         }
 
-        filenames
+        Ok(filenames)
+    }
+
+    // Returns the file by id, or None if it does not exist or viewer cannot see its location
+    pub async fn get_file_by_id(&self, file_id: i64, viewer: &Viewer) -> DbResult<Option<FileInfo>> {
+        let sql = format!("SELECT * FROM files
+                           WHERE id=? AND location_id IN ({})", visible_location_ids_sql());
+
+        Ok(viewer.bind(sqlx::query_as::<_, FileInfo>(&sql).bind(file_id))
+                    .fetch_optional(&self.pool)
+                    .await?)
+    }
+
+    // Returns the file, or None if it does not exist or viewer cannot see its location
+    pub async fn get_file(&self, filename: &String, viewer: &Viewer) -> DbResult<Option<FileInfo>> {
+        let sql = format!("SELECT * FROM files
+                           WHERE filename=? AND location_id IN ({})", visible_location_ids_sql());
+
+        Ok(viewer.bind(sqlx::query_as::<_, FileInfo>(&sql).bind(filename))
+                    .fetch_optional(&self.pool)
+                    .await?)
+    }
+
+    // Files still waiting for their thumbnails, ie. queued when the server stopped
+    pub async fn get_files_without_thumbnails(&self) -> DbResult<Vec<FileInfo>> {
+        Ok(sqlx::query_as::<_, FileInfo>("SELECT * FROM files
+                                            WHERE thumb_256 IS NULL OR thumb_1024 IS NULL")
+                    .fetch_all(&self.pool)
+                    .await?)
     }
 
-    pub async fn get_file(&self, filename: &String) -> FileInfo {
-        sqlx::query_as!(FileInfo,
-                        "SELECT * FROM files
-                         WHERE filename=?",
-                        filename)
-                    .fetch_one(&self.pool)
-                    .await.ok().expect(&format!("Could not find file '{}'", filename))
+    pub async fn get_location_file_count(&self, location_id: i64) -> DbResult<i64> {
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM files
+                                            WHERE location_id=?;")
+                .bind(location_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(row.0)
     }
 }
@@ -1,9 +1,38 @@
 use serde::Serialize;
+use sqlx::query::QueryAs;
+use sqlx::sqlite::{Sqlite, SqliteArguments};
+
+use chrono::Utc;
 
 use crate::db::MapDB;
+use crate::db::crypto::DbCrypto;
+use crate::db::error::{DbError, DbResult};
+
+// Mean earth radius, used for distance calculations
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+// Who can see a location, see migrations/014_add_location_visibility.sql
+pub const VISIBILITIES: [&str; 4] = ["private", "group", "link", "public"];
+
+// Visibilities a location can be created with, "link" is set afterwards as that hands out its share token
+pub const NEW_VISIBILITIES: [&str; 3] = ["private", "group", "public"];
+
+// Columns of LocationData
+const LOCATION_COLUMNS: &str = "locations.id, locations.label, locations.lat, locations.lon, 
+                                locations.kind, locations.owner_id, locations.visibility";
+
+// Condition on locations for the rows a Viewer can see, bind with Viewer::bind
+const VISIBLE_TO_VIEWER: &str = "(? OR locations.visibility='public'
+    OR (? <> -1 AND locations.owner_id=?)
+    OR (locations.visibility='link' AND locations.share_token=?)
+    OR (locations.visibility IN ('group', 'link') AND EXISTS (
+        SELECT 1 FROM location_shares
+        WHERE location_shares.location_id=locations.id
+          AND ((? <> -1 AND location_shares.user_id=?)
+               OR location_shares.group_id IN (SELECT group_id FROM user_group_members WHERE user_id=?)))))";
 
 // Location Data stored in the locations table
-#[derive(Serialize)]
+#[derive(Serialize, sqlx::FromRow)]
 pub struct LocationData {
     pub id: i64,
     pub label: String,
@@ -11,53 +40,286 @@ pub struct LocationData {
     pub lon: f32,
     pub kind: String,
     pub owner_id: i64,
+    pub visibility: String,
+}
+
+// Whoever is reading locations (and their files and comments)
+#[derive(Clone)]
+pub struct Viewer {
+    pub user_id:        i64, // -1 when not logged in
+    pub see_all:        bool,
+    pub share_token:    Option<String>,
+}
+
+impl Viewer {
+    // Not logged in, only sees public locations
+    pub fn public() -> Viewer {
+        Viewer {
+            user_id:        -1,
+            see_all:        false,
+            share_token:    None,
+        }
+    }
+
+    // Sees everything, for the CLI and background work
+    pub fn all() -> Viewer {
+        Viewer {
+            user_id:        -1,
+            see_all:        true,
+            share_token:    None,
+        }
+    }
+
+    pub fn user(user_id: i64, see_all: bool) -> Viewer {
+        Viewer {
+            user_id,
+            see_all,
+            share_token:    None,
+        }
+    }
+
+    // Also sees the "link" location with this share token
+    pub fn with_share_token(mut self, share_token: Option<String>) -> Viewer {
+        self.share_token = share_token;
+        self
+    }
+
+    // Binds the parameters of VISIBLE_TO_VIEWER, which must come after any other parameters
+    pub fn bind<'q, O>(&self, query: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+        query
+            .bind(self.see_all)
+            .bind(self.user_id)
+            .bind(self.user_id)
+            .bind(self.share_token.clone())
+            .bind(self.user_id)
+            .bind(self.user_id)
+            .bind(self.user_id)
+    }
+}
+
+// Ids of the (not deleted) locations a Viewer can see, bind with Viewer::bind
+pub fn visible_location_ids_sql() -> String {
+    format!("SELECT locations.id FROM locations WHERE locations.deleted_date=-1 AND {}", VISIBLE_TO_VIEWER)
+}
+
+// Visibility for a new location, default when none was asked for
+pub fn new_visibility<'a>(visibility: Option<&'a str>, default: &'a str) -> Result<&'a str, String> {
+    let visibility = visibility.unwrap_or(default);
+
+    if !NEW_VISIBILITIES.contains(&visibility) {
+        return Err("visibility must be private, group or public".to_string());
+    }
+
+    Ok(visibility)
+}
+
+// Finite and on the globe, NaN would otherwise slip past a plain range check
+pub fn is_valid_lat_lon(lat: f64, lon: f64) -> bool {
+    lat.is_finite() && lon.is_finite() && lat.abs() <= 90.0 && lon.abs() <= 180.0
+}
+
+// Great-circle distance in meters between two points (haversine)
+pub fn distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2)
+            + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
 }
 
 impl MapDB { 
     //pub async fn add_location(&self, location: &LocationData) -> i64 {
-    pub async fn add_location(&self, label: &String, lat: f64, lon: f64, kind: &String, owner_id: i64) -> i64 {
-        sqlx::query("INSERT INTO locations 
-                                    (label, lat, lon, kind, owner_id) 
-                            VALUES  (?, ?, ?, ?, ?);")
+    // The column defaults to public for locations from before visibility, new ones always set it
+    pub async fn add_location(&self, label: &String, lat: f64, lon: f64, kind: &String, visibility: &str, owner_id: i64) -> DbResult<i64> {
+        Ok(sqlx::query("INSERT INTO locations 
+                                    (label, lat, lon, kind, visibility, owner_id) 
+                            VALUES  (?, ?, ?, ?, ?, ?);")
                 .bind(&label)
                 .bind(lat)
                 .bind(lon)
                 .bind(&kind)
+                .bind(&visibility)
                 .bind(owner_id)
                 .execute(&self.pool)
-                .await
-                .expect("Inserting location into db")
-                .last_insert_rowid()
+                .await?
+                .last_insert_rowid())
     }
 
-    pub async fn get_all_locations(&self) -> Vec<LocationData> {
-        sqlx::query_as!(LocationData, 
-                        "SELECT * FROM locations")
+    pub async fn get_all_locations(&self, viewer: &Viewer) -> DbResult<Vec<LocationData>> {
+        let sql = format!("SELECT {} FROM locations
+                           WHERE deleted_date=-1 AND {}", LOCATION_COLUMNS, VISIBLE_TO_VIEWER);
+
+        Ok(viewer.bind(sqlx::query_as::<_, LocationData>(&sql))
                     .fetch_all(&self.pool)
-                    .await.ok().unwrap()
+                    .await?)
+    }
+
+    // Returns the location, or None if it does not exist, was deleted or viewer cannot see it
+    pub async fn get_location(&self, location_id: i64, viewer: &Viewer) -> DbResult<Option<LocationData>> {
+        let sql = format!("SELECT {} FROM locations
+                           WHERE id=? AND deleted_date=-1 AND {}", LOCATION_COLUMNS, VISIBLE_TO_VIEWER);
+
+        Ok(viewer.bind(sqlx::query_as::<_, LocationData>(&sql).bind(location_id))
+                    .fetch_optional(&self.pool)
+                    .await?)
+    }
+
+    // Sets who can see the location, returns the new share token for "link"
+    // Setting "link" again makes a new token, so old links stop working
+    pub async fn set_location_visibility(&self, location_id: i64, visibility: &str) -> DbResult<Option<String>> {
+        let share_token = if visibility == "link" {
+            Some(DbCrypto::gen_token())
+        } else {
+            None
+        };
+
+        sqlx::query("UPDATE locations 
+                            SET visibility=?, share_token=?
+                            WHERE id=?")
+                .bind(&visibility)
+                .bind(&share_token)
+                .bind(location_id)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(share_token)
+    }
+
+    // Replaces who a "group" or "link" location is shared with
+    pub async fn set_location_shares(&self, location_id: i64, user_ids: &[i64], group_ids: &[i64]) -> DbResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM location_shares WHERE location_id=?")
+                .bind(location_id)
+                .execute(&mut tx)
+                .await?;
+
+        let shares = user_ids.iter().map(|user_id| (*user_id, -1))
+            .chain(group_ids.iter().map(|group_id| (-1, *group_id)));
+
+        for (user_id, group_id) in shares {
+            sqlx::query("INSERT INTO location_shares 
+                                        (location_id, user_id, group_id) 
+                                VALUES  (?, ?, ?);")
+                    .bind(location_id)
+                    .bind(user_id)
+                    .bind(group_id)
+                    .execute(&mut tx)
+                    .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn edit_location(&self, location_id: i64, label: &str, lat: f64, lon: f64, kind: &str) -> DbResult<()> {
+        sqlx::query("UPDATE locations 
+                            SET label=?, lat=?, lon=?, kind=?
+                            WHERE id=?")
+                .bind(&label)
+                .bind(lat)
+                .bind(lon)
+                .bind(&kind)
+                .bind(location_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Removes the location row and its shares, files and comments must be dealt with first
+    pub async fn delete_location(&self, location_id: i64) -> DbResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM location_shares WHERE location_id=?")
+                .bind(location_id)
+                .execute(&mut tx)
+                .await?;
+
+        sqlx::query("DELETE FROM locations WHERE id=?")
+                .bind(location_id)
+                .execute(&mut tx)
+                .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Moves all files and comments of one location onto another, then removes the first and its shares
+    // Done in one transaction so a failure never leaves a half moved location
+    pub async fn reassign_and_delete_location(&self, from_location_id: i64, to_location_id: i64) -> DbResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("UPDATE files 
+                            SET location_id=?
+                            WHERE location_id=?")
+                .bind(to_location_id)
+                .bind(from_location_id)
+                .execute(&mut tx)
+                .await?;
+
+        sqlx::query("UPDATE comments 
+                            SET location_id=?
+                            WHERE location_id=?")
+                .bind(to_location_id)
+                .bind(from_location_id)
+                .execute(&mut tx)
+                .await?;
+
+        sqlx::query("DELETE FROM location_shares WHERE location_id=?")
+                .bind(from_location_id)
+                .execute(&mut tx)
+                .await?;
+
+        sqlx::query("DELETE FROM locations WHERE id=?")
+                .bind(from_location_id)
+                .execute(&mut tx)
+                .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Hides the location, keeping its files and comments in the db
+    pub async fn soft_delete_location(&self, location_id: i64) -> DbResult<()> {
+        sqlx::query("UPDATE locations 
+                            SET deleted_date=?
+                            WHERE id=?")
+                .bind(Utc::now().timestamp())
+                .bind(location_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
     }
 
-    async fn get_location_ids(&self, label: &String, lat: f64, lon: f64, location_type: &String, owner_id: i64) -> Vec<i64> {
+    // Returns the ids of all locations matching (label, lat, lon, kind, owner_id) that viewer can see
+    // Can ignore owner_id by passing in -1
+    pub async fn get_location_ids(&self, label: &String, lat: f64, lon: f64, kind: &String, owner_id: i64, viewer: &Viewer) -> DbResult<Vec<i64>> {
         let rows: Vec<(i64,)> = if owner_id == -1 {
-            sqlx::query_as("SELECT id FROM locations
-                            WHERE label=? and lat=? and lon=? and location_type=?")
+            let sql = format!("SELECT id FROM locations
+                               WHERE label=? and lat=? and lon=? and kind=? and deleted_date=-1 and {}", VISIBLE_TO_VIEWER);
+
+            viewer.bind(sqlx::query_as(&sql)
                         .bind(&label)
                         .bind(lat)
                         .bind(lon)
-                        .bind(&location_type)
+                        .bind(&kind))
                         .fetch_all(&self.pool)
-                        .await.ok().unwrap_or(Vec::new())
+                        .await?
         }
         else {
-            sqlx::query_as("SELECT id FROM locations
-                            WHERE label=? and lat=? and lon=? and location_type=? and owner_id=?")
+            let sql = format!("SELECT id FROM locations
+                               WHERE label=? and lat=? and lon=? and kind=? and owner_id=? and deleted_date=-1 and {}", VISIBLE_TO_VIEWER);
+
+            viewer.bind(sqlx::query_as(&sql)
                         .bind(&label)
                         .bind(lat)
                         .bind(lon)
-                        .bind(&location_type)
-                        .bind(owner_id)
+                        .bind(&kind)
+                        .bind(owner_id))
                         .fetch_all(&self.pool)
-                        .await.ok().unwrap_or(Vec::new())
+                        .await?
         };
         
         let mut ids = Vec::new();
@@ -66,21 +328,251 @@ impl MapDB {
             ids.push(rows[i].0);
         }
 
-        ids
+        Ok(ids)
     }
 
-    pub async fn get_location_id(&self, label: &String, lat: f64, lon: f64, kind: &String, owner_id: i64) -> i64 {
-        // Returns a vector of location_ids that match (label, lat, lon, owner_id)
+    pub async fn get_location_id(&self, label: &String, lat: f64, lon: f64, kind: &String, visibility: &str, owner_id: i64) -> DbResult<i64> {
+        // Returns the location_id that matches (label, lat, lon, owner_id), adding it with visibility if there is none
         // Can ignore owner_id by passing in -1
-        let location_ids = self.get_location_ids(&label, lat, lon, &kind, owner_id).await;
+        let location_ids = self.get_location_ids(&label, lat, lon, &kind, owner_id, &Viewer::user(owner_id, false)).await?;
 
         if owner_id != -1 && location_ids.len() > 1 {
-            panic!("Too many locations returned! Each owner should only have one location with the same (label, lat, lon, type)")
+            // Each owner should only have one location with the same (label, lat, lon, type)
+            return Err(DbError::ConstraintViolation(format!("owner {} has {} locations '{}' at {}, {}", owner_id, location_ids.len(), label, lat, lon)));
         }
         else if location_ids.len() == 0 {
-            return self.add_location(label, lat, lon, kind, owner_id).await;
+            return self.add_location(label, lat, lon, kind, visibility, owner_id).await;
+        }
+
+        Ok(location_ids[0])
+    }
+
+    // Returns all locations inside the bounding box, using the locations_rtree index
+    // A box with min_lon > max_lon is treated as crossing the antimeridian
+    pub async fn get_locations_in_bbox(&self, min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64, viewer: &Viewer) -> DbResult<Vec<LocationData>> {
+        if min_lon > max_lon {
+            let mut locations = self.query_locations_in_bbox(min_lon, min_lat, 180.0, max_lat, viewer).await?;
+            locations.append(&mut self.query_locations_in_bbox(-180.0, min_lat, max_lon, max_lat, viewer).await?);
+            return Ok(locations);
         }
 
-        location_ids[0]
+        self.query_locations_in_bbox(min_lon, min_lat, max_lon, max_lat, viewer).await
+    }
+
+    // get_locations_in_bbox for a box that does not cross the antimeridian
+    async fn query_locations_in_bbox(&self, min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64, viewer: &Viewer) -> DbResult<Vec<LocationData>> {
+        let sql = format!("SELECT {}
+                           FROM locations
+                           INNER JOIN locations_rtree ON locations.id=locations_rtree.id
+                           WHERE locations_rtree.max_lat>=? AND locations_rtree.min_lat<=?
+                             AND locations_rtree.max_lon>=? AND locations_rtree.min_lon<=?
+                             AND locations.deleted_date=-1 AND {}", LOCATION_COLUMNS, VISIBLE_TO_VIEWER);
+
+        Ok(viewer.bind(sqlx::query_as::<_, LocationData>(&sql)
+                    .bind(min_lat)
+                    .bind(max_lat)
+                    .bind(min_lon)
+                    .bind(max_lon))
+                    .fetch_all(&self.pool)
+                    .await?)
+    }
+
+    // Returns all locations within radius_m meters of (lat, lon), nearest first
+    pub async fn get_locations_near(&self, lat: f64, lon: f64, radius_m: f64, viewer: &Viewer) -> DbResult<Vec<LocationData>> {
+        // Narrow down with the index first, then filter on the real distance
+        let (min_lon, min_lat, max_lon, max_lat) = search_bbox(lat, lon, radius_m);
+        let candidates = self.get_locations_in_bbox(min_lon, min_lat, max_lon, max_lat, viewer).await?;
+
+        let mut locations: Vec<(f64, LocationData)> = candidates
+            .into_iter()
+            .map(|location| (distance_m(lat, lon, location.lat as f64, location.lon as f64), location))
+            .filter(|(distance, _)| *distance <= radius_m)
+            .collect();
+
+        locations.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        Ok(locations.into_iter().map(|(_, location)| location).collect())
+    }
+
+    // Returns the closest location within radius_m meters of (lat, lon), if any
+    pub async fn get_nearest_location(&self, lat: f64, lon: f64, radius_m: f64, viewer: &Viewer) -> DbResult<Option<LocationData>> {
+        Ok(self.get_locations_near(lat, lon, radius_m, viewer).await?.into_iter().next())
+    }
+}
+
+// Smallest (min_lon, min_lat, max_lon, max_lat) box holding every point within radius_m of (lat, lon)
+// min_lon > max_lon when it crosses the antimeridian. A circle reaching over a pole covers every longitude
+fn search_bbox(lat: f64, lon: f64, radius_m: f64) -> (f64, f64, f64, f64) {
+    let angle = radius_m / EARTH_RADIUS_M; // Radians along the surface
+    let min_lat = lat - angle.to_degrees();
+    let max_lat = lat + angle.to_degrees();
+
+    // Half the width of the circle at its widest, which is north or south of lat
+    let sin_d_lon = angle.sin() / lat.to_radians().cos();
+
+    if min_lat <= -90.0 || max_lat >= 90.0 || angle >= std::f64::consts::FRAC_PI_2 || sin_d_lon >= 1.0 {
+        return (-180.0, min_lat.max(-90.0), 180.0, max_lat.min(90.0));
+    }
+
+    let d_lon = sin_d_lon.asin().to_degrees();
+    (wrap_lon(lon - d_lon), min_lat, wrap_lon(lon + d_lon), max_lat)
+}
+
+// Wraps a longitude back into [-180, 180]
+fn wrap_lon(lon: f64) -> f64 {
+    if lon > 180.0 {
+        lon - 360.0
+    }
+    else if lon < -180.0 {
+        lon + 360.0
+    }
+    else {
+        lon
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{block_on, test_db};
+
+    const KM: f64 = 1000.0;
+
+    // Is (lat, lon) inside a box from search_bbox
+    fn in_bbox((min_lon, min_lat, max_lon, max_lat): (f64, f64, f64, f64), lat: f64, lon: f64) -> bool {
+        let in_lon = if min_lon > max_lon {
+            lon >= min_lon || lon <= max_lon
+        } else {
+            lon >= min_lon && lon <= max_lon
+        };
+
+        in_lon && lat >= min_lat && lat <= max_lat
+    }
+
+    #[test]
+    fn longitudes_wrap_into_range() {
+        assert_eq!(wrap_lon(190.0), -170.0);
+        assert_eq!(wrap_lon(-190.0), 170.0);
+        assert_eq!(wrap_lon(180.0), 180.0);
+        assert_eq!(wrap_lon(-180.0), -180.0);
+        assert_eq!(wrap_lon(12.5), 12.5);
+    }
+
+    #[test]
+    fn search_box_contains_the_circle() {
+        // Points on the circle every 5 degrees of bearing, from a few places and sizes
+        for &(lat, lon) in &[(0.0, 0.0), (52.5, 13.4), (-33.9, 151.2), (70.0, -20.0), (80.0, 0.0), (-85.0, 100.0), (10.0, 179.5)] {
+            for &radius_m in &[50.0, 10.0 * KM, 600.0 * KM, 1000.0 * KM] {
+                let bbox = search_bbox(lat, lon, radius_m);
+                let angle = radius_m / EARTH_RADIUS_M * 0.999_999; // Just inside, clear of rounding
+
+                for bearing in (0..360).step_by(5) {
+                    let bearing = (bearing as f64).to_radians();
+                    let (lat1, lon1) = (lat.to_radians(), lon.to_radians());
+
+                    // Destination point given distance and bearing from start point
+                    let lat2 = (lat1.sin() * angle.cos() + lat1.cos() * angle.sin() * bearing.cos()).asin();
+                    let lon2 = lon1 + (bearing.sin() * angle.sin() * lat1.cos()).atan2(angle.cos() - lat1.sin() * lat2.sin());
+                    let (lat2, lon2) = (lat2.to_degrees(), wrap_lon(lon2.to_degrees()));
+
+                    assert!(in_bbox(bbox, lat2, lon2), "({}, {}) radius {}: ({}, {}) not in {:?}", lat, lon, radius_m, lat2, lon2, bbox);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn search_box_over_a_pole_covers_every_longitude() {
+        // 600km from 85N reaches 5.4 degrees of latitude, over the pole
+        let (min_lon, min_lat, max_lon, max_lat) = search_bbox(85.0, 10.0, 600.0 * KM);
+        assert_eq!((min_lon, max_lon, max_lat), (-180.0, 180.0, 90.0));
+        assert!((min_lat - (85.0 - 5.396)).abs() < 0.01);
+
+        // Just across the pole, 180 degrees of longitude away
+        assert!(distance_m(85.0, 10.0, 89.7, -170.0) < 600.0 * KM);
+        assert!(in_bbox(search_bbox(85.0, 10.0, 600.0 * KM), 89.7, -170.0));
+
+        let (min_lon, min_lat, max_lon, _) = search_bbox(-89.0, 0.0, 200.0 * KM);
+        assert_eq!((min_lon, min_lat, max_lon), (-180.0, -90.0, 180.0));
+
+        // Close to but not over the pole, the box is still narrower than the globe
+        let (min_lon, _, max_lon, _) = search_bbox(80.0, 0.0, 500.0 * KM);
+        assert!(min_lon > -180.0 && max_lon < 180.0);
+    }
+
+    #[test]
+    fn search_box_across_the_antimeridian_wraps() {
+        let (min_lon, _, max_lon, _) = search_bbox(0.0, 179.9, 50.0 * KM);
+        assert!(min_lon > max_lon);
+        assert!(in_bbox(search_bbox(0.0, 179.9, 50.0 * KM), 0.0, -179.9));
+    }
+
+    #[test]
+    fn lat_lon_must_be_finite_and_on_the_globe() {
+        assert!(is_valid_lat_lon(90.0, -180.0));
+        assert!(!is_valid_lat_lon(90.1, 0.0));
+        assert!(!is_valid_lat_lon(0.0, 180.1));
+        assert!(!is_valid_lat_lon(f64::NAN, 0.0));
+        assert!(!is_valid_lat_lon(0.0, f64::INFINITY));
+    }
+
+    #[test]
+    fn new_visibility_defaults_and_rejects_link() {
+        assert_eq!(new_visibility(None, "private"), Ok("private"));
+        assert_eq!(new_visibility(Some("public"), "private"), Ok("public"));
+        assert!(new_visibility(Some("link"), "private").is_err());
+        assert!(new_visibility(Some("everyone"), "private").is_err());
+    }
+
+    #[test]
+    fn new_locations_get_the_visibility_they_are_given() {
+        block_on(async {
+            let db = test_db().await;
+            let (owner_id, _) = db.add_user("owner", "correct horse battery").await.unwrap();
+            let (other_id, _) = db.add_user("other", "correct horse battery").await.unwrap();
+
+            let private_id = db.add_location(&"Home".to_string(), 1.0, 2.0, &"photo".to_string(), "private", owner_id).await.unwrap();
+            let public_id = db.add_location(&"Park".to_string(), 1.0, 2.0, &"pin".to_string(), "public", owner_id).await.unwrap();
+
+            assert!(db.get_location(private_id, &Viewer::user(owner_id, false)).await.unwrap().is_some());
+            assert!(db.get_location(private_id, &Viewer::user(other_id, false)).await.unwrap().is_none());
+            assert!(db.get_location(private_id, &Viewer::public()).await.unwrap().is_none());
+            assert!(db.get_location(public_id, &Viewer::public()).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn deleted_locations_leave_no_shares_behind() {
+        block_on(async {
+            let db = test_db().await;
+            let (owner_id, _) = db.add_user("owner", "correct horse battery").await.unwrap();
+            let (friend_id, _) = db.add_user("friend", "correct horse battery").await.unwrap();
+            let share_count = |location_id: i64| {
+                let db = db.clone();
+                async move {
+                    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM location_shares WHERE location_id=?")
+                            .bind(location_id)
+                            .fetch_one(&db.pool)
+                            .await
+                            .unwrap();
+                    count
+                }
+            };
+
+            let deleted_id = db.add_location(&"A".to_string(), 1.0, 2.0, &"pin".to_string(), "group", owner_id).await.unwrap();
+            let merged_id = db.add_location(&"B".to_string(), 1.0, 2.0, &"pin".to_string(), "group", owner_id).await.unwrap();
+            let kept_id = db.add_location(&"C".to_string(), 1.0, 2.0, &"pin".to_string(), "group", owner_id).await.unwrap();
+
+            for location_id in &[deleted_id, merged_id, kept_id] {
+                db.set_location_shares(*location_id, &[friend_id], &[]).await.unwrap();
+            }
+
+            db.delete_location(deleted_id).await.unwrap();
+            db.reassign_and_delete_location(merged_id, kept_id).await.unwrap();
+
+            assert_eq!(share_count(deleted_id).await, 0);
+            assert_eq!(share_count(merged_id).await, 0);
+            assert_eq!(share_count(kept_id).await, 1);
+        });
     }
 }
@@ -0,0 +1,72 @@
+use serde::Serialize;
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+
+// Failed login attempts for a key, ie. "ip:127.0.0.1" or "user:james"
+#[derive(Serialize, Clone, sqlx::FromRow)]
+pub struct LoginAttempts {
+    pub key:                String,
+    pub failures:           i64,
+    pub last_failure_date:  i64,
+    pub locked_until:       i64,
+}
+
+impl MapDB {
+    // Inserts or replaces the attempts for attempts.key
+    pub async fn save_login_attempts(&self, attempts: &LoginAttempts) -> DbResult<()> {
+        sqlx::query("INSERT OR REPLACE INTO login_attempts 
+                                    (key, failures, last_failure_date, locked_until) 
+                            VALUES  (?, ?, ?, ?);")
+                .bind(&attempts.key)
+                .bind(attempts.failures)
+                .bind(attempts.last_failure_date)
+                .bind(attempts.locked_until)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    pub async fn get_login_attempts(&self, key: &str) -> DbResult<Option<LoginAttempts>> {
+        Ok(sqlx::query_as("SELECT key, failures, last_failure_date, locked_until 
+                            FROM login_attempts 
+                            WHERE key=?;")
+                .bind(&key)
+                .fetch_optional(&self.pool)
+                .await?)
+    }
+
+    pub async fn get_all_login_attempts(&self) -> DbResult<Vec<LoginAttempts>> {
+        Ok(sqlx::query_as("SELECT key, failures, last_failure_date, locked_until 
+                            FROM login_attempts 
+                            ORDER BY locked_until DESC;")
+                .fetch_all(&self.pool)
+                .await?)
+    }
+
+    pub async fn delete_login_attempts(&self, key: &str) -> DbResult<bool> {
+        Ok(sqlx::query("DELETE FROM login_attempts WHERE key=?")
+                .bind(&key)
+                .execute(&self.pool)
+                .await?
+                .rows_affected() > 0)
+    }
+
+    // Forgets keys that are not locked out and have had no failures since before, returns how many
+    pub async fn delete_expired_login_attempts(&self, now: i64, before: i64) -> DbResult<u64> {
+        Ok(sqlx::query("DELETE FROM login_attempts WHERE locked_until<=? AND last_failure_date<?")
+                .bind(now)
+                .bind(before)
+                .execute(&self.pool)
+                .await?
+                .rows_affected())
+    }
+
+    // Returns how many were cleared
+    pub async fn delete_all_login_attempts(&self) -> DbResult<u64> {
+        Ok(sqlx::query("DELETE FROM login_attempts")
+                .execute(&self.pool)
+                .await?
+                .rows_affected())
+    }
+}
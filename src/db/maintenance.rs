@@ -0,0 +1,54 @@
+use serde::Serialize;
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+
+// A migration from migrations/ that has been run on this database
+#[derive(Serialize)]
+pub struct AppliedMigration {
+    pub version:        i64,
+    pub description:    String,
+    pub installed_on:   String,
+}
+
+impl MapDB {
+    // Migrations are run whenever the db is opened, this lists the ones that have been
+    pub async fn get_applied_migrations(&self) -> DbResult<Vec<AppliedMigration>> {
+        let rows: Vec<(i64, String, String)> = sqlx::query_as("SELECT version, description, installed_on 
+                                            FROM _sqlx_migrations 
+                                            WHERE success=1
+                                            ORDER BY version;")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter()
+            .map(|(version, description, installed_on)| AppliedMigration {
+                version,
+                description,
+                installed_on,
+            })
+            .collect())
+    }
+
+    // Writes a consistent copy of the database to filename, safe while the server is running
+    // filename must not exist yet
+    pub async fn backup(&self, filename: &str) -> DbResult<()> {
+        sqlx::query("VACUUM INTO ?")
+                .bind(&filename)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Runs SQLite's integrity check, returns the problems found or an empty list
+    pub async fn check_integrity(&self) -> DbResult<Vec<String>> {
+        let rows: Vec<(String,)> = sqlx::query_as("PRAGMA integrity_check;")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter()
+            .map(|row| row.0)
+            .filter(|problem| problem != "ok")
+            .collect())
+    }
+}
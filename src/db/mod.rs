@@ -6,49 +6,62 @@ use sqlx::{
     Pool, Sqlite,
 };
 
+use crate::db::error::DbResult;
+use crate::db::password_policy::PasswordPolicy;
+use crate::settings::Settings;
+
+pub mod api_tokens;
 pub mod comments;
 pub mod crypto;
+pub mod error;
 pub mod files;
 pub mod locations;
+pub mod login_attempts;
+pub mod maintenance;
+pub mod password_policy;
+pub mod permissions;
+pub mod recovery_codes;
+pub mod sessions;
+pub mod tracks;
 pub mod users;
 pub mod user_groups;
 
-// SQLite database file
-const DEFAULT_SQLITE_NAME: &str = "db.sqlite";
-
-// Pool settings
-const POOL_TIMEOUT: Duration        = Duration::from_secs(30);
-const POOL_MAX_CONNECTIONS: u32     = 4;
-
 #[derive(Clone)]
 pub struct MapDB {
     pub pool: Pool<Sqlite>,
+    totp_window: u64,       // Steps either side of now a totp code is accepted for, to allow for clock skew
+    session_idle_secs: i64, // Sessions end after this long without a request
+    session_max_age_secs: i64, // or this long after login
+    website_url: String,    // Issuer of totp codes
+    pub password_policy: PasswordPolicy, // Callers check new passwords before add_user or set_user_password
 }
 
 impl MapDB {
-    pub async fn new_from(db_name: &str) -> MapDB {
+    pub async fn new(settings: &Settings) -> DbResult<MapDB> {
         // Default (and only for a while probably) is to create a sqlite
-        MapDB {
-            pool: MapDB::open_sqlite_db(&db_name).await.expect("could not open db"),
-        }
-    }
-    
-    pub async fn new() -> MapDB {
-        MapDB::new_from(DEFAULT_SQLITE_NAME).await
+        Ok(MapDB {
+            pool: MapDB::open_sqlite_db(settings).await?,
+            totp_window: settings.totp_window,
+            session_idle_secs: settings.session_idle_secs,
+            session_max_age_secs: settings.session_max_age_secs,
+            website_url: settings.website_url.to_string(),
+            password_policy: PasswordPolicy::new(settings),
+        })
     }
 
-    async fn open_sqlite_db(db_name: &str) -> Result<Pool<Sqlite>, Box<dyn std::error::Error>> {
-        let database_url = format!("sqlite://{}", db_name);
+    async fn open_sqlite_db(settings: &Settings) -> DbResult<Pool<Sqlite>> {
+        let database_url = format!("sqlite://{}", settings.db_path);
+        let pool_timeout = Duration::from_secs(settings.db_timeout_secs);
     
         let connection_options = SqliteConnectOptions::from_str(&database_url)?
             .create_if_missing(true)
             .journal_mode(SqliteJournalMode::Wal)
             .synchronous(SqliteSynchronous::Normal)
-            .busy_timeout(POOL_TIMEOUT);
+            .busy_timeout(pool_timeout);
     
         let sqlite_pool = SqlitePoolOptions::new()
-            .max_connections(POOL_MAX_CONNECTIONS)
-            .connect_timeout(POOL_TIMEOUT)
+            .max_connections(settings.db_max_connections)
+            .connect_timeout(pool_timeout)
             .connect_with(connection_options)
             .await?;
     
@@ -57,4 +70,22 @@ impl MapDB {
     
         Ok(sqlite_pool)
     }
-}
\ No newline at end of file
+}
+
+// A new, migrated db in the temp dir, for tests that need one
+#[cfg(test)]
+pub async fn test_db() -> MapDB {
+    let mut settings = Settings::default();
+    settings.db_path = std::env::temp_dir()
+        .join(format!("mymap-test-{}.sqlite", uuid::Uuid::new_v4()))
+        .to_string_lossy()
+        .to_string();
+
+    MapDB::new(&settings).await.expect("could not create test db")
+}
+
+// Runs a db test, sqlx needs to be inside the actix runtime
+#[cfg(test)]
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    actix_web::rt::System::new("db-test").block_on(future)
+}
@@ -0,0 +1,38 @@
+use crate::settings::Settings;
+
+// Rules a new password must follow, checked wherever a password is chosen
+// Existing passwords are never rechecked, so a stricter policy only applies from the next change
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    pub min_length:     usize,
+    pub min_classes:    usize, // Of lower case, upper case, digits and symbols
+}
+
+impl PasswordPolicy {
+    pub fn new(settings: &Settings) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length:     settings.password_min_length,
+            min_classes:    settings.password_min_classes,
+        }
+    }
+
+    // Returns why the password is refused, or Ok if it is allowed
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length {
+            return Err(format!("Password must be at least {} characters", self.min_length));
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+
+        if classes.iter().filter(|has| **has).count() < self.min_classes {
+            return Err(format!("Password must use at least {} of: lower case, upper case, digits and symbols", self.min_classes));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::str::FromStr;
+
+// Everything a user can be allowed to do, stored by name in group_permissions
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Permission {
+    #[serde(rename = "*")]
+    All, // Has every permission
+    AddLocation,
+    EditLocation,
+    EditOtherLocation,
+    DeleteLocation,
+    DeleteOtherLocation,
+    AddComment,
+    EditComment,
+    EditOtherComment,
+    SaveFile,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 10] = [
+        Permission::All,
+        Permission::AddLocation,
+        Permission::EditLocation,
+        Permission::EditOtherLocation,
+        Permission::DeleteLocation,
+        Permission::DeleteOtherLocation,
+        Permission::AddComment,
+        Permission::EditComment,
+        Permission::EditOtherComment,
+        Permission::SaveFile,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::All => "*",
+            Permission::AddLocation => "addLocation",
+            Permission::EditLocation => "editLocation",
+            Permission::EditOtherLocation => "editOtherLocation",
+            Permission::DeleteLocation => "deleteLocation",
+            Permission::DeleteOtherLocation => "deleteOtherLocation",
+            Permission::AddComment => "addComment",
+            Permission::EditComment => "editComment",
+            Permission::EditOtherComment => "editOtherComment",
+            Permission::SaveFile => "saveFile",
+        }
+    }
+
+    // Parses a comma separated list, ie. "addLocation,saveFile"
+    pub fn parse_list(list: &str) -> Result<Vec<Permission>, String> {
+        list.split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| p.parse::<Permission>())
+            .collect()
+    }
+
+    pub fn join(permissions: &[Permission]) -> String {
+        permissions.iter().map(|p| p.as_str()).collect::<Vec<&str>>().join(",")
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Permission, String> {
+        Permission::ALL.iter()
+            .find(|p| p.as_str() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown permission '{}'", s))
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for permission in Permission::ALL.iter() {
+            assert_eq!(permission.to_string().parse::<Permission>(), Ok(*permission));
+            assert_eq!(serde_json::to_string(permission).unwrap(), format!("\"{}\"", permission));
+            assert_eq!(serde_json::from_str::<Permission>(&format!("\"{}\"", permission)).unwrap(), *permission);
+        }
+
+        assert_eq!("*".parse::<Permission>(), Ok(Permission::All));
+    }
+
+    #[test]
+    fn names_only_match_exactly() {
+        for name in &["canComment", "comment", "EditComment", "editcomment", "editOther", " addLocation", "all", ""] {
+            assert!(name.parse::<Permission>().is_err(), "parsed '{}'", name);
+        }
+
+        assert_eq!("editOtherComment".parse::<Permission>(), Ok(Permission::EditOtherComment));
+        assert_eq!("editComment".parse::<Permission>(), Ok(Permission::EditComment));
+    }
+
+    #[test]
+    fn lists_are_comma_separated() {
+        assert_eq!(Permission::parse_list(" addLocation, saveFile,,"), Ok(vec![Permission::AddLocation, Permission::SaveFile]));
+        assert_eq!(Permission::parse_list(""), Ok(vec![]));
+        assert!(Permission::parse_list("addLocation,canComment").is_err());
+
+        let all = Permission::join(&Permission::ALL);
+        assert_eq!(Permission::parse_list(&all), Ok(Permission::ALL.to_vec()));
+    }
+}
@@ -0,0 +1,67 @@
+use chrono::Utc;
+
+use crate::db::MapDB;
+use crate::db::crypto::DbCrypto;
+use crate::db::error::DbResult;
+
+// Recovery codes handed out per user
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+impl MapDB {
+    // Replaces the user's recovery codes with a new set, returns them to show the user
+    // Only hashes are stored, so like the totp secret they are only retrievable here
+    pub async fn add_recovery_codes(&self, user_id: i64) -> DbResult<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id=?")
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+
+        let mut codes = Vec::new();
+
+        for _ in 0..RECOVERY_CODE_COUNT {
+            let code = DbCrypto::gen_recovery_code();
+
+            sqlx::query("INSERT INTO recovery_codes 
+                                        (user_id, code_hash) 
+                                VALUES  (?, ?);")
+                    .bind(user_id)
+                    .bind(DbCrypto::hash_recovery_code(&code))
+                    .execute(&mut tx)
+                    .await?;
+
+            codes.push(code);
+        }
+
+        tx.commit().await?;
+
+        Ok(codes)
+    }
+
+    // Uses up one of the user's recovery codes, returns false if it is not valid or already used
+    pub async fn use_recovery_code(&self, user_id: i64, code: &str) -> DbResult<bool> {
+        let res = sqlx::query("UPDATE recovery_codes 
+                                    SET used_date=?
+                                    WHERE user_id=? AND code_hash=? AND used_date=-1")
+                .bind(Utc::now().timestamp())
+                .bind(user_id)
+                .bind(DbCrypto::hash_recovery_code(code))
+                .execute(&self.pool)
+                .await?;
+
+        Ok(res.rows_affected() == 1)
+    }
+
+    // Number of recovery codes the user has left
+    pub async fn get_unused_recovery_code_count(&self, user_id: i64) -> DbResult<i64> {
+        let row: (i64,) = sqlx::query_as("SELECT COUNT(*) 
+                                            FROM recovery_codes 
+                                            WHERE user_id=? AND used_date=-1;")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(row.0)
+    }
+}
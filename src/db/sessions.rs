@@ -0,0 +1,137 @@
+use serde::Serialize;
+use chrono::Utc;
+
+use crate::db::MapDB;
+use crate::db::crypto::DbCrypto;
+use crate::db::error::DbResult;
+
+// last_seen_date is only written once this many seconds have passed, not on every request
+const LAST_SEEN_RESOLUTION: i64 = 60;
+
+// A login session, as listed to its user (never includes the token)
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id:             i64,
+    pub created_date:   f64,
+    pub last_seen_date: f64,
+    pub ip:             String,
+    pub user_agent:     String,
+}
+
+impl MapDB {
+    // Starts a new session for the user, returns the token to hand to the client
+    // Only a hash of the token is stored
+    pub async fn add_session(&self, user_id: i64, ip: &str, user_agent: &str) -> DbResult<String> {
+        let token = DbCrypto::gen_token();
+        let now = Utc::now().timestamp();
+
+        sqlx::query("INSERT INTO sessions 
+                                    (token_hash, user_id, created_date, last_seen_date, ip, user_agent) 
+                            VALUES  (?, ?, ?, ?, ?, ?);")
+                .bind(DbCrypto::hash_token(&token))
+                .bind(user_id)
+                .bind(now)
+                .bind(now)
+                .bind(&ip)
+                .bind(&user_agent)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(token)
+    }
+
+    // Returns the username the session belongs to, or None if it was revoked or has expired
+    // Also records that the session was seen, an expired session is deleted
+    pub async fn get_session_username(&self, token: &str) -> DbResult<Option<String>> {
+        let token_hash = DbCrypto::hash_token(token);
+        let now = Utc::now().timestamp();
+
+        let row: Option<(String, f64, f64)> = sqlx::query_as("SELECT users.username, sessions.created_date, sessions.last_seen_date 
+                                            FROM sessions
+                                            INNER JOIN users ON users.id=sessions.user_id
+                                            WHERE sessions.token_hash=? AND users.disabled=0;")
+                .bind(&token_hash)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        let (username, created_date, last_seen_date) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        if (last_seen_date as i64) < now - self.session_idle_secs || (created_date as i64) < now - self.session_max_age_secs {
+            self.delete_session(token).await?;
+            return Ok(None);
+        }
+
+        sqlx::query("UPDATE sessions 
+                            SET last_seen_date=?
+                            WHERE token_hash=? AND last_seen_date<?")
+                .bind(now)
+                .bind(&token_hash)
+                .bind(now - LAST_SEEN_RESOLUTION)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(Some(username))
+    }
+
+    // Returns the id of the session with this token
+    pub async fn get_session_id(&self, token: &str) -> DbResult<Option<i64>> {
+        let row: Option<(i64,)> = sqlx::query_as("SELECT id FROM sessions WHERE token_hash=?;")
+                .bind(DbCrypto::hash_token(token))
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.map(|row| row.0))
+    }
+
+    pub async fn get_user_sessions(&self, user_id: i64) -> DbResult<Vec<SessionInfo>> {
+        let rows: Vec<(i64, f64, f64, String, String)> =
+            sqlx::query_as("SELECT id, created_date, last_seen_date, ip, user_agent 
+                            FROM sessions
+                            WHERE user_id=?
+                            ORDER BY last_seen_date DESC")
+                .bind(user_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter()
+            .map(|(id, created_date, last_seen_date, ip, user_agent)| SessionInfo {
+                id,
+                created_date,
+                last_seen_date,
+                ip,
+                user_agent,
+            })
+            .collect())
+    }
+
+    // Ends the session with this token (logout)
+    pub async fn delete_session(&self, token: &str) -> DbResult<()> {
+        sqlx::query("DELETE FROM sessions WHERE token_hash=?")
+                .bind(DbCrypto::hash_token(token))
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Revokes one of the user's sessions, returns false if it is not theirs
+    pub async fn delete_user_session(&self, user_id: i64, session_id: i64) -> DbResult<bool> {
+        Ok(sqlx::query("DELETE FROM sessions WHERE id=? AND user_id=?")
+                .bind(session_id)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected() > 0)
+    }
+
+    // Revokes every session of the user, returns how many there were
+    pub async fn delete_user_sessions(&self, user_id: i64) -> DbResult<u64> {
+        Ok(sqlx::query("DELETE FROM sessions WHERE user_id=?")
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected())
+    }
+}
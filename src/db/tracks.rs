@@ -0,0 +1,99 @@
+use serde::Serialize;
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::locations::Viewer;
+
+// A single point along a track, ele in meters and time as a unix timestamp
+#[derive(Serialize, Clone)]
+pub struct TrackPoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub ele: Option<f64>,
+    pub time: Option<f64>,
+}
+
+// Track stored in the tracks table along with its points
+#[derive(Serialize)]
+pub struct TrackData {
+    pub id: i64,
+    pub label: String,
+    pub kind: String,
+    pub owner_id: i64,
+    pub points: Vec<TrackPoint>,
+}
+
+impl MapDB {
+    // Adds a track and all of its points, returns the track id
+    pub async fn add_track(&self, label: &str, kind: &str, owner_id: i64, points: &Vec<TrackPoint>) -> DbResult<i64> {
+        let mut tx = self.pool.begin().await?;
+
+        let track_id = sqlx::query("INSERT INTO tracks 
+                                    (label, kind, owner_id) 
+                            VALUES  (?, ?, ?);")
+                .bind(&label)
+                .bind(&kind)
+                .bind(owner_id)
+                .execute(&mut tx)
+                .await?
+                .last_insert_rowid();
+
+        for (seq, point) in points.iter().enumerate() {
+            sqlx::query("INSERT INTO track_points 
+                                    (track_id, seq, lat, lon, ele, time) 
+                            VALUES  (?, ?, ?, ?, ?, ?);")
+                .bind(track_id)
+                .bind(seq as i64)
+                .bind(point.lat)
+                .bind(point.lon)
+                .bind(point.ele)
+                .bind(point.time)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(track_id)
+    }
+
+    pub async fn get_track_points(&self, track_id: i64) -> DbResult<Vec<TrackPoint>> {
+        let rows: Vec<(f64, f64, Option<f64>, Option<f64>)> =
+            sqlx::query_as("SELECT lat, lon, ele, time FROM track_points
+                            WHERE track_id=?
+                            ORDER BY seq")
+                .bind(track_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter()
+            .map(|(lat, lon, ele, time)| TrackPoint { lat, lon, ele, time })
+            .collect())
+    }
+
+    // Tracks have no visibility of their own, so a viewer only sees their own tracks (unless they see all)
+    pub async fn get_all_tracks(&self, viewer: &Viewer) -> DbResult<Vec<TrackData>> {
+        let rows: Vec<(i64, String, String, i64)> =
+            sqlx::query_as("SELECT id, label, kind, owner_id FROM tracks
+                            WHERE ? OR (? <> -1 AND owner_id=?)")
+                .bind(viewer.see_all)
+                .bind(viewer.user_id)
+                .bind(viewer.user_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut tracks = Vec::new();
+
+        for (id, label, kind, owner_id) in rows {
+            tracks.push(TrackData {
+                id,
+                label,
+                kind,
+                owner_id,
+                points: self.get_track_points(id).await?,
+            });
+        }
+
+        Ok(tracks)
+    }
+}
@@ -1,6 +1,8 @@
 use serde::Serialize;
 
 use crate::db::MapDB;
+use crate::db::error::{DbError, DbResult};
+use crate::db::permissions::Permission;
 
 const DEFAULT_GUEST_NAME: &str = "guest";
 const DEFAULT_ADMIN_NAME: &str = "admin";
@@ -9,79 +11,249 @@ const DEFAULT_ADMIN_NAME: &str = "admin";
 pub struct UserGroupInfo {
     pub id:             i64,
     pub group_name:     String,
-    pub permissions:    String,
+    pub permissions:    Vec<Permission>,
+}
+
+impl UserGroupInfo {
+    // Does this group have permission, directly or through "*"
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.iter().any(|p| *p == permission || *p == Permission::All)
+    }
 }
 
 impl MapDB {
-    pub async fn add_user_group(&self, group_name: &str, permissions: &str) -> i64 {
-        sqlx::query("INSERT INTO user_groups 
-                                    (group_name, permissions) 
-                            VALUES  (?, ?);")
+    pub async fn add_user_group(&self, group_name: &str, permissions: &[Permission]) -> DbResult<i64> {
+        let group_id = sqlx::query("INSERT INTO user_groups 
+                                    (group_name) 
+                            VALUES  (?);")
                 .bind(&group_name)
-                .bind(&permissions)
                 .execute(&self.pool)
-                .await
-                .expect("Inserting new user group into db")
-                .last_insert_rowid()
+                .await?
+                .last_insert_rowid();
+
+        for permission in permissions {
+            self.grant_group_permission(group_id, *permission).await?;
+        }
+
+        Ok(group_id)
+    }
+
+    // Replaces all of the group's permissions
+    pub async fn edit_user_group(&self, group_name: &str, permissions: &[Permission]) -> DbResult<()> {
+        let group_id = self.get_user_group_id(group_name).await?;
+
+        sqlx::query("DELETE FROM group_permissions WHERE group_id=?")
+                .bind(group_id)
+                .execute(&self.pool)
+                .await?;
+
+        for permission in permissions {
+            self.grant_group_permission(group_id, *permission).await?;
+        }
+        Ok(())
     }
 
-    pub async fn edit_user_group(&self, group_name: &str, permissions: &str) {
-        sqlx::query("UPDATE user_groups 
-                            SET permissions=?
-                            WHERE group_name=?")
-                .bind(&permissions)
-                .bind(&group_name)
+    pub async fn grant_group_permission(&self, group_id: i64, permission: Permission) -> DbResult<()> {
+        sqlx::query("INSERT OR IGNORE INTO group_permissions 
+                                    (group_id, permission) 
+                            VALUES  (?, ?);")
+                .bind(group_id)
+                .bind(permission.as_str())
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Returns false if the group did not have this permission
+    pub async fn revoke_group_permission(&self, group_id: i64, permission: Permission) -> DbResult<bool> {
+        Ok(sqlx::query("DELETE FROM group_permissions WHERE group_id=? AND permission=?")
+                .bind(group_id)
+                .bind(permission.as_str())
                 .execute(&self.pool)
-                .await
-                .expect("Updating user group in db");
+                .await?
+                .rows_affected() > 0)
+    }
+
+    // Permissions no longer known to this version are skipped
+    pub async fn get_group_permissions(&self, group_id: i64) -> DbResult<Vec<Permission>> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT permission 
+                                            FROM group_permissions 
+                                            WHERE group_id=?
+                                            ORDER BY permission;")
+                .bind(group_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.iter()
+            .filter_map(|row| row.0.parse::<Permission>().ok())
+            .collect())
+    }
+
+    async fn new_user_group(&self, id: i64, group_name: String) -> DbResult<UserGroupInfo> {
+        Ok(UserGroupInfo {
+            id,
+            group_name,
+            permissions: self.get_group_permissions(id).await?,
+        })
     }
 
-    pub async fn get_user_group_by_id(&self, group_id: i64) -> Option<UserGroupInfo> {
-        sqlx::query_as!(UserGroupInfo, "SELECT *
+    pub async fn get_user_group_by_id(&self, group_id: i64) -> DbResult<Option<UserGroupInfo>> {
+        let row: Option<(i64, String)> = sqlx::query_as("SELECT id, group_name
                                         FROM user_groups 
-                                        WHERE id=?;", 
-                                   group_id)
-                    .fetch_one(&self.pool)
-                    .await.ok()
+                                        WHERE id=?;")
+                    .bind(group_id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+        match row {
+            Some((id, group_name)) => Ok(Some(self.new_user_group(id, group_name).await?)),
+            None => Ok(None),
+        }
     }
 
-    pub async fn get_all_user_groups(&self) -> Vec<UserGroupInfo> {
-        sqlx::query_as!(UserGroupInfo, 
-                    "SELECT * FROM user_groups")
+    pub async fn get_all_user_groups(&self) -> DbResult<Vec<UserGroupInfo>> {
+        let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, group_name FROM user_groups")
                 .fetch_all(&self.pool)
-                .await.ok().unwrap()
+                .await?;
+
+        let mut groups = Vec::new();
+
+        for (id, group_name) in rows {
+            groups.push(self.new_user_group(id, group_name).await?);
+        }
+
+        Ok(groups)
     }
 
-    pub async fn get_user_group_by_name(&self, group_name: &str) -> Option<UserGroupInfo> {
-        sqlx::query_as!(UserGroupInfo, "SELECT *
+    pub async fn get_user_group_by_name(&self, group_name: &str) -> DbResult<Option<UserGroupInfo>> {
+        let row: Option<(i64, String)> = sqlx::query_as("SELECT id, group_name
                                         FROM user_groups 
-                                        WHERE group_name=?;", 
-                                   group_name)
-                    .fetch_one(&self.pool)
-                    .await.ok()
+                                        WHERE group_name=?;")
+                    .bind(&group_name)
+                    .fetch_optional(&self.pool)
+                    .await?;
+
+        match row {
+            Some((id, group_name)) => Ok(Some(self.new_user_group(id, group_name).await?)),
+            None => Ok(None),
+        }
     }
 
     // Returns group_id from group_name
-    pub async fn get_user_group_id(&self, group_name: &str) -> i64 {
-        let row: (i64,) = sqlx::query_as("SELECT id 
+    pub async fn get_user_group_id(&self, group_name: &str) -> DbResult<i64> {
+        let row: Option<(i64,)> = sqlx::query_as("SELECT id 
                         FROM user_groups 
                         WHERE group_name=?;")
                 .bind(&group_name)
-                .fetch_one(&self.pool)
-                .await.ok().expect(&format!("Could not query user_group '{}'", group_name));
+                .fetch_optional(&self.pool)
+                .await?;
 
-        row.0
+        match row {
+            Some((group_id,)) => Ok(group_id),
+            None => Err(DbError::NotFound(format!("user group '{}'", group_name))),
+        }
     }
 
-    pub async fn get_user_group_id_guest(&self) -> i64 {
+    pub async fn get_user_group_id_guest(&self) -> DbResult<i64> {
         self.get_user_group_id(DEFAULT_GUEST_NAME).await
     }
 
-    pub async fn get_user_group_id_admin(&self) -> i64 {
+    pub async fn get_user_group_id_admin(&self) -> DbResult<i64> {
         self.get_user_group_id(DEFAULT_ADMIN_NAME).await
     }
 
-    pub async fn is_user_group(&self, group_name: &str) -> bool {
-        !self.get_user_group_by_name(group_name).await.is_none()
+    pub async fn is_user_group(&self, group_name: &str) -> DbResult<bool> {
+        Ok(!self.get_user_group_by_name(group_name).await?.is_none())
+    }
+
+    // Deletes the group, its permissions and shares
+    // Members left without any group go back to guest, like new users
+    pub async fn delete_user_group(&self, group_id: i64) -> DbResult<()> {
+        let guest_id = self.get_user_group_id_guest().await?;
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("INSERT OR IGNORE INTO user_group_members (user_id, group_id)
+                            SELECT user_id, ?
+                            FROM user_group_members
+                            WHERE group_id=? AND user_id NOT IN (
+                                SELECT user_id FROM user_group_members WHERE group_id<>?
+                            );")
+                .bind(guest_id)
+                .bind(group_id)
+                .bind(group_id)
+                .execute(&mut tx)
+                .await?;
+
+        for table in ["user_group_members", "group_permissions", "location_shares"].iter() {
+            sqlx::query(&format!("DELETE FROM {} WHERE group_id=?", table))
+                    .bind(group_id)
+                    .execute(&mut tx)
+                    .await?;
+        }
+
+        sqlx::query("DELETE FROM user_groups WHERE id=?")
+                .bind(group_id)
+                .execute(&mut tx)
+                .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // guest and admin are needed for new users and can't be deleted
+    pub fn is_default_user_group(group_name: &str) -> bool {
+        group_name == DEFAULT_GUEST_NAME || group_name == DEFAULT_ADMIN_NAME
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{block_on, test_db};
+
+    fn group(permissions: &[Permission]) -> UserGroupInfo {
+        UserGroupInfo {
+            id:             1,
+            group_name:     "test".to_string(),
+            permissions:    permissions.to_vec(),
+        }
+    }
+
+    #[test]
+    fn other_permissions_do_not_include_own() {
+        let moderators = group(&[Permission::EditOtherComment, Permission::DeleteOtherLocation]);
+
+        assert!(moderators.has_permission(Permission::EditOtherComment));
+        assert!(!moderators.has_permission(Permission::EditComment));
+        assert!(!moderators.has_permission(Permission::DeleteLocation));
+        assert!(!moderators.has_permission(Permission::AddComment));
+    }
+
+    #[test]
+    fn star_has_every_permission() {
+        let admins = group(&[Permission::All]);
+
+        for permission in Permission::ALL.iter() {
+            assert!(admins.has_permission(*permission), "{}", permission);
+        }
+        assert!(!group(&[]).has_permission(Permission::AddComment));
+    }
+
+    // The default groups were created with free-text permissions, migrated to group_permissions
+    #[test]
+    fn default_group_permissions_are_migrated() {
+        block_on(async {
+            let db = test_db().await;
+            let expected = [
+                ("guest", vec![]),
+                ("admin", vec![Permission::All]),
+                ("user", vec![Permission::AddComment, Permission::EditComment]), // Was "canComment"
+            ];
+
+            for (group_name, permissions) in expected.iter() {
+                let group = db.get_user_group_by_name(group_name).await.unwrap().unwrap();
+                assert_eq!(&group.permissions, permissions, "{}", group_name);
+            }
+        });
     }
 }
@@ -3,6 +3,8 @@ use chrono::Utc;
 
 use crate::db::MapDB;
 use crate::db::crypto::DbCrypto;
+use crate::db::error::{DbError, DbResult};
+use crate::db::permissions::Permission;
 use crate::db::user_groups::UserGroupInfo;
 
 /*#[derive(Serialize)]
@@ -18,113 +20,201 @@ pub struct UserRow {
 #[derive(Serialize)]
 pub struct UserInfo {
     pub username:       String,
-    pub group:          UserGroupInfo,
+    pub groups:         Vec<UserGroupInfo>,
+    pub grants:         Vec<Permission>, // Per-user, on top of the groups
+    pub denies:         Vec<Permission>, // Per-user, overrides groups and grants
+    pub disabled:       bool,
+}
+
+// What anyone may see of another user, ie. the author of a comment
+#[derive(Serialize)]
+pub struct PublicUserInfo {
+    pub username:       String,
+    pub groups:         Vec<String>, // Group names
+}
+
+// A new totp secret, as shown to the user once when it is created
+#[derive(Serialize)]
+pub struct TotpSetup {
+    pub qr_code:        String, // Base64 PNG
+    pub url:            String, // otpauth:// provisioning URI, what the QR code contains
+}
+
+impl TotpSetup {
+    fn new(username: &str, totp_secret: &str, website_url: &str) -> TotpSetup {
+        TotpSetup {
+            qr_code:    DbCrypto::gen_totp_qr(username, totp_secret, website_url),
+            url:        DbCrypto::gen_totp_url(username, totp_secret, website_url),
+        }
+    }
+}
+
+impl UserInfo {
+    // A deny always wins, otherwise a grant or any group with the permission (or "*") allows it
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        let matches = |p: &Permission| *p == permission || *p == Permission::All;
+
+        if self.denies.iter().any(matches) {
+            return false;
+        }
+
+        self.grants.iter().any(matches) || self.groups.iter().any(|group| group.has_permission(permission))
+    }
+
+    pub fn is_in_group(&self, group_name: &str) -> bool {
+        self.groups.iter().any(|group| group.group_name == group_name)
+    }
+
+    // Leaves out permission overrides and account status, which only the user and admins should see
+    pub fn public(&self) -> PublicUserInfo {
+        PublicUserInfo {
+            username:   self.username.to_string(),
+            groups:     self.groups.iter().map(|group| group.group_name.to_string()).collect(),
+        }
+    }
 }
 
 impl MapDB { 
 
-    pub async fn new_user(&self, username: &str, group_id: i64) -> Option<UserInfo> {
-        if group_id == -1 {
-            return None;
+    pub async fn new_user(&self, user_id: i64, username: &str) -> DbResult<UserInfo> {
+        let (disabled,): (bool,) =
+            sqlx::query_as("SELECT disabled 
+                            FROM users
+                            WHERE id=?;")
+                        .bind(user_id)
+                        .fetch_one(&self.pool)
+                        .await?;
+
+        let group_ids: Vec<(i64,)> = 
+            sqlx::query_as("SELECT group_id 
+                            FROM user_group_members
+                            WHERE user_id=?
+                            ORDER BY group_id;")
+                        .bind(user_id)
+                        .fetch_all(&self.pool)
+                        .await?;
+
+        let mut groups = Vec::new();
+
+        for (group_id,) in group_ids {
+            let group = self.get_user_group_by_id(group_id).await?
+                .ok_or_else(|| DbError::NotFound(format!("group {} of user '{}'", group_id, username)))?;
+            groups.push(group);
         }
 
-        Some(UserInfo {
+        let overrides: Vec<(String, bool)> =
+            sqlx::query_as("SELECT permission, allow 
+                            FROM user_permissions
+                            WHERE user_id=?
+                            ORDER BY permission;")
+                        .bind(user_id)
+                        .fetch_all(&self.pool)
+                        .await?;
+
+        let mut grants = Vec::new();
+        let mut denies = Vec::new();
+
+        for (permission, allow) in overrides {
+            if let Ok(permission) = permission.parse::<Permission>() {
+                if allow {
+                    grants.push(permission);
+                } else {
+                    denies.push(permission);
+                }
+            }
+        }
+
+        Ok(UserInfo {
             username: username.to_string(),
-            group: self.get_user_group_by_id(group_id).await.expect("Invalid group on user")
+            groups,
+            grants,
+            denies,
+            disabled,
         })
     }
 
-    pub async fn get_user_by_username(&self, username: &str) -> Option<UserInfo> {
-        let group_id: i64 = 
-            sqlx::query_as("SELECT group_id 
+    pub async fn get_user_by_username(&self, username: &str) -> DbResult<Option<UserInfo>> {
+        let row: Option<(i64,)> = 
+            sqlx::query_as("SELECT id 
                             FROM users
                             WHERE username=?;")
                         .bind(&username)
-                        .fetch_one(&self.pool)
-                        .await.ok().unwrap_or((-1,)).0;
-        self.new_user(&username, group_id).await
+                        .fetch_optional(&self.pool)
+                        .await?;
+
+        match row {
+            Some((user_id,)) => Ok(Some(self.new_user(user_id, &username).await?)),
+            None => Ok(None),
+        }
     }
 
-    pub async fn get_user_by_id(&self, user_id: i64) -> Option<UserInfo> {
-        let row: (i64,String) =  
-            sqlx::query_as("SELECT group_id, username 
+    pub async fn get_user_by_id(&self, user_id: i64) -> DbResult<Option<UserInfo>> {
+        let row: Option<(String,)> =  
+            sqlx::query_as("SELECT username 
                             FROM users
                             WHERE id=?;")
                         .bind(user_id)
-                        .fetch_one(&self.pool)
-                        .await.ok().unwrap_or((-1,"".to_string()));
-        let group_id = row.0;
-        let username = row.1;
-        println!("fetched: {}, {}", username, group_id);
+                        .fetch_optional(&self.pool)
+                        .await?;
 
-        self.new_user(&username, group_id).await
+        match row {
+            Some((username,)) => Ok(Some(self.new_user(user_id, &username).await?)),
+            None => Ok(None),
+        }
     }
 
-    pub async fn get_all_users(&self) -> Vec<UserInfo> {
+    pub async fn get_all_users(&self) -> DbResult<Vec<UserInfo>> {
         let rows: Vec<(i64, String)> =
-            sqlx::query_as("SELECT group_id, username FROM users")
+            sqlx::query_as("SELECT id, username FROM users")
                     .fetch_all(&self.pool)
-                    .await.ok().unwrap_or(Vec::new());
+                    .await?;
 
         let mut users = Vec::new();
 
-        // TODO: Could get all user groups first and pass in just the group itself
-        //       Would then save a db call per user
-        for i in 0..rows.len() {
-            let group_id = rows[i].0;
-            let username = rows[i].1.to_string();
-
-            users.push(self.new_user(&username, group_id).await.unwrap());
+        // TODO: Could get all user groups first and pass in just the groups themselves
+        //       Would then save a few db calls per user
+        for (user_id, username) in rows {
+            users.push(self.new_user(user_id, &username).await?);
         }
         
-        users
-    }
-
-    pub async fn get_user_id(&self, username: &str) -> i64 {
-        let row: (i64,) = sqlx::query_as("SELECT id 
-                                            FROM users 
-                                            WHERE username=?;")
-                .bind(&username)
-                .fetch_one(&self.pool)
-                .await.ok().expect(&format!("Could not query username '{}'", username));
-
-        row.0
+        Ok(users)
     }
 
-    // Returns the salt of the username from the database, or "" if none
-    async fn get_user_salt(&self, username: &str) -> String {
-        let row: (String,) = sqlx::query_as("SELECT salt 
+    pub async fn get_user_id(&self, username: &str) -> DbResult<i64> {
+        let row: Option<(i64,)> = sqlx::query_as("SELECT id 
                                             FROM users 
                                             WHERE username=?;")
                 .bind(&username)
-                .fetch_one(&self.pool)
-                .await.ok().unwrap_or(("".to_string(),));
+                .fetch_optional(&self.pool)
+                .await?;
 
-        row.0
+        match row {
+            Some((user_id,)) => Ok(user_id),
+            None => Err(DbError::NotFound(format!("user '{}'", username))),
+        }
     }
 
-    // Returns the totp secret of the username from the database, or "" if none
-    async fn get_user_totp_secret(&self, username: &str) -> String {
-        let row: (String,) = sqlx::query_as("SELECT totp_secret 
+    // Returns the totp secret and last used totp step of the username, or ("", -1) if none
+    async fn get_user_totp(&self, username: &str) -> DbResult<(String, i64)> {
+        Ok(sqlx::query_as("SELECT totp_secret, totp_last_step 
                                             FROM users 
                                             WHERE username=?;")
                 .bind(&username)
-                .fetch_one(&self.pool)
-                .await.ok().unwrap_or(("".to_string(),));
-
-        row.0
+                .fetch_optional(&self.pool)
+                .await?
+                .unwrap_or(("".to_string(), -1)))
     }
 
     // Adds a new user to the database, 
-    // Computes a salt & hash of provided password to store
-    // Returns the user_id and base64 encoding of a QR of the totp_secret
+    // Computes an Argon2id hash of provided password to store (salt is part of the hash)
+    // Returns the user_id and the QR code and URI of the totp_secret
     // Can only retrieve totp_secret through this, hence one time only
-    pub async fn add_user(&self, username: &str, password: &str) -> (i64, String) {
-        let salt        = DbCrypto::gen_rand_salt();
-        let password    = DbCrypto::password_to_hash(&password, &salt);
+    pub async fn add_user(&self, username: &str, password: &str) -> DbResult<(i64, TotpSetup)> {
+        let salt        = "";
+        let password    = DbCrypto::hash_password(&password);
         let totp_secret = DbCrypto::gen_rand_secret();
-        let qr_code     = DbCrypto::gen_totp_qr(username, &totp_secret);
-        let guest_id = self.get_user_group_id_guest().await; // New users default to guest
+        let totp_setup  = TotpSetup::new(username, &totp_secret, &self.website_url);
+        let guest_id = self.get_user_group_id_guest().await?; // New users default to guest
 
         let user_id = sqlx::query("INSERT INTO users 
                                     (
@@ -137,7 +227,7 @@ impl MapDB {
                 .bind(&password)
                 .bind("")
                 .bind(&salt)
-                .bind(guest_id)
+                .bind(guest_id) // No longer read, memberships are in user_group_members
                 .bind(&totp_secret)
                 .bind(false)
                 .bind(false)
@@ -145,82 +235,341 @@ impl MapDB {
                 .bind(-1)
                 .bind(-1)
                 .execute(&self.pool)
-                .await
-                .expect("Inserting new user into db")
+                .await?
                 .last_insert_rowid();
 
-        (user_id, qr_code)
+        self.add_user_to_group(user_id, guest_id).await?;
+
+        Ok((user_id, totp_setup))
     }
 
-    pub async fn add_user_to_group(&self, user_id: i64, group_id: i64) {
+    // Replaces the user's password with an Argon2id hash of password
+    pub async fn set_user_password(&self, user_id: i64, password: &str) -> DbResult<()> {
         sqlx::query("UPDATE users 
-                            SET group_id=?
+                            SET password=?, salt=?
                             WHERE id=?")
+                .bind(DbCrypto::hash_password(&password))
+                .bind("")
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(())
+    }
+
+    // Adds the user to a group, leaving guest since they now have a real group
+    pub async fn add_user_to_group(&self, user_id: i64, group_id: i64) -> DbResult<()> {
+        sqlx::query("INSERT OR IGNORE INTO user_group_members 
+                                    (user_id, group_id) 
+                            VALUES  (?, ?);")
+                .bind(user_id)
+                .bind(group_id)
+                .execute(&self.pool)
+                .await?;
+
+        let guest_id = self.get_user_group_id_guest().await?;
+
+        if group_id != guest_id {
+            self.remove_user_from_group(user_id, guest_id).await?;
+        }
+        Ok(())
+    }
+
+    // Returns false if the user was not in the group
+    pub async fn remove_user_from_group(&self, user_id: i64, group_id: i64) -> DbResult<bool> {
+        Ok(sqlx::query("DELETE FROM user_group_members WHERE user_id=? AND group_id=?")
+                .bind(user_id)
                 .bind(group_id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected() > 0)
+    }
+
+    // Grants (allow) or denies a permission for just this user, replacing any earlier override
+    pub async fn set_user_permission(&self, user_id: i64, permission: Permission, allow: bool) -> DbResult<()> {
+        sqlx::query("INSERT OR REPLACE INTO user_permissions 
+                                    (user_id, permission, allow) 
+                            VALUES  (?, ?, ?);")
                 .bind(user_id)
+                .bind(permission.as_str())
+                .bind(allow)
                 .execute(&self.pool)
-                .await
-                .expect("Updating user group of user in db");
+                .await?;
+        Ok(())
     }
 
-    pub async fn is_user(&self, username: &str) -> bool {
-        !self.get_user_by_username(username).await.is_none()
+    // Removes a grant or deny, returns false if there was none
+    pub async fn clear_user_permission(&self, user_id: i64, permission: Permission) -> DbResult<bool> {
+        Ok(sqlx::query("DELETE FROM user_permissions WHERE user_id=? AND permission=?")
+                .bind(user_id)
+                .bind(permission.as_str())
+                .execute(&self.pool)
+                .await?
+                .rows_affected() > 0)
+    }
+
+    pub async fn is_user(&self, username: &str) -> DbResult<bool> {
+        Ok(!self.get_user_by_username(username).await?.is_none())
     }
 
     // Is this a valid TOTP code for this username?
-    pub async fn is_user_totp(&self, username: &str, totp: &str) -> bool {
-        let totp_secret = self.get_user_totp_secret(username).await;
-        DbCrypto::is_valid_totp(&totp_secret, totp)
+    // Each code can only be used once, so a code seen by someone else cannot be replayed
+    pub async fn is_user_totp(&self, username: &str, totp: &str) -> DbResult<bool> {
+        let (totp_secret, last_step) = self.get_user_totp(username).await?;
+
+        if totp_secret == "" {
+            return Ok(false);
+        }
+
+        let step = match DbCrypto::get_totp_step(&totp_secret, totp, self.totp_window) {
+            Some(step) if step > last_step => step,
+            _ => return Ok(false),
+        };
+
+        // Only one of two requests racing with the same code can move the step forward
+        let res = sqlx::query("UPDATE users 
+                                    SET totp_last_step=?
+                                    WHERE username=? AND totp_last_step<?")
+                .bind(step)
+                .bind(&username)
+                .bind(step)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(res.rows_affected() == 1)
     }
 
     // Has this user verified their TOTP code since initial registation?
-    pub async fn is_user_totp_verified(&self, username: &str) -> bool {
-        let row: (bool,) = sqlx::query_as("SELECT totp_verified 
+    pub async fn is_user_totp_verified(&self, username: &str) -> DbResult<bool> {
+        let row: Option<(bool,)> = sqlx::query_as("SELECT totp_verified 
                                             FROM users 
                                             WHERE username=?;")
                 .bind(&username)
-                .fetch_one(&self.pool)
-                .await.ok().unwrap_or((false,));
+                .fetch_optional(&self.pool)
+                .await?;
 
-        row.0
+        Ok(row.map(|row| row.0).unwrap_or(false))
     }
 
     // Checks if the provided credentials match a user
     // Returns -1 on failed login, or user id on success
-    pub async fn is_user_login(&self, username: &str, password: &str, totp: &str) -> i64 {
-        if !self.is_user_totp(username, totp).await {
-            return -1;
-        }
+    // The password is checked first so a wrong one does not use up the totp code
+    pub async fn is_user_login(&self, username: &str, password: &str, totp: &str) -> DbResult<i64> {
+        let user_id = self.check_user_password(username, password).await?;
 
-        let salt = self.get_user_salt(username).await;
-
-        // Save us an SQL query if we didn't get a salt and just return -1
-        if salt.eq("") {
-            return -1;
+        if user_id == -1 || !self.is_user_totp(username, totp).await? {
+            return Ok(-1);
         }
 
-        let password = DbCrypto::password_to_hash(&password, &salt);
+        Ok(user_id)
+    }
 
-        let row: (i64,) = sqlx::query_as("SELECT id 
+    // Checks the password alone, for when a recovery code stands in for the TOTP code
+    // Returns -1 on a wrong password or a disabled user, or user id on success
+    // A legacy SHA-256 password hash is upgraded to Argon2id on success
+    pub async fn check_user_password(&self, username: &str, password: &str) -> DbResult<i64> {
+        let row: Option<(i64, String, String)> = sqlx::query_as("SELECT id, password, salt 
                                             FROM users 
-                                            WHERE username=? AND password=?;")
+                                            WHERE username=? AND disabled=0;")
                 .bind(&username)
-                .bind(&password)
-                .fetch_one(&self.pool)
-                .await.ok().unwrap_or((-1,));
+                .fetch_optional(&self.pool)
+                .await?;
+
+        if row.is_none() {
+            return Ok(-1);
+        }
+
+        let (user_id, stored_hash, salt) = row.unwrap();
+
+        if !DbCrypto::verify_password(&password, &stored_hash, &salt) {
+            return Ok(-1);
+        }
+
+        if DbCrypto::is_legacy_hash(&stored_hash) {
+            self.set_user_password(user_id, &password).await?;
+        }
+
+        Ok(user_id)
+    }
+
+    // Gives the user a new totp secret, which must be verified again
+    // Returns the QR code and URI of the new secret, only retrievable here
+    pub async fn reset_user_totp(&self, user_id: i64, username: &str) -> DbResult<TotpSetup> {
+        let totp_secret = DbCrypto::gen_rand_secret();
+
+        sqlx::query("UPDATE users 
+                            SET totp_secret=?, totp_verified=?, totp_last_step=?
+                            WHERE id=?")
+                .bind(&totp_secret)
+                .bind(false)
+                .bind(-1)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?;
 
-        row.0
+        Ok(TotpSetup::new(username, &totp_secret, &self.website_url))
     }
 
     // Updates the database to record that this user has verified their TOTP
-    pub async fn verified_totp(&self, username: &str) {
+    pub async fn verified_totp(&self, username: &str) -> DbResult<()> {
         sqlx::query("UPDATE users 
                             SET totp_verified=?
                             WHERE username=?")
                 .bind(true)
                 .bind(&username)
                 .execute(&self.pool)
-                .await
-                .expect("Updating totp_verified of user in db");
+                .await?;
+        Ok(())
+    }
+
+    // A disabled user is logged out everywhere and their API tokens stop working
+    pub async fn set_user_disabled(&self, user_id: i64, disabled: bool) -> DbResult<()> {
+        sqlx::query("UPDATE users 
+                            SET disabled=?
+                            WHERE id=?")
+                .bind(disabled)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?;
+
+        if disabled {
+            self.delete_user_sessions(user_id).await?;
+        }
+        Ok(())
+    }
+
+    // Locations, files, comments and tracks owned by the user
+    pub async fn get_user_content_count(&self, user_id: i64) -> DbResult<i64> {
+        let (count,): (i64,) = sqlx::query_as("SELECT 
+                                                (SELECT COUNT(*) FROM locations WHERE owner_id=?) +
+                                                (SELECT COUNT(*) FROM files WHERE owner_id=?) +
+                                                (SELECT COUNT(*) FROM comments WHERE owner_id=?) +
+                                                (SELECT COUNT(*) FROM tracks WHERE owner_id=?);")
+                .bind(user_id)
+                .bind(user_id)
+                .bind(user_id)
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count)
+    }
+
+    // Gives everything the user owns to another user
+    pub async fn move_user_content(&self, from_user_id: i64, to_user_id: i64) -> DbResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for table in ["locations", "files", "comments", "tracks"].iter() {
+            sqlx::query(&format!("UPDATE {} SET owner_id=? WHERE owner_id=?", table))
+                    .bind(to_user_id)
+                    .bind(from_user_id)
+                    .execute(&mut tx)
+                    .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Deletes the user along with their sessions, tokens, recovery codes, groups and shares
+    // Anything they own is left as is, see get_user_content_count and move_user_content
+    pub async fn delete_user(&self, user_id: i64) -> DbResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for table in ["user_group_members", "user_permissions", "sessions", "api_tokens", "recovery_codes", "location_shares"].iter() {
+            sqlx::query(&format!("DELETE FROM {} WHERE user_id=?", table))
+                    .bind(user_id)
+                    .execute(&mut tx)
+                    .await?;
+        }
+
+        sqlx::query("DELETE FROM users WHERE id=?")
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{block_on, test_db};
+
+    fn user(groups: Vec<UserGroupInfo>, grants: &[Permission], denies: &[Permission]) -> UserInfo {
+        UserInfo {
+            username:   "alice".to_string(),
+            groups,
+            grants:     grants.to_vec(),
+            denies:     denies.to_vec(),
+            disabled:   false,
+        }
+    }
+
+    fn group(permissions: &[Permission]) -> UserGroupInfo {
+        UserGroupInfo {
+            id:             1,
+            group_name:     "test".to_string(),
+            permissions:    permissions.to_vec(),
+        }
+    }
+
+    #[test]
+    fn deny_beats_grants_and_groups() {
+        let denied = user(vec![group(&[Permission::All])], &[Permission::SaveFile], &[Permission::SaveFile]);
+
+        assert!(!denied.has_permission(Permission::SaveFile));
+        assert!(denied.has_permission(Permission::AddLocation));
+
+        // Denying "*" denies everything
+        let locked = user(vec![group(&[Permission::All])], &[Permission::AddComment], &[Permission::All]);
+        for permission in Permission::ALL.iter() {
+            assert!(!locked.has_permission(*permission), "{}", permission);
+        }
+    }
+
+    #[test]
+    fn grants_add_to_groups() {
+        let commenter = user(vec![group(&[Permission::AddComment])], &[Permission::SaveFile], &[]);
+
+        assert!(commenter.has_permission(Permission::AddComment));
+        assert!(commenter.has_permission(Permission::SaveFile));
+        assert!(!commenter.has_permission(Permission::EditComment));
+
+        let moderator = user(vec![group(&[])], &[Permission::EditOtherComment], &[]);
+        assert!(!moderator.has_permission(Permission::EditComment));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn totp_codes_cannot_be_used_twice() {
+        block_on(async {
+            let db = test_db().await;
+            db.add_user("alice", "correct horse battery").await.unwrap();
+
+            let (totp_secret, _) = db.get_user_totp("alice").await.unwrap();
+            let now = Utc::now().timestamp() as u64;
+            let code = DbCrypto::gen_totp_code(&totp_secret, now);
+
+            assert!(db.is_user_totp("alice", &code).await.unwrap());
+            assert!(!db.is_user_totp("alice", &code).await.unwrap());
+
+            // An earlier code still inside the window can't be used after a later one either
+            let earlier = DbCrypto::gen_totp_code(&totp_secret, now - 30);
+            assert!(!db.is_user_totp("alice", &earlier).await.unwrap());
+
+            let later = DbCrypto::gen_totp_code(&totp_secret, now + 30);
+            assert!(db.is_user_totp("alice", &later).await.unwrap());
+            assert!(!db.is_user_totp("alice", &later).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn unknown_users_have_no_valid_codes() {
+        block_on(async {
+            let db = test_db().await;
+
+            assert!(!db.is_user_totp("nobody", "000000").await.unwrap());
+        });
+    }
+}
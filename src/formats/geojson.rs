@@ -0,0 +1,161 @@
+use serde_json::{json, Value};
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::locations::{is_valid_lat_lon, Viewer};
+use crate::formats::ImportedLocation;
+
+// Kind given to imported points without a "kind" property
+const DEFAULT_KIND: &str = "geojson";
+
+// Builds a FeatureCollection of every location viewer can see, for GIS tools
+pub async fn export_locations(db: &MapDB, viewer: &Viewer) -> DbResult<Value> {
+    let locations = db.get_all_locations(viewer).await?;
+    let mut features = Vec::new();
+
+    for location in locations {
+        let owner = match db.get_user_by_id(location.owner_id).await? {
+            Some(user) => user.username,
+            None => "".to_string(),
+        };
+
+        features.push(json!({
+            "type": "Feature",
+            "id": location.id,
+            "geometry": {
+                "type": "Point",
+                "coordinates": [location.lon, location.lat],
+            },
+            "properties": {
+                "id": location.id,
+                "label": location.label,
+                "kind": location.kind,
+                "owner": owner,
+                "files": db.get_location_filenames(location.id, viewer).await?,
+                "comment_count": db.get_comment_count_on_location(location.id).await?,
+            },
+        }));
+    }
+
+    Ok(json!({
+        "type": "FeatureCollection",
+        "features": features,
+    }))
+}
+
+// Parses a FeatureCollection (or a single Feature) into locations to import
+// Only Point geometries are supported, anything else is an error
+pub fn parse_locations(input: &Value) -> Result<Vec<ImportedLocation>, String> {
+    let features = match input["type"].as_str() {
+        Some("FeatureCollection") => input["features"]
+            .as_array()
+            .ok_or("FeatureCollection has no features array")?
+            .iter()
+            .collect(),
+        Some("Feature") => vec![input],
+        _ => return Err("Expected a FeatureCollection or Feature".to_string()),
+    };
+
+    let mut locations = Vec::new();
+
+    for (i, feature) in features.iter().enumerate() {
+        let geometry = &feature["geometry"];
+
+        if geometry["type"].as_str() != Some("Point") {
+            return Err(format!("Feature {}: only Point geometries can be imported", i));
+        }
+
+        let coordinates = geometry["coordinates"].as_array().ok_or(format!("Feature {}: missing coordinates", i))?;
+        let lon = coordinates.get(0).and_then(|v| v.as_f64());
+        let lat = coordinates.get(1).and_then(|v| v.as_f64());
+
+        if lon.is_none() || lat.is_none() {
+            return Err(format!("Feature {}: coordinates must be [lon, lat]", i));
+        }
+
+        let (lat, lon) = (lat.unwrap(), lon.unwrap());
+
+        if !is_valid_lat_lon(lat, lon) {
+            return Err(format!("Feature {}: coordinates out of range", i));
+        }
+
+        let properties = &feature["properties"];
+        let label = properties["label"]
+            .as_str()
+            .or(properties["name"].as_str())
+            .unwrap_or("");
+        let kind = properties["kind"].as_str().unwrap_or(DEFAULT_KIND);
+
+        locations.push(ImportedLocation {
+            label: label.to_string(),
+            lat,
+            lon,
+            kind: kind.to_string(),
+        });
+    }
+
+    Ok(locations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_become_locations() {
+        let locations = parse_locations(&json!({
+            "type": "FeatureCollection",
+            "features": [
+                { "type": "Feature", "geometry": { "type": "Point", "coordinates": [13.4, 52.5] },
+                  "properties": { "label": "Berlin", "name": "ignored", "kind": "city" } },
+                { "type": "Feature", "geometry": { "type": "Point", "coordinates": [151.2, -33.9, 5.0] },
+                  "properties": { "name": "Sydney" } },
+                { "type": "Feature", "geometry": { "type": "Point", "coordinates": [0, 0] } },
+            ],
+        })).unwrap();
+
+        let locations: Vec<(&str, f64, f64, &str)> = locations
+            .iter()
+            .map(|l| (l.label.as_str(), l.lat, l.lon, l.kind.as_str()))
+            .collect();
+
+        assert_eq!(locations, vec![
+            ("Berlin", 52.5, 13.4, "city"),
+            ("Sydney", -33.9, 151.2, DEFAULT_KIND),
+            ("", 0.0, 0.0, DEFAULT_KIND),
+        ]);
+    }
+
+    #[test]
+    fn single_features_are_accepted() {
+        let locations = parse_locations(&json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [1, 2] },
+            "properties": { "label": "One" },
+        })).unwrap();
+
+        assert_eq!(locations.len(), 1);
+        assert_eq!((locations[0].lat, locations[0].lon), (2.0, 1.0));
+    }
+
+    #[test]
+    fn invalid_features_are_rejected() {
+        let point = |coordinates: Value| json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": coordinates },
+        });
+
+        assert!(parse_locations(&point(json!([0, 90.1]))).is_err());
+        assert!(parse_locations(&point(json!([180.1, 0]))).is_err());
+        assert!(parse_locations(&point(json!([1]))).is_err());
+        assert!(parse_locations(&point(json!(["1", "2"]))).is_err());
+        assert!(parse_locations(&point(json!(null))).is_err());
+
+        assert!(parse_locations(&json!({
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": [[0, 0], [1, 1]] },
+        })).is_err());
+        assert!(parse_locations(&json!({ "type": "FeatureCollection" })).is_err());
+        assert!(parse_locations(&json!({ "type": "Point", "coordinates": [0, 0] })).is_err());
+    }
+}
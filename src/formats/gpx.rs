@@ -0,0 +1,377 @@
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::locations::Viewer;
+use crate::db::tracks::TrackPoint;
+use crate::formats::{parse_lat_lon, xml_escape, ImportedData, ImportedLocation, ImportedTrack};
+
+// Kinds used when a waypoint/track has no <sym> or <type>
+const DEFAULT_WAYPOINT_KIND: &str = "waypoint";
+const DEFAULT_TRACK_KIND: &str = "track";
+
+// Waypoint currently being read
+struct Waypoint {
+    lat: f64,
+    lon: f64,
+    name: String,
+    sym: String,
+    kind: String,
+}
+
+// State while walking through the GPX document
+struct GpxParser {
+    data: ImportedData,
+    tag: String,
+    waypoint: Option<Waypoint>,
+    track: Option<ImportedTrack>,
+    track_segments: usize,  // Segments of the current track already added as tracks
+    point: Option<TrackPoint>,
+}
+
+impl GpxParser {
+    fn push_track(&mut self, mut track: ImportedTrack) {
+        if track.kind.is_empty() {
+            track.kind = DEFAULT_TRACK_KIND.to_string();
+        }
+        self.data.tracks.push(track);
+    }
+
+    fn start(&mut self, element: &BytesStart, reader: &Reader<&[u8]>) -> Result<(), String> {
+        self.tag = String::from_utf8_lossy(element.local_name()).to_string();
+
+        match element.local_name() {
+            b"wpt" => {
+                let (lat, lon) = read_lat_lon(element, reader)?;
+                self.waypoint = Some(Waypoint {
+                    lat,
+                    lon,
+                    name: String::new(),
+                    sym: String::new(),
+                    kind: String::new(),
+                });
+            }
+            b"trk" | b"rte" => {
+                self.track = Some(ImportedTrack {
+                    label: String::new(),
+                    kind: String::new(),
+                    points: Vec::new(),
+                });
+            }
+            b"trkpt" | b"rtept" => {
+                let (lat, lon) = read_lat_lon(element, reader)?;
+                self.point = Some(TrackPoint { lat, lon, ele: None, time: None });
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str) {
+        // Text belongs to the innermost open waypoint, point or track
+        if let Some(point) = self.point.as_mut() {
+            match self.tag.as_str() {
+                "ele" => point.ele = text.trim().parse::<f64>().ok(),
+                "time" => point.time = DateTime::parse_from_rfc3339(text.trim())
+                    .ok()
+                    .map(|t| t.timestamp() as f64),
+                _ => (),
+            }
+        }
+        else if let Some(waypoint) = self.waypoint.as_mut() {
+            match self.tag.as_str() {
+                "name" => waypoint.name = text.to_string(),
+                "sym" => waypoint.sym = text.to_string(),
+                "type" => waypoint.kind = text.to_string(),
+                _ => (),
+            }
+        }
+        else if let Some(track) = self.track.as_mut() {
+            match self.tag.as_str() {
+                "name" => track.label = text.to_string(),
+                "type" => track.kind = text.to_string(),
+                _ => (),
+            }
+        }
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        self.tag = String::new();
+
+        match name {
+            b"wpt" => {
+                if let Some(waypoint) = self.waypoint.take() {
+                    let kind = if !waypoint.sym.is_empty() {
+                        waypoint.sym
+                    } else if !waypoint.kind.is_empty() {
+                        waypoint.kind
+                    } else {
+                        DEFAULT_WAYPOINT_KIND.to_string()
+                    };
+
+                    self.data.locations.push(ImportedLocation {
+                        label: waypoint.name,
+                        lat: waypoint.lat,
+                        lon: waypoint.lon,
+                        kind,
+                    });
+                }
+            }
+            b"trkpt" | b"rtept" => {
+                if let (Some(point), Some(track)) = (self.point.take(), self.track.as_mut()) {
+                    track.points.push(point);
+                }
+            }
+            b"trkseg" => {
+                // Every segment becomes its own track, the gap between two segments is not part of the route
+                if let Some(mut track) = self.track.take() {
+                    if track.points.len() > 0 {
+                        let points = std::mem::take(&mut track.points);
+                        self.push_track(ImportedTrack {
+                            label: track.label.to_string(),
+                            kind: track.kind.to_string(),
+                            points,
+                        });
+                        self.track_segments += 1;
+                    }
+                    self.track = Some(track);
+                }
+            }
+            b"trk" | b"rte" => {
+                if let Some(track) = self.track.take() {
+                    // Points outside of a <trkseg> (routes), or a track without any points
+                    if track.points.len() > 0 || self.track_segments == 0 {
+                        self.push_track(track);
+                    }
+                    self.track_segments = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// Reads the lat/lon attributes of a <wpt>, <trkpt> or <rtept>
+fn read_lat_lon(element: &BytesStart, reader: &Reader<&[u8]>) -> Result<(f64, f64), String> {
+    let mut lat = String::new();
+    let mut lon = String::new();
+
+    for attr in element.attributes() {
+        let attr = attr.map_err(|e| format!("Invalid attribute: {}", e))?;
+        let value = attr.unescape_and_decode_value(reader).map_err(|e| format!("Invalid attribute: {}", e))?;
+
+        match attr.key {
+            b"lat" => lat = value,
+            b"lon" => lon = value,
+            _ => (),
+        }
+    }
+
+    parse_lat_lon(&lat, &lon).ok_or(format!("Invalid lat/lon at position {}", reader.buffer_position()))
+}
+
+// Parses GPX waypoints into locations, and track segments/routes into tracks
+// A waypoint's <sym> (or else <type>) is kept as the location kind
+pub fn parse(input: &str) -> Result<ImportedData, String> {
+    let mut reader = Reader::from_str(input);
+    reader.trim_text(true);
+
+    let mut parser = GpxParser {
+        data: ImportedData {
+            locations: Vec::new(),
+            tracks: Vec::new(),
+        },
+        tag: String::new(),
+        waypoint: None,
+        track: None,
+        track_segments: 0,
+        point: None,
+    };
+
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => parser.start(e, &reader)?,
+            Ok(Event::Empty(ref e)) => {
+                parser.start(e, &reader)?;
+                parser.end(e.local_name());
+            }
+            Ok(Event::Text(ref e)) | Ok(Event::CData(ref e)) => {
+                let text = e.unescape_and_decode(&reader).map_err(|e| format!("Invalid text: {}", e))?;
+                parser.text(&text);
+            }
+            Ok(Event::End(ref e)) => parser.end(e.local_name()),
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("Invalid GPX at position {}: {}", reader.buffer_position(), e)),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(parser.data)
+}
+
+// Formats a unix timestamp as an RFC 3339 time for <time>
+fn format_time(time: f64) -> String {
+    Utc.timestamp_opt(time as i64, 0)
+        .unwrap()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+const GPX_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                          <gpx version=\"1.1\" creator=\"MyMap\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n";
+const GPX_FOOTER: &str = "</gpx>\n";
+
+// Writes a location as a <wpt>
+fn waypoint(label: &str, lat: f32, lon: f32, kind: &str) -> String {
+    let mut gpx = format!("  <wpt lat=\"{}\" lon=\"{}\">\n", lat, lon);
+    gpx.push_str(&format!("    <name>{}</name>\n", xml_escape(label)));
+    gpx.push_str(&format!("    <sym>{}</sym>\n", xml_escape(kind)));
+    gpx.push_str(&format!("    <type>{}</type>\n", xml_escape(kind)));
+    gpx.push_str("  </wpt>\n");
+    gpx
+}
+
+// Writes a track as a <trk> with a single <trkseg>
+fn track(label: &str, kind: &str, points: &Vec<TrackPoint>) -> String {
+    let mut gpx = String::from("  <trk>\n");
+    gpx.push_str(&format!("    <name>{}</name>\n", xml_escape(label)));
+    gpx.push_str(&format!("    <type>{}</type>\n", xml_escape(kind)));
+    gpx.push_str("    <trkseg>\n");
+
+    for point in points {
+        gpx.push_str(&format!("      <trkpt lat=\"{}\" lon=\"{}\">", point.lat, point.lon));
+        if let Some(ele) = point.ele {
+            gpx.push_str(&format!("<ele>{}</ele>", ele));
+        }
+        if let Some(time) = point.time {
+            gpx.push_str(&format!("<time>{}</time>", format_time(time)));
+        }
+        gpx.push_str("</trkpt>\n");
+    }
+
+    gpx.push_str("    </trkseg>\n");
+    gpx.push_str("  </trk>\n");
+    gpx
+}
+
+// Writes all locations viewer can see as waypoints and all tracks as tracks
+pub async fn export(db: &MapDB, viewer: &Viewer) -> DbResult<String> {
+    let mut gpx = String::from(GPX_HEADER);
+
+    for location in db.get_all_locations(viewer).await? {
+        gpx.push_str(&waypoint(&location.label, location.lat, location.lon, &location.kind));
+    }
+
+    for track_data in db.get_all_tracks(viewer).await? {
+        gpx.push_str(&track(&track_data.label, &track_data.kind, &track_data.points));
+    }
+
+    gpx.push_str(GPX_FOOTER);
+    Ok(gpx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waypoints_become_locations() {
+        let data = parse(r#"<?xml version="1.0"?>
+            <gpx version="1.1">
+              <wpt lat="52.5" lon="13.4"><name>Berlin</name><sym>city</sym><type>capital</type></wpt>
+              <wpt lat="-33.9" lon="151.2"><name>Sydney</name><type>harbour</type></wpt>
+              <wpt lat="0" lon="0"/>
+            </gpx>"#).unwrap();
+
+        let locations: Vec<(&str, f64, f64, &str)> = data.locations
+            .iter()
+            .map(|l| (l.label.as_str(), l.lat, l.lon, l.kind.as_str()))
+            .collect();
+
+        assert_eq!(locations, vec![
+            ("Berlin", 52.5, 13.4, "city"),
+            ("Sydney", -33.9, 151.2, "harbour"),
+            ("", 0.0, 0.0, DEFAULT_WAYPOINT_KIND),
+        ]);
+        assert!(data.tracks.is_empty());
+    }
+
+    #[test]
+    fn each_track_segment_becomes_a_track() {
+        let data = parse(r#"<gpx>
+              <trk>
+                <name>Walk</name>
+                <trkseg>
+                  <trkpt lat="1" lon="2"><ele>10.5</ele><time>2021-06-01T12:00:00Z</time></trkpt>
+                  <trkpt lat="1.1" lon="2.1"/>
+                </trkseg>
+                <trkseg>
+                  <trkpt lat="3" lon="4"/>
+                </trkseg>
+              </trk>
+              <rte><name>Route</name><type>bike</type><rtept lat="5" lon="6"/></rte>
+            </gpx>"#).unwrap();
+
+        let tracks: Vec<(&str, &str, usize)> = data.tracks
+            .iter()
+            .map(|t| (t.label.as_str(), t.kind.as_str(), t.points.len()))
+            .collect();
+
+        assert_eq!(tracks, vec![
+            ("Walk", DEFAULT_TRACK_KIND, 2),
+            ("Walk", DEFAULT_TRACK_KIND, 1),
+            ("Route", "bike", 1),
+        ]);
+
+        let first = &data.tracks[0].points[0];
+        assert_eq!((first.lat, first.lon, first.ele, first.time), (1.0, 2.0, Some(10.5), Some(1622548800.0)));
+        assert_eq!(data.tracks[1].points[0].lat, 3.0);
+    }
+
+    #[test]
+    fn invalid_coordinates_are_rejected() {
+        for (lat, lon) in &[("NaN", "0"), ("0", "inf"), ("90.1", "0"), ("0", "-180.1"), ("", "0"), ("north", "0")] {
+            let waypoint = format!(r#"<gpx><wpt lat="{}" lon="{}"/></gpx>"#, lat, lon);
+            assert!(parse(&waypoint).is_err(), "accepted wpt {}, {}", lat, lon);
+
+            let point = format!(r#"<gpx><trk><trkseg><trkpt lat="{}" lon="{}"/></trkseg></trk></gpx>"#, lat, lon);
+            assert!(parse(&point).is_err(), "accepted trkpt {}, {}", lat, lon);
+        }
+    }
+
+    #[test]
+    fn malformed_xml_is_rejected() {
+        assert!(parse("<gpx><wpt lat=\"1\" lon=\"2\"></gpx>").is_err());
+    }
+
+    #[test]
+    fn export_round_trips_escaped_text() {
+        let label = r#"Fish & <Chips> "Bar" 'n' Grill"#;
+        let kind = "café & bar";
+        let points = vec![
+            TrackPoint { lat: 1.5, lon: -2.5, ele: Some(100.0), time: Some(1622548800.0) },
+            TrackPoint { lat: 1.6, lon: -2.6, ele: None, time: None },
+        ];
+
+        let gpx = format!("{}{}{}{}", GPX_HEADER, waypoint(label, 1.5, -2.5, kind), track(label, kind, &points), GPX_FOOTER);
+        let data = parse(&gpx).unwrap();
+
+        assert_eq!(data.locations.len(), 1);
+        assert_eq!((data.locations[0].label.as_str(), data.locations[0].kind.as_str()), (label, kind));
+        assert_eq!((data.locations[0].lat, data.locations[0].lon), (1.5, -2.5));
+
+        assert_eq!(data.tracks.len(), 1);
+        assert_eq!((data.tracks[0].label.as_str(), data.tracks[0].kind.as_str()), (label, kind));
+
+        let read: Vec<(f64, f64, Option<f64>, Option<f64>)> = data.tracks[0].points
+            .iter()
+            .map(|p| (p.lat, p.lon, p.ele, p.time))
+            .collect();
+        assert_eq!(read, vec![(1.5, -2.5, Some(100.0), Some(1622548800.0)), (1.6, -2.6, None, None)]);
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::locations::Viewer;
+use crate::db::tracks::TrackPoint;
+use crate::formats::{parse_lat_lon, xml_escape, ImportedData, ImportedLocation, ImportedTrack};
+
+// Kind used for placemarks that are not inside a named <Folder>
+const DEFAULT_KIND: &str = "placemark";
+
+// Placemark currently being read
+struct Placemark {
+    name: String,
+    point: Option<(f64, f64)>,
+    line: Vec<TrackPoint>,
+}
+
+// State while walking through the KML document
+struct KmlParser {
+    data: ImportedData,
+    tag: String,
+    folders: Vec<String>,
+    placemark: Option<Placemark>,
+    in_point: bool,
+    in_line: bool,
+}
+
+impl KmlParser {
+    fn start(&mut self, element: &BytesStart) {
+        self.tag = String::from_utf8_lossy(element.local_name()).to_string();
+
+        match element.local_name() {
+            b"Folder" => self.folders.push(String::new()),
+            b"Placemark" => {
+                self.placemark = Some(Placemark {
+                    name: String::new(),
+                    point: None,
+                    line: Vec::new(),
+                });
+            }
+            b"Point" => self.in_point = true,
+            b"LineString" => self.in_line = true,
+            _ => (),
+        }
+    }
+
+    fn text(&mut self, text: &str) -> Result<(), String> {
+        if let Some(placemark) = self.placemark.as_mut() {
+            match self.tag.as_str() {
+                "name" => placemark.name = text.to_string(),
+                "coordinates" if self.in_point => {
+                    let tuple = text.split_whitespace().next().unwrap_or("");
+                    placemark.point = Some(parse_coordinate(tuple)?);
+                }
+                "coordinates" if self.in_line => {
+                    for tuple in text.split_whitespace() {
+                        let (lat, lon) = parse_coordinate(tuple)?;
+                        let ele = tuple.split(',').nth(2).and_then(|v| v.parse::<f64>().ok());
+                        placemark.line.push(TrackPoint { lat, lon, ele, time: None });
+                    }
+                }
+                _ => (),
+            }
+        }
+        else if self.tag == "name" {
+            // Only the first <name> directly in a folder names it
+            if let Some(folder) = self.folders.last_mut() {
+                if folder.is_empty() {
+                    *folder = text.to_string();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn end(&mut self, name: &[u8]) {
+        self.tag = String::new();
+
+        match name {
+            b"Folder" => {
+                self.folders.pop();
+            }
+            b"Point" => self.in_point = false,
+            b"LineString" => self.in_line = false,
+            b"Placemark" => {
+                if let Some(placemark) = self.placemark.take() {
+                    let kind = self.folders
+                        .iter()
+                        .rev()
+                        .find(|f| !f.is_empty())
+                        .cloned()
+                        .unwrap_or(DEFAULT_KIND.to_string());
+
+                    if let Some((lat, lon)) = placemark.point {
+                        self.data.locations.push(ImportedLocation {
+                            label: placemark.name,
+                            lat,
+                            lon,
+                            kind,
+                        });
+                    }
+                    else if placemark.line.len() > 0 {
+                        self.data.tracks.push(ImportedTrack {
+                            label: placemark.name,
+                            kind,
+                            points: placemark.line,
+                        });
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// Parses a KML "lon,lat[,alt]" tuple
+fn parse_coordinate(tuple: &str) -> Result<(f64, f64), String> {
+    let values: Vec<&str> = tuple.split(',').collect();
+
+    if values.len() < 2 {
+        return Err(format!("Invalid coordinates '{}'", tuple));
+    }
+
+    parse_lat_lon(values[1], values[0]).ok_or(format!("Invalid coordinates '{}'", tuple))
+}
+
+// Parses KML Point placemarks into locations and LineString placemarks into tracks
+// The name of the enclosing <Folder> is kept as the kind
+pub fn parse(input: &str) -> Result<ImportedData, String> {
+    let mut reader = Reader::from_str(input);
+    reader.trim_text(true);
+
+    let mut parser = KmlParser {
+        data: ImportedData {
+            locations: Vec::new(),
+            tracks: Vec::new(),
+        },
+        tag: String::new(),
+        folders: Vec::new(),
+        placemark: None,
+        in_point: false,
+        in_line: false,
+    };
+
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => parser.start(e),
+            Ok(Event::Empty(ref e)) => {
+                parser.start(e);
+                parser.end(e.local_name());
+            }
+            Ok(Event::Text(ref e)) | Ok(Event::CData(ref e)) => {
+                let text = e.unescape_and_decode(&reader).map_err(|e| format!("Invalid text: {}", e))?;
+                parser.text(&text)?;
+            }
+            Ok(Event::End(ref e)) => parser.end(e.local_name()),
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(format!("Invalid KML at position {}: {}", reader.buffer_position(), e)),
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(parser.data)
+}
+
+// Writes a Placemark with a single Point
+fn point_placemark(label: &str, lat: f64, lon: f64) -> String {
+    format!("      <Placemark>\n        <name>{}</name>\n        <Point><coordinates>{},{}</coordinates></Point>\n      </Placemark>\n",
+            xml_escape(label), lon, lat)
+}
+
+// Writes a Placemark with a LineString through all points
+fn line_placemark(label: &str, points: &Vec<TrackPoint>) -> String {
+    let coordinates: Vec<String> = points
+        .iter()
+        .map(|p| match p.ele {
+            Some(ele) => format!("{},{},{}", p.lon, p.lat, ele),
+            None => format!("{},{}", p.lon, p.lat),
+        })
+        .collect();
+
+    format!("      <Placemark>\n        <name>{}</name>\n        <LineString><coordinates>{}</coordinates></LineString>\n      </Placemark>\n",
+            xml_escape(label), coordinates.join(" "))
+}
+
+// Writes all locations viewer can see and all tracks, with one Folder per kind
+pub async fn export(db: &MapDB, viewer: &Viewer) -> DbResult<String> {
+    let mut folders: BTreeMap<String, String> = BTreeMap::new();
+
+    for location in db.get_all_locations(viewer).await? {
+        folders.entry(location.kind.to_string())
+            .or_insert(String::new())
+            .push_str(&point_placemark(&location.label, location.lat as f64, location.lon as f64));
+    }
+
+    for track in db.get_all_tracks(viewer).await? {
+        folders.entry(track.kind.to_string())
+            .or_insert(String::new())
+            .push_str(&line_placemark(&track.label, &track.points));
+    }
+
+    let mut kml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    kml.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n");
+    kml.push_str("  <Document>\n");
+    kml.push_str("    <name>MyMap</name>\n");
+
+    for (kind, placemarks) in folders {
+        kml.push_str("    <Folder>\n");
+        kml.push_str(&format!("      <name>{}</name>\n", xml_escape(&kind)));
+        kml.push_str(&placemarks);
+        kml.push_str("    </Folder>\n");
+    }
+
+    kml.push_str("  </Document>\n");
+    kml.push_str("</kml>\n");
+    Ok(kml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placemarks_take_the_kind_of_their_folder() {
+        let data = parse(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <kml xmlns="http://www.opengis.net/kml/2.2">
+              <Document>
+                <name>Trip</name>
+                <Placemark><name>Loose</name><Point><coordinates>1,2</coordinates></Point></Placemark>
+                <Folder>
+                  <name>food</name>
+                  <Placemark><name>Cafe</name><Point><coordinates>13.4,52.5,34</coordinates></Point></Placemark>
+                  <Folder>
+                    <name>bakery</name>
+                    <Placemark><name>Bread</name><Point><coordinates> 13.5,52.6 </coordinates></Point></Placemark>
+                  </Folder>
+                  <Folder>
+                    <Placemark><name>Unnamed folder</name><Point><coordinates>13.6,52.7</coordinates></Point></Placemark>
+                  </Folder>
+                  <Placemark><name>After</name><Point><coordinates>13.7,52.8</coordinates></Point></Placemark>
+                </Folder>
+              </Document>
+            </kml>"#).unwrap();
+
+        let locations: Vec<(&str, f64, f64, &str)> = data.locations
+            .iter()
+            .map(|l| (l.label.as_str(), l.lat, l.lon, l.kind.as_str()))
+            .collect();
+
+        assert_eq!(locations, vec![
+            ("Loose", 2.0, 1.0, DEFAULT_KIND),
+            ("Cafe", 52.5, 13.4, "food"),
+            ("Bread", 52.6, 13.5, "bakery"),
+            ("Unnamed folder", 52.7, 13.6, "food"),
+            ("After", 52.8, 13.7, "food"),
+        ]);
+        assert!(data.tracks.is_empty());
+    }
+
+    #[test]
+    fn line_strings_become_tracks() {
+        let data = parse(r#"<kml><Folder><name>hikes</name>
+                <Placemark>
+                  <name>Ridge</name>
+                  <LineString><coordinates>
+                    8.1,46.1,1200 8.2,46.2,1300
+                    8.3,46.3
+                  </coordinates></LineString>
+                </Placemark>
+            </Folder></kml>"#).unwrap();
+
+        assert!(data.locations.is_empty());
+        assert_eq!(data.tracks.len(), 1);
+        assert_eq!((data.tracks[0].label.as_str(), data.tracks[0].kind.as_str()), ("Ridge", "hikes"));
+
+        let points: Vec<(f64, f64, Option<f64>)> = data.tracks[0].points.iter().map(|p| (p.lat, p.lon, p.ele)).collect();
+        assert_eq!(points, vec![(46.1, 8.1, Some(1200.0)), (46.2, 8.2, Some(1300.0)), (46.3, 8.3, None)]);
+    }
+
+    #[test]
+    fn invalid_coordinates_are_rejected() {
+        for coordinates in &["NaN,0", "0,inf", "0,90.1", "180.1,0", "1", "east,north"] {
+            let point = format!("<kml><Placemark><Point><coordinates>{}</coordinates></Point></Placemark></kml>", coordinates);
+            assert!(parse(&point).is_err(), "accepted Point {}", coordinates);
+
+            let line = format!("<kml><Placemark><LineString><coordinates>1,2 {}</coordinates></LineString></Placemark></kml>", coordinates);
+            assert!(parse(&line).is_err(), "accepted LineString {}", coordinates);
+        }
+    }
+
+    #[test]
+    fn export_round_trips_escaped_text() {
+        let label = r#"Fish & <Chips> "Bar" 'n' Grill"#;
+        let kind = "café & bar";
+        let points = vec![
+            TrackPoint { lat: 1.5, lon: -2.5, ele: Some(100.0), time: None },
+            TrackPoint { lat: 1.6, lon: -2.6, ele: None, time: None },
+        ];
+
+        let kml = format!("<kml><Folder><name>{}</name>\n{}{}</Folder></kml>",
+                          xml_escape(kind), point_placemark(label, 1.5, -2.5), line_placemark(label, &points));
+        let data = parse(&kml).unwrap();
+
+        assert_eq!(data.locations.len(), 1);
+        assert_eq!((data.locations[0].label.as_str(), data.locations[0].kind.as_str()), (label, kind));
+        assert_eq!((data.locations[0].lat, data.locations[0].lon), (1.5, -2.5));
+
+        assert_eq!(data.tracks.len(), 1);
+        assert_eq!((data.tracks[0].label.as_str(), data.tracks[0].kind.as_str()), (label, kind));
+
+        let read: Vec<(f64, f64, Option<f64>)> = data.tracks[0].points.iter().map(|p| (p.lat, p.lon, p.ele)).collect();
+        assert_eq!(read, vec![(1.5, -2.5, Some(100.0)), (1.6, -2.6, None)]);
+    }
+}
@@ -0,0 +1,223 @@
+use serde::Serialize;
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::locations::{is_valid_lat_lon, Viewer};
+use crate::db::tracks::TrackPoint;
+
+pub mod geojson;
+pub mod gpx;
+pub mod kml;
+
+// A location read from an import file, not yet saved to the db
+pub struct ImportedLocation {
+    pub label: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub kind: String,
+}
+
+// A track read from an import file, not yet saved to the db
+pub struct ImportedTrack {
+    pub label: String,
+    pub kind: String,
+    pub points: Vec<TrackPoint>,
+}
+
+// Everything read from one import file
+pub struct ImportedData {
+    pub locations: Vec<ImportedLocation>,
+    pub tracks: Vec<ImportedTrack>,
+}
+
+// A location that was not imported because it already exists
+#[derive(Serialize)]
+pub struct ImportDuplicate {
+    pub label: String,
+    pub lat: f64,
+    pub lon: f64,
+    pub kind: String,
+    pub existing_ids: Vec<i64>,
+}
+
+// Summary of an import, returned to the web client and printed by the cli
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub created: Vec<i64>,
+    pub would_create: usize,
+    pub duplicates: Vec<ImportDuplicate>,
+    pub tracks_created: Vec<i64>,
+    pub would_create_tracks: usize,
+}
+
+impl ImportReport {
+    pub fn print(&self) {
+        if self.dry_run {
+            println!("Dry run, nothing was saved.");
+            println!("Would create {} location(s), {} track(s)", self.would_create, self.would_create_tracks);
+        } else {
+            println!("Created {} location(s), {} track(s)", self.created.len(), self.tracks_created.len());
+        }
+
+        println!("Skipped {} duplicate(s)", self.duplicates.len());
+        for dup in &self.duplicates {
+            println!("  '{}' ({}, {}, {}) already exists as {:?}", dup.label, dup.lat, dup.lon, dup.kind, dup.existing_ids);
+        }
+    }
+}
+
+// Parses an import file of the given format ("geojson", "gpx" or "kml")
+pub fn parse_import(format: &str, input: &[u8]) -> Result<ImportedData, String> {
+    match format {
+        "geojson" => {
+            let input: serde_json::Value = serde_json::from_slice(input).map_err(|e| format!("Invalid json: {}", e))?;
+
+            Ok(ImportedData {
+                locations: geojson::parse_locations(&input)?,
+                tracks: Vec::new(),
+            })
+        }
+        "gpx" => gpx::parse(&String::from_utf8_lossy(input)),
+        "kml" => kml::parse(&String::from_utf8_lossy(input)),
+        _ => Err(format!("Unknown import format '{}'", format)),
+    }
+}
+
+// Exports all locations and tracks viewer can see in the given format ("geojson", "gpx" or "kml")
+pub async fn export(format: &str, db: &MapDB, viewer: &Viewer) -> Result<String, String> {
+    match format {
+        "geojson" => geojson::export_locations(db, viewer).await.map(|v| v.to_string()).map_err(|e| e.to_string()),
+        "gpx" => gpx::export(db, viewer).await.map_err(|e| e.to_string()),
+        "kml" => kml::export(db, viewer).await.map_err(|e| e.to_string()),
+        _ => Err(format!("Unknown export format '{}'", format)),
+    }
+}
+
+// Saves imported locations with visibility and tracks owned by owner_id, skipping locations that already exist
+// With dry_run set nothing is written, only the report is built
+pub async fn import(db: &MapDB, data: &ImportedData, owner_id: i64, visibility: &str, dry_run: bool) -> DbResult<ImportReport> {
+    let mut report = ImportReport {
+        dry_run,
+        created: Vec::new(),
+        would_create: 0,
+        duplicates: Vec::new(),
+        tracks_created: Vec::new(),
+        would_create_tracks: data.tracks.len(),
+    };
+
+    // A dry run saves nothing, so repeats within the file are found here instead of in the db
+    // They are reported as duplicates without existing ids, like a real import reports them with the new id
+    let mut pending: Vec<&ImportedLocation> = Vec::new();
+
+    for location in &data.locations {
+        let existing_ids = db.get_location_ids(&location.label, location.lat, location.lon, &location.kind, -1, &Viewer::user(owner_id, false)).await?;
+        let repeated = dry_run && pending.iter().any(|other| other.label == location.label
+                                                              && other.lat == location.lat
+                                                              && other.lon == location.lon
+                                                              && other.kind == location.kind);
+
+        if existing_ids.len() > 0 || repeated {
+            report.duplicates.push(ImportDuplicate {
+                label: location.label.to_string(),
+                lat: location.lat,
+                lon: location.lon,
+                kind: location.kind.to_string(),
+                existing_ids,
+            });
+            continue;
+        }
+
+        report.would_create += 1;
+
+        if dry_run {
+            pending.push(location);
+        } else {
+            report.created.push(db.add_location(&location.label, location.lat, location.lon, &location.kind, visibility, owner_id).await?);
+        }
+    }
+
+    if !dry_run {
+        for track in &data.tracks {
+            report.tracks_created.push(db.add_track(&track.label, &track.kind, owner_id, &track.points).await?);
+        }
+    }
+
+    Ok(report)
+}
+
+// Escapes text for use inside xml elements and attributes
+pub fn xml_escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Parses a lat/lon pair, checking that it is in range
+fn parse_lat_lon(lat: &str, lon: &str) -> Option<(f64, f64)> {
+    let lat = lat.trim().parse::<f64>().ok()?;
+    let lon = lon.trim().parse::<f64>().ok()?;
+
+    if !is_valid_lat_lon(lat, lon) {
+        return None;
+    }
+
+    Some((lat, lon))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{block_on, test_db};
+
+    fn location(label: &str, lat: f64, lon: f64, kind: &str) -> ImportedLocation {
+        ImportedLocation {
+            label: label.to_string(),
+            lat,
+            lon,
+            kind: kind.to_string(),
+        }
+    }
+
+    fn duplicate_labels(report: &ImportReport) -> Vec<(&str, usize)> {
+        report.duplicates.iter().map(|d| (d.label.as_str(), d.existing_ids.len())).collect()
+    }
+
+    #[test]
+    fn dry_run_reports_repeats_within_the_file() {
+        block_on(async {
+            let db = test_db().await;
+            let (owner_id, _) = db.add_user("importer", "correct horse battery").await.unwrap();
+            db.add_location(&"Saved".to_string(), 10.0, 20.0, &"pin".to_string(), "private", owner_id).await.unwrap();
+
+            let data = ImportedData {
+                locations: vec![
+                    location("Saved", 10.0, 20.0, "pin"),
+                    location("New", 1.0, 2.0, "pin"),
+                    location("New", 1.0, 2.0, "pin"),
+                    location("New", 1.0, 2.0, "other"),
+                ],
+                tracks: Vec::new(),
+            };
+
+            let dry_run = import(&db, &data, owner_id, "private", true).await.unwrap();
+            assert_eq!(dry_run.would_create, 2);
+            assert!(dry_run.created.is_empty());
+            assert_eq!(duplicate_labels(&dry_run), vec![("Saved", 1), ("New", 0)]);
+
+            // A real import of the same file creates and skips the same locations
+            let real = import(&db, &data, owner_id, "private", false).await.unwrap();
+            assert_eq!(real.created.len(), dry_run.would_create);
+            assert_eq!(duplicate_labels(&real), vec![("Saved", 1), ("New", 1)]);
+        });
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        assert!(parse_import("csv", b"").is_err());
+        assert!(parse_import("geojson", b"{not json").is_err());
+    }
+}
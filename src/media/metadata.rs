@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use chrono::NaiveDate;
+use exif::{Exif, In, Reader, Tag, Value};
+use serde::Serialize;
+
+// Metadata read from a photo's EXIF, any of it may be missing
+#[derive(Serialize, Default, Clone)]
+pub struct PhotoMetadata {
+    pub taken_date: Option<f64>,
+    pub camera_model: Option<String>,
+    pub orientation: Option<i64>,
+    pub gps_lat: Option<f64>,
+    pub gps_lon: Option<f64>,
+    pub gps_alt: Option<f64>,
+}
+
+impl PhotoMetadata {
+    pub fn has_gps(&self) -> bool {
+        self.gps_lat.is_some() && self.gps_lon.is_some()
+    }
+}
+
+// Reads EXIF from a JPEG/HEIC/TIFF/PNG/WebP file
+// Returns empty metadata if the file has none or could not be read
+pub fn read_metadata(filepath: &str) -> PhotoMetadata {
+    let file = match File::open(filepath) {
+        Ok(file) => file,
+        Err(_) => return PhotoMetadata::default(),
+    };
+
+    match Reader::new().read_from_container(&mut BufReader::new(file)) {
+        Ok(exif) => metadata_from_exif(&exif),
+        Err(_) => PhotoMetadata::default(),
+    }
+}
+
+fn metadata_from_exif(exif: &Exif) -> PhotoMetadata {
+    PhotoMetadata {
+        taken_date: read_taken_date(exif),
+        camera_model: read_ascii(exif, Tag::Model),
+        orientation: exif.get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .map(|v| v as i64),
+        gps_lat: read_gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S", 90.0),
+        gps_lon: read_gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W", 180.0),
+        gps_alt: read_gps_altitude(exif),
+    }
+}
+
+// First string of an ASCII field, trimmed
+fn read_ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) if values.len() > 0 => {
+            let s = String::from_utf8_lossy(&values[0]).trim_matches(char::from(0)).trim().to_string();
+            if s.is_empty() { None } else { Some(s) }
+        }
+        _ => None,
+    }
+}
+
+// Capture time as a unix timestamp, camera local time is treated as UTC unless
+// OffsetTimeOriginal (or OffsetTime for DateTime) records its offset
+fn read_taken_date(exif: &Exif) -> Option<f64> {
+    let (field, offset_tag) = match exif.get_field(Tag::DateTimeOriginal, In::PRIMARY) {
+        Some(field) => (field, Tag::OffsetTimeOriginal),
+        None => (exif.get_field(Tag::DateTime, In::PRIMARY)?, Tag::OffsetTime),
+    };
+
+    let mut dt = match &field.value {
+        Value::Ascii(values) if values.len() > 0 => exif::DateTime::from_ascii(&values[0]).ok()?,
+        _ => return None,
+    };
+
+    // ie. "+09:00", an unreadable offset is the same as none
+    if let Some(Value::Ascii(values)) = exif.get_field(offset_tag, In::PRIMARY).map(|f| &f.value) {
+        if values.len() > 0 {
+            dt.parse_offset(&values[0]).ok();
+        }
+    }
+
+    let timestamp = NaiveDate::from_ymd_opt(dt.year as i32, dt.month as u32, dt.day as u32)?
+        .and_hms_opt(dt.hour as u32, dt.minute as u32, dt.second as u32)?
+        .timestamp();
+
+    let offset_secs = dt.offset.map(|minutes| minutes as i64 * 60).unwrap_or(0);
+
+    Some((timestamp - offset_secs) as f64)
+}
+
+// Degrees/minutes/seconds to signed decimal degrees, None if it is not a finite value up to max_degrees
+fn read_gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str, max_degrees: f64) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() >= 3 => v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0,
+        Value::Rational(v) if v.len() >= 1 => v[0].to_f64(),
+        _ => return None,
+    };
+
+    if !degrees.is_finite() || degrees.abs() > max_degrees {
+        return None;
+    }
+
+    match read_ascii(exif, ref_tag) {
+        Some(r) if r.eq_ignore_ascii_case(negative_ref) => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+// Altitude in meters, negative when GPSAltitudeRef says below sea level
+fn read_gps_altitude(exif: &Exif) -> Option<f64> {
+    let altitude = match &exif.get_field(Tag::GPSAltitude, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() > 0 => v[0].to_f64(),
+        _ => return None,
+    };
+
+    if !altitude.is_finite() {
+        return None;
+    }
+
+    let below_sea_level = exif.get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .unwrap_or(0) == 1;
+
+    Some(if below_sea_level { -altitude } else { altitude })
+}
@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+
+pub mod metadata;
+pub mod s3;
+pub mod sniff;
+pub mod store;
+pub mod thumbnails;
+
+// Uploads are streamed into staging_dir (the upload_dir setting) and checked before they go into the media store
+pub fn create_staging_dir(staging_dir: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(staging_dir)
+}
+
+// Path of an upload that is still being received
+pub fn staging_path(staging_dir: &str, filename: &str) -> String {
+    PathBuf::from(staging_dir).join(filename).to_string_lossy().to_string()
+}
@@ -0,0 +1,146 @@
+// Number of leading bytes needed to recognise every allowed type
+pub const SNIFF_LEN: usize = 16;
+
+// A file type that may be uploaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MediaType {
+    pub mime: &'static str,
+    pub extension: &'static str,
+    pub is_image: bool,       // Has EXIF worth reading
+    pub has_thumbnails: bool, // Can be decoded to generate thumbnails
+}
+
+pub const JPEG: MediaType = MediaType { mime: "image/jpeg", extension: "jpg", is_image: true, has_thumbnails: true };
+pub const PNG: MediaType = MediaType { mime: "image/png", extension: "png", is_image: true, has_thumbnails: true };
+pub const WEBP: MediaType = MediaType { mime: "image/webp", extension: "webp", is_image: true, has_thumbnails: true };
+pub const HEIC: MediaType = MediaType { mime: "image/heic", extension: "heic", is_image: true, has_thumbnails: false };
+pub const MP4: MediaType = MediaType { mime: "video/mp4", extension: "mp4", is_image: false, has_thumbnails: false };
+pub const PDF: MediaType = MediaType { mime: "application/pdf", extension: "pdf", is_image: false, has_thumbnails: false };
+
+// ISO base media brands that are HEIF images rather than video
+const HEIF_BRANDS: [&[u8]; 8] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1"];
+
+// ISO base media brands accepted as MP4 video
+const MP4_BRANDS: [&[u8]; 8] = [b"isom", b"iso2", b"iso4", b"iso5", b"mp41", b"mp42", b"avc1", b"M4V "];
+
+// Identifies the type of a file from its first bytes, None if it is not on the allowlist
+pub fn sniff(head: &[u8]) -> Option<MediaType> {
+    if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some(JPEG);
+    }
+
+    if head.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        return Some(PNG);
+    }
+
+    if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return Some(WEBP);
+    }
+
+    if head.starts_with(b"%PDF-") {
+        return Some(PDF);
+    }
+
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        let brand = &head[8..12];
+
+        if HEIF_BRANDS.contains(&brand) {
+            return Some(HEIC);
+        }
+
+        if MP4_BRANDS.contains(&brand) {
+            return Some(MP4);
+        }
+    }
+
+    None
+}
+
+// Type of a stored file going by its extension, which was set from its sniffed type
+pub fn media_type_from_filename(filename: &str) -> Option<MediaType> {
+    let extension = filename.rsplit('.').next().unwrap_or("");
+
+    [JPEG, PNG, WEBP, HEIC, MP4, PDF]
+        .iter()
+        .find(|t| t.extension == extension)
+        .copied()
+}
+
+// Mime type of a stored file going by its extension, used when serving it
+pub fn mime_from_filename(filename: &str) -> &'static str {
+    media_type_from_filename(filename)
+        .map(|t| t.mime)
+        .unwrap_or("application/octet-stream")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pads a signature to SNIFF_LEN bytes, as the start of a real upload would be
+    fn head(signature: &[u8]) -> Vec<u8> {
+        let mut head = signature.to_vec();
+        head.resize(SNIFF_LEN.max(signature.len()), 0);
+        head
+    }
+
+    // Start of an ISO base media file with the given major brand
+    fn ftyp(brand: &[u8]) -> Vec<u8> {
+        let mut head = vec![0, 0, 0, 0x18];
+        head.extend_from_slice(b"ftyp");
+        head.extend_from_slice(brand);
+        head
+    }
+
+    #[test]
+    fn allowed_signatures_are_recognised() {
+        assert_eq!(sniff(&head(&[0xFF, 0xD8, 0xFF, 0xE0])), Some(JPEG));
+        assert_eq!(sniff(&head(&[0xFF, 0xD8, 0xFF, 0xE1])), Some(JPEG));
+        assert_eq!(sniff(&head(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A])), Some(PNG));
+        assert_eq!(sniff(&head(b"RIFF\x24\x00\x00\x00WEBPVP8 ")), Some(WEBP));
+        assert_eq!(sniff(&head(b"%PDF-1.7")), Some(PDF));
+
+        for brand in HEIF_BRANDS.iter() {
+            assert_eq!(sniff(&head(&ftyp(brand))), Some(HEIC), "brand {:?}", brand);
+        }
+
+        for brand in MP4_BRANDS.iter() {
+            assert_eq!(sniff(&head(&ftyp(brand))), Some(MP4), "brand {:?}", brand);
+        }
+    }
+
+    #[test]
+    fn look_alikes_are_rejected() {
+        assert_eq!(sniff(&head(b"RIFF\x24\x00\x00\x00WAVEfmt ")), None); // Audio
+        assert_eq!(sniff(&head(b"RIFF\x24\x00\x00\x00AVI LIST")), None);
+        assert_eq!(sniff(&head(&ftyp(b"qt  "))), None); // QuickTime
+        assert_eq!(sniff(&head(&ftyp(b"avif"))), None);
+        assert_eq!(sniff(&head(&ftyp(b"HEIC"))), None); // Brands are case sensitive
+        assert_eq!(sniff(&head(&[0xFF, 0xD8, 0x00])), None);
+        assert_eq!(sniff(&head(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x00])), None);
+        assert_eq!(sniff(&head(b"%PDF")), None);
+        assert_eq!(sniff(&head(b"GIF89a")), None);
+        assert_eq!(sniff(&head(b"<svg xmlns=")), None);
+        assert_eq!(sniff(&head(b"#!/bin/sh")), None);
+        assert_eq!(sniff(&head(b"PK\x03\x04")), None); // Zip
+    }
+
+    #[test]
+    fn files_shorter_than_sniff_len_are_sniffed_as_they_are() {
+        assert_eq!(sniff(&[]), None);
+        assert_eq!(sniff(&[0xFF, 0xD8]), None);
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF]), Some(JPEG));
+        assert_eq!(sniff(b"%PDF-"), Some(PDF));
+        assert_eq!(sniff(b"RIFF\x00\x00\x00\x00WEB"), None);
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftyphei"), None);
+        assert_eq!(sniff(&ftyp(b"heic")), Some(HEIC));
+    }
+
+    #[test]
+    fn stored_files_are_typed_by_extension() {
+        assert_eq!(media_type_from_filename("0b5e.jpg"), Some(JPEG));
+        assert_eq!(media_type_from_filename("0b5e.heic"), Some(HEIC));
+        assert_eq!(media_type_from_filename("0b5e.svg"), None);
+        assert_eq!(mime_from_filename("0b5e.exe"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use actix_web::web;
+use async_trait::async_trait;
+
+// Where uploaded media (and its thumbnails) is kept, keys are plain filenames
+#[async_trait(?Send)]
+pub trait MediaStore: Send + Sync {
+    // Moves a finished file from local disk into the store
+    async fn put_file(&self, key: &str, filepath: &str) -> io::Result<()>;
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()>;
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+pub type SharedMediaStore = Arc<dyn MediaStore>;
+
+// Keys are generated by us, but never let one escape the store
+pub fn check_key(key: &str) -> io::Result<()> {
+    if key.is_empty() || key.contains('/') || key.contains('\\') || key.starts_with('.') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid media key '{}'", key)));
+    }
+
+    Ok(())
+}
+
+// Stores media as files in a directory
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: &str) -> io::Result<LocalStore> {
+        std::fs::create_dir_all(dir)?;
+
+        Ok(LocalStore {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.dir.join(key))
+    }
+}
+
+// web::block wraps the io error, unwrap it again
+fn blocking_error(e: actix_web::error::BlockingError<io::Error>) -> io::Error {
+    match e {
+        actix_web::error::BlockingError::Error(e) => e,
+        actix_web::error::BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "blocking operation canceled"),
+    }
+}
+
+#[async_trait(?Send)]
+impl MediaStore for LocalStore {
+    async fn put_file(&self, key: &str, filepath: &str) -> io::Result<()> {
+        let (from, to) = (PathBuf::from(filepath), self.path(key)?);
+
+        web::block(move || {
+            // Staging may be on another filesystem, fall back to copying
+            if std::fs::rename(&from, &to).is_err() {
+                std::fs::copy(&from, &to)?;
+                std::fs::remove_file(&from)?;
+            }
+            Ok(())
+        }).await.map_err(blocking_error)
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        let path = self.path(key)?;
+        web::block(move || std::fs::write(path, data)).await.map_err(blocking_error)
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let path = self.path(key)?;
+        web::block(move || std::fs::read(path)).await.map_err(blocking_error)
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key)?;
+        web::block(move || std::fs::remove_file(path)).await.map_err(blocking_error)
+    }
+}
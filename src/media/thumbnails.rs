@@ -0,0 +1,171 @@
+use std::io::Cursor;
+
+use actix_web::web;
+use image::error::{LimitError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::io::Reader;
+use image::{DynamicImage, ImageError, ImageOutputFormat};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::db::MapDB;
+use crate::media::sniff::media_type_from_filename;
+use crate::media::store::SharedMediaStore;
+
+// Longest side, in pixels, of each thumbnail generated for an image
+pub const THUMBNAIL_SIZES: [u32; 2] = [256, 1024];
+
+// JPEG quality of the thumbnails
+const THUMBNAIL_QUALITY: u8 = 85;
+
+// Jobs waiting at most, uploads past this get their thumbnails from the backfill on next start
+const QUEUE_LENGTH: usize = 1024;
+
+// Largest image decoded, 50 megapixels is ~200MB as RGBA
+const MAX_PIXELS: u64 = 50_000_000;
+
+// An uploaded image waiting for its thumbnails
+struct ThumbnailJob {
+    file_id: i64,
+    filename: String,
+    orientation: Option<i64>,
+}
+
+// Handle to the background task generating thumbnails, cheap to clone
+#[derive(Clone)]
+pub struct ThumbnailWorker {
+    sender: Sender<ThumbnailJob>,
+}
+
+impl ThumbnailWorker {
+    // Spawns the worker on the current actix runtime,
+    // along with queueing any images that were still waiting when the server last stopped
+    pub fn start(db: MapDB, store: SharedMediaStore) -> ThumbnailWorker {
+        let (sender, receiver) = channel(QUEUE_LENGTH);
+        actix_web::rt::spawn(backfill(db.clone(), sender.clone()));
+        actix_web::rt::spawn(run(db, store, receiver));
+
+        ThumbnailWorker { sender }
+    }
+
+    // Queues thumbnail generation for a stored image, returns immediately
+    pub fn queue(&self, file_id: i64, filename: &str, orientation: Option<i64>) {
+        let job = ThumbnailJob {
+            file_id,
+            filename: filename.to_string(),
+            orientation,
+        };
+
+        if self.sender.clone().try_send(job).is_err() {
+            println!("Thumbnail queue full or stopped, no thumbnails for '{}' until the next start", filename);
+        }
+    }
+}
+
+// Queues every image without thumbnails, waiting for room in the queue rather than dropping any
+async fn backfill(db: MapDB, mut sender: Sender<ThumbnailJob>) {
+    let files = match db.get_files_without_thumbnails().await {
+        Ok(files) => files,
+        Err(e) => {
+            println!("Could not find files waiting for thumbnails: {}", e);
+            return;
+        }
+    };
+
+    for file in files {
+        if !media_type_from_filename(&file.filename).map_or(false, |t| t.has_thumbnails) {
+            continue;
+        }
+
+        let job = ThumbnailJob {
+            file_id: file.id,
+            filename: file.filename,
+            orientation: file.orientation,
+        };
+
+        if sender.send(job).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn run(db: MapDB, store: SharedMediaStore, mut receiver: Receiver<ThumbnailJob>) {
+    while let Some(job) = receiver.recv().await {
+        if let Err(e) = process_job(&db, &store, &job).await {
+            println!("Could not create thumbnails for '{}': {}", job.filename, e);
+        }
+    }
+}
+
+async fn process_job(db: &MapDB, store: &SharedMediaStore, job: &ThumbnailJob) -> Result<(), String> {
+    let original = store.get(&job.filename).await.map_err(|e| e.to_string())?;
+    let orientation = job.orientation;
+
+    // Decoding and resizing is CPU heavy, keep it off the event loop
+    let thumbnails = web::block(move || generate_thumbnails(&original, orientation))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut names = Vec::new();
+
+    for (size, data) in THUMBNAIL_SIZES.iter().zip(thumbnails.into_iter()) {
+        let name = thumbnail_name(&job.filename, *size);
+        store.put(&name, data).await.map_err(|e| e.to_string())?;
+        names.push(name);
+    }
+
+    db.set_file_thumbnails(job.file_id, &names[0], &names[1]).await.map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// Name of the thumbnail of this size for an uploaded file
+pub fn thumbnail_name(filename: &str, size: u32) -> String {
+    let stem = filename.rsplitn(2, '.').last().unwrap_or(filename);
+    format!("{}_{}.jpg", stem, size)
+}
+
+// Rotates/flips the image upright according to its EXIF orientation
+fn apply_orientation(img: DynamicImage, orientation: Option<i64>) -> DynamicImage {
+    match orientation.unwrap_or(1) {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// Decodes an image, refusing ones over MAX_PIXELS before allocating anything for them
+fn decode_image(original: &[u8]) -> Result<DynamicImage, ImageError> {
+    let (width, height) = Reader::new(Cursor::new(original)).with_guessed_format()?.into_dimensions()?;
+
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError)));
+    }
+
+    Reader::new(Cursor::new(original)).with_guessed_format()?.decode()
+}
+
+// Encodes one JPEG per THUMBNAIL_SIZES from the original image
+fn generate_thumbnails(original: &[u8], orientation: Option<i64>) -> Result<Vec<Vec<u8>>, ImageError> {
+    let img = apply_orientation(decode_image(original)?, orientation);
+    let mut thumbnails = Vec::new();
+
+    for size in THUMBNAIL_SIZES.iter() {
+        // Never upscale small images
+        let thumbnail = if img.width() > *size || img.height() > *size {
+            img.resize(*size, *size, FilterType::Lanczos3)
+        } else {
+            img.clone()
+        };
+
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(thumbnail.to_rgb8()).write_to(&mut data, ImageOutputFormat::Jpeg(THUMBNAIL_QUALITY))?;
+        thumbnails.push(data);
+    }
+
+    Ok(thumbnails)
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// Settings file read when none is given with --config or MYMAP_CONFIG, skipped if missing
+const DEFAULT_SETTINGS_FILE: &str = "mymap.toml";
+
+// Env vars are the setting name in upper case with this prefix, ie. MYMAP_PORT
+const ENV_PREFIX: &str = "MYMAP_";
+
+// Defaults
+const DEFAULT_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PORT: u32 = 8080;
+const DEFAULT_WWW_PATH: &str = "./www/build/";
+const DEFAULT_SQLITE_NAME: &str = "db.sqlite";
+const DEFAULT_POOL_MAX_CONNECTIONS: u32 = 4;
+const DEFAULT_POOL_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MEDIA_DIR: &str = "./media"; // Kept outside of www/ so uploads are only served through /media
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_COOKIE_KEY_FILE: &str = "cookie_keys";
+const DEFAULT_COOKIE_SAME_SITE: &str = "lax";
+const DEFAULT_WEBSITE_URL: &str = "gekinzuku.github.io";
+const DEFAULT_TOTP_WINDOW: u64 = 1;
+const DEFAULT_SESSION_IDLE_SECS: i64 = 30 * 24 * 60 * 60;
+const DEFAULT_SESSION_MAX_AGE_SECS: i64 = 90 * 24 * 60 * 60;
+const DEFAULT_MAX_FILE_SIZE_MB: usize = 25;
+const DEFAULT_MAX_REQUEST_SIZE_MB: usize = 100;
+const DEFAULT_MAX_IMPORT_SIZE_MB: usize = 16;
+const DEFAULT_PASSWORD_MIN_LENGTH: usize = 8;
+const DEFAULT_PASSWORD_MIN_CLASSES: usize = 1;
+
+// Every setting, as named in the settings file and (upper cased) in env vars
+pub const SETTING_NAMES: [&str; 32] = [
+    "address",
+    "port",
+    "www_path",
+    "db_path",
+    "db_max_connections",
+    "db_timeout_secs",
+    "media_dir",
+    "s3_endpoint",
+    "s3_bucket",
+    "s3_region",
+    "s3_access_key",
+    "s3_secret_key",
+    "upload_dir",
+    "cookie_key_file",
+    "cookie_key",
+    "cookie_old_keys",
+    "cookie_secure",
+    "cookie_same_site",
+    "cookie_max_age",
+    "cookie_domain",
+    "website_url",
+    "totp_window",
+    "session_idle_secs",
+    "session_max_age_secs",
+    "persist_lockouts",
+    "trust_proxy",
+    "auth_api",
+    "max_file_size_mb",
+    "max_request_size_mb",
+    "max_import_size_mb",
+    "password_min_length",
+    "password_min_classes",
+];
+
+// Settings only shown as set or not by --print-config
+const SECRET_SETTINGS: [&str; 4] = ["cookie_key", "cookie_old_keys", "s3_access_key", "s3_secret_key"];
+
+// Where the effective value of a setting came from
+#[derive(Clone, Copy, PartialEq)]
+pub enum SettingSource {
+    Default,
+    File,
+    Env,
+    Cli,
+}
+
+#[derive(Clone)]
+pub struct Settings {
+    pub address:                String,
+    pub port:                   u32,
+    pub www_path:               String,     // Built webapp served at /
+    pub db_path:                String,     // SQLite database file
+    pub db_max_connections:     u32,
+    pub db_timeout_secs:        u64,        // Waiting for a connection, or for a locked db
+    pub media_dir:              String,     // Local media store, unless S3 is used
+    pub s3_endpoint:            String,     // Keeps uploads in an S3-compatible bucket if set, ie. http://localhost:9000
+    pub s3_bucket:              String,
+    pub s3_region:              String,
+    pub s3_access_key:          String,
+    pub s3_secret_key:          String,
+    pub upload_dir:             String,     // Uploads are staged here until they are checked
+    pub cookie_key_file:        String,     // Cookie signing keys, one hex key per line, newest first
+    pub cookie_key:             String,     // Current signing key (hex), overrides the key file if set
+    pub cookie_old_keys:        String,     // Comma separated keys (hex) that are still accepted
+    pub cookie_secure:          bool,       // Only send cookies over https
+    pub cookie_same_site:       String,     // strict, lax or none
+    pub cookie_max_age:         i64,        // Seconds, -1 for the browser session
+    pub cookie_domain:          String,     // Domain set on the cookies, if not empty
+    pub website_url:            String,     // Shown in authenticator apps next to the username
+    pub totp_window:            u64,        // 30s steps either side of now a TOTP code is accepted for
+    pub session_idle_secs:      i64,        // Sessions end after this long without a request
+    pub session_max_age_secs:   i64,        // and this long after login regardless
+    pub persist_lockouts:       bool,       // Keeps failed logins in the db so they survive restarts
+    pub trust_proxy:            bool,       // Client ip from X-Forwarded-For/Forwarded, only behind a proxy that sets them
+    pub auth_api:               bool,       // false disables logins and all writes
+    pub max_file_size_mb:       usize,
+    pub max_request_size_mb:    usize,
+    pub max_import_size_mb:     usize,      // Raw request bodies, ie. imports
+    pub password_min_length:    usize,      // Characters, for new passwords only
+    pub password_min_classes:   usize,      // Of lower case, upper case, digits and symbols, 1-4
+    settings_file:              Option<String>,
+    sources:                    HashMap<String, SettingSource>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            address:                DEFAULT_ADDRESS.to_string(),
+            port:                   DEFAULT_PORT,
+            www_path:               DEFAULT_WWW_PATH.to_string(),
+            db_path:                DEFAULT_SQLITE_NAME.to_string(),
+            db_max_connections:     DEFAULT_POOL_MAX_CONNECTIONS,
+            db_timeout_secs:        DEFAULT_POOL_TIMEOUT_SECS,
+            media_dir:              DEFAULT_MEDIA_DIR.to_string(),
+            s3_endpoint:            String::new(),
+            s3_bucket:              String::new(),
+            s3_region:              DEFAULT_S3_REGION.to_string(),
+            s3_access_key:          String::new(),
+            s3_secret_key:          String::new(),
+            upload_dir:             std::env::temp_dir().join("mymap-uploads").to_string_lossy().to_string(),
+            cookie_key_file:        DEFAULT_COOKIE_KEY_FILE.to_string(),
+            cookie_key:             String::new(),
+            cookie_old_keys:        String::new(),
+            cookie_secure:          false,
+            cookie_same_site:       DEFAULT_COOKIE_SAME_SITE.to_string(),
+            cookie_max_age:         -1,
+            cookie_domain:          String::new(),
+            website_url:            DEFAULT_WEBSITE_URL.to_string(),
+            totp_window:            DEFAULT_TOTP_WINDOW,
+            session_idle_secs:      DEFAULT_SESSION_IDLE_SECS,
+            session_max_age_secs:   DEFAULT_SESSION_MAX_AGE_SECS,
+            persist_lockouts:       false,
+            trust_proxy:            false,
+            auth_api:               true,
+            max_file_size_mb:       DEFAULT_MAX_FILE_SIZE_MB,
+            max_request_size_mb:    DEFAULT_MAX_REQUEST_SIZE_MB,
+            max_import_size_mb:     DEFAULT_MAX_IMPORT_SIZE_MB,
+            password_min_length:    DEFAULT_PASSWORD_MIN_LENGTH,
+            password_min_classes:   DEFAULT_PASSWORD_MIN_CLASSES,
+            settings_file:          None,
+            sources:                HashMap::new(),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value '{}' for setting '{}'", value, name))
+}
+
+// A number of seconds that must be above zero
+fn parse_positive(name: &str, value: &str) -> Result<i64, String> {
+    match parse_value(name, value)? {
+        secs if secs > 0 => Ok(secs),
+        _ => Err(format!("Setting '{}' must be above 0, not '{}'", name, value)),
+    }
+}
+
+impl Settings {
+    // Layers, each overriding the last: defaults, the settings file, MYMAP_* env vars, then cli_overrides
+    // An explicitly given settings file must exist, the default one is optional
+    pub fn load(settings_file: Option<&str>, cli_overrides: &[(String, String)]) -> Result<Settings, String> {
+        Settings::load_with_env(settings_file, |var| std::env::var(var).ok(), cli_overrides)
+    }
+
+    // load, with env vars read through env
+    fn load_with_env<F: Fn(&str) -> Option<String>>(settings_file: Option<&str>, env: F, cli_overrides: &[(String, String)]) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+
+        let settings_file = settings_file
+            .map(|f| f.to_string())
+            .or(env(&format!("{}CONFIG", ENV_PREFIX)));
+
+        match settings_file {
+            Some(settings_file) => settings.load_file(&settings_file)?,
+            None if Path::new(DEFAULT_SETTINGS_FILE).exists() => settings.load_file(DEFAULT_SETTINGS_FILE)?,
+            None => (),
+        }
+
+        for name in SETTING_NAMES.iter() {
+            let var = Settings::env_var(name);
+
+            if let Some(value) = env(&var) {
+                settings.set(name, &value, SettingSource::Env).map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+
+        for (name, value) in cli_overrides {
+            settings.set(name, value, SettingSource::Cli)?;
+        }
+
+        Ok(settings)
+    }
+
+    fn load_file(&mut self, filename: &str) -> Result<(), String> {
+        let contents = std::fs::read_to_string(filename)
+            .map_err(|e| format!("Could not read settings file '{}': {}", filename, e))?;
+        let table: toml::value::Table = toml::from_str(&contents)
+            .map_err(|e| format!("Could not parse settings file '{}': {}", filename, e))?;
+
+        for (name, value) in table {
+            // Strings are used as is, anything else is parsed like an env var would be
+            let value = match value {
+                toml::Value::String(value) => value,
+                value => value.to_string(),
+            };
+
+            self.set(&name, &value, SettingSource::File).map_err(|e| format!("{}: {}", filename, e))?;
+        }
+
+        self.settings_file = Some(filename.to_string());
+        Ok(())
+    }
+
+    fn env_var(name: &str) -> String {
+        format!("{}{}", ENV_PREFIX, name.to_uppercase())
+    }
+
+    // Parses and sets one setting by name, remembering where its value came from
+    pub fn set(&mut self, name: &str, value: &str, source: SettingSource) -> Result<(), String> {
+        match name {
+            "address" => self.address = value.to_string(),
+            "port" => self.port = parse_value(name, value)?,
+            "www_path" => self.www_path = value.to_string(),
+            "db_path" => self.db_path = value.to_string(),
+            "db_max_connections" => self.db_max_connections = parse_value(name, value)?,
+            "db_timeout_secs" => self.db_timeout_secs = parse_value(name, value)?,
+            "media_dir" => self.media_dir = value.to_string(),
+            "s3_endpoint" => self.s3_endpoint = value.trim().to_string(),
+            "s3_bucket" => self.s3_bucket = value.trim().to_string(),
+            "s3_region" => self.s3_region = value.trim().to_string(),
+            "s3_access_key" => self.s3_access_key = value.trim().to_string(),
+            "s3_secret_key" => self.s3_secret_key = value.trim().to_string(),
+            "upload_dir" => self.upload_dir = value.to_string(),
+            "cookie_key_file" => self.cookie_key_file = value.to_string(),
+            "cookie_key" => self.cookie_key = value.trim().to_string(),
+            "cookie_old_keys" => self.cookie_old_keys = value.trim().to_string(),
+            "cookie_secure" => self.cookie_secure = parse_value(name, value)?,
+            "cookie_same_site" => {
+                let same_site = value.trim().to_lowercase();
+
+                if !["strict", "lax", "none"].contains(&same_site.as_str()) {
+                    return Err(format!("Setting '{}' must be strict, lax or none, not '{}'", name, value));
+                }
+                self.cookie_same_site = same_site;
+            }
+            "cookie_max_age" => self.cookie_max_age = parse_value(name, value)?,
+            "cookie_domain" => self.cookie_domain = value.trim().to_string(),
+            "website_url" => self.website_url = value.to_string(),
+            "totp_window" => self.totp_window = parse_value(name, value)?,
+            "session_idle_secs" => self.session_idle_secs = parse_positive(name, value)?,
+            "session_max_age_secs" => self.session_max_age_secs = parse_positive(name, value)?,
+            "persist_lockouts" => self.persist_lockouts = parse_value(name, value)?,
+            "trust_proxy" => self.trust_proxy = parse_value(name, value)?,
+            "auth_api" => self.auth_api = parse_value(name, value)?,
+            "max_file_size_mb" => self.max_file_size_mb = parse_value(name, value)?,
+            "max_request_size_mb" => self.max_request_size_mb = parse_value(name, value)?,
+            "max_import_size_mb" => self.max_import_size_mb = parse_value(name, value)?,
+            "password_min_length" => self.password_min_length = parse_value(name, value)?,
+            "password_min_classes" => {
+                let min_classes = parse_value(name, value)?;
+
+                // There are only 4 classes, 5 would refuse every password
+                if !(1..=4).contains(&min_classes) {
+                    return Err(format!("Setting '{}' must be 1 to 4, not '{}'", name, value));
+                }
+                self.password_min_classes = min_classes;
+            }
+            _ => return Err(format!("Unknown setting '{}'", name)),
+        }
+
+        self.sources.insert(name.to_string(), source);
+        Ok(())
+    }
+
+    // Value of a setting as it would be written in the settings file
+    fn value_of(&self, name: &str) -> toml::Value {
+        match name {
+            "address" => toml::Value::from(self.address.as_str()),
+            "port" => toml::Value::from(self.port as i64),
+            "www_path" => toml::Value::from(self.www_path.as_str()),
+            "db_path" => toml::Value::from(self.db_path.as_str()),
+            "db_max_connections" => toml::Value::from(self.db_max_connections as i64),
+            "db_timeout_secs" => toml::Value::from(self.db_timeout_secs as i64),
+            "media_dir" => toml::Value::from(self.media_dir.as_str()),
+            "s3_endpoint" => toml::Value::from(self.s3_endpoint.as_str()),
+            "s3_bucket" => toml::Value::from(self.s3_bucket.as_str()),
+            "s3_region" => toml::Value::from(self.s3_region.as_str()),
+            "s3_access_key" => toml::Value::from(self.s3_access_key.as_str()),
+            "s3_secret_key" => toml::Value::from(self.s3_secret_key.as_str()),
+            "upload_dir" => toml::Value::from(self.upload_dir.as_str()),
+            "cookie_key_file" => toml::Value::from(self.cookie_key_file.as_str()),
+            "cookie_key" => toml::Value::from(self.cookie_key.as_str()),
+            "cookie_old_keys" => toml::Value::from(self.cookie_old_keys.as_str()),
+            "cookie_secure" => toml::Value::from(self.cookie_secure),
+            "cookie_same_site" => toml::Value::from(self.cookie_same_site.as_str()),
+            "cookie_max_age" => toml::Value::from(self.cookie_max_age),
+            "cookie_domain" => toml::Value::from(self.cookie_domain.as_str()),
+            "website_url" => toml::Value::from(self.website_url.as_str()),
+            "totp_window" => toml::Value::from(self.totp_window as i64),
+            "session_idle_secs" => toml::Value::from(self.session_idle_secs),
+            "session_max_age_secs" => toml::Value::from(self.session_max_age_secs),
+            "persist_lockouts" => toml::Value::from(self.persist_lockouts),
+            "trust_proxy" => toml::Value::from(self.trust_proxy),
+            "auth_api" => toml::Value::from(self.auth_api),
+            "max_file_size_mb" => toml::Value::from(self.max_file_size_mb as i64),
+            "max_request_size_mb" => toml::Value::from(self.max_request_size_mb as i64),
+            "max_import_size_mb" => toml::Value::from(self.max_import_size_mb as i64),
+            "password_min_length" => toml::Value::from(self.password_min_length as i64),
+            "password_min_classes" => toml::Value::from(self.password_min_classes as i64),
+            _ => toml::Value::from(""),
+        }
+    }
+
+    // ie. "env MYMAP_PORT" or "file mymap.toml"
+    fn describe_source(&self, name: &str) -> String {
+        match self.sources.get(name).copied().unwrap_or(SettingSource::Default) {
+            SettingSource::Default => "default".to_string(),
+            SettingSource::File => format!("file {}", self.settings_file.as_deref().unwrap_or("")),
+            SettingSource::Env => format!("env {}", Settings::env_var(name)),
+            SettingSource::Cli => "cli".to_string(),
+        }
+    }
+
+    // The effective settings in settings file format, each commented with where it came from
+    // Keys are only shown as <hidden>
+    fn describe(&self) -> Vec<String> {
+        SETTING_NAMES
+            .iter()
+            .map(|name| {
+                let value = match self.value_of(name) {
+                    toml::Value::String(value) if SECRET_SETTINGS.contains(name) && !value.is_empty() => toml::Value::from("<hidden>"),
+                    value => value,
+                };
+
+                format!("{} = {} # {}", name, value, self.describe_source(name))
+            })
+            .collect()
+    }
+
+    // For --print-config
+    pub fn print(&self) {
+        for line in self.describe() {
+            println!("{}", line);
+        }
+    }
+
+    pub fn full_address(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a settings file to the temp dir, returns its path
+    fn settings_file(contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mymap-settings-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn load(file: &str, env: &[(&str, &str)], cli: &[(&str, &str)]) -> Result<Settings, String> {
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let cli: Vec<(String, String)> = cli.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+
+        Settings::load_with_env(Some(file), |var| env.get(var).cloned(), &cli)
+    }
+
+    // The line describe() prints for a setting
+    fn line_of(settings: &Settings, name: &str) -> String {
+        settings.describe()
+            .into_iter()
+            .find(|line| line.starts_with(&format!("{} = ", name)))
+            .unwrap()
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let file = settings_file("address = \"127.0.0.1\"\nport = 9000\nwebsite_url = \"file.example\"\nauth_api = false\n");
+
+        let settings = load(&file,
+                            &[("MYMAP_PORT", "9001"), ("MYMAP_WEBSITE_URL", "env.example")],
+                            &[("port", "9002")]).unwrap();
+
+        assert_eq!(settings.port, 9002);
+        assert_eq!(settings.website_url, "env.example");
+        assert_eq!(settings.address, "127.0.0.1");
+        assert_eq!(settings.auth_api, false);
+        assert_eq!(settings.totp_window, DEFAULT_TOTP_WINDOW);
+
+        assert_eq!(line_of(&settings, "port"), "port = 9002 # cli");
+        assert_eq!(line_of(&settings, "website_url"), "website_url = \"env.example\" # env MYMAP_WEBSITE_URL");
+        assert_eq!(line_of(&settings, "address"), format!("address = \"127.0.0.1\" # file {}", file));
+        assert_eq!(line_of(&settings, "auth_api"), format!("auth_api = false # file {}", file));
+        assert_eq!(line_of(&settings, "totp_window"), format!("totp_window = {} # default", DEFAULT_TOTP_WINDOW));
+    }
+
+    #[test]
+    fn every_setting_is_described_once() {
+        let settings = load(&settings_file(""), &[], &[]).unwrap();
+        let lines = settings.describe();
+
+        assert_eq!(lines.len(), SETTING_NAMES.len());
+        for name in SETTING_NAMES.iter() {
+            assert!(line_of(&settings, name).ends_with("# default"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn secrets_are_hidden() {
+        let file = settings_file("cookie_key = \"00112233\"\n");
+        let settings = load(&file,
+                            &[("MYMAP_S3_ACCESS_KEY", "AKIAEXAMPLE"), ("MYMAP_S3_SECRET_KEY", "wJalrEXAMPLE")],
+                            &[("cookie_old_keys", "44556677")]).unwrap();
+
+        assert_eq!(settings.s3_secret_key, "wJalrEXAMPLE");
+        assert_eq!(line_of(&settings, "cookie_key"), format!("cookie_key = \"<hidden>\" # file {}", file));
+        assert_eq!(line_of(&settings, "cookie_old_keys"), "cookie_old_keys = \"<hidden>\" # cli");
+        assert_eq!(line_of(&settings, "s3_access_key"), "s3_access_key = \"<hidden>\" # env MYMAP_S3_ACCESS_KEY");
+
+        let printed = settings.describe().join("\n");
+        for secret in &["00112233", "44556677", "AKIAEXAMPLE", "wJalrEXAMPLE"] {
+            assert!(!printed.contains(secret), "{} was printed", secret);
+        }
+
+        // Unset secrets show that they are unset
+        let settings = load(&settings_file(""), &[], &[]).unwrap();
+        assert_eq!(line_of(&settings, "s3_secret_key"), "s3_secret_key = \"\" # default");
+    }
+
+    #[test]
+    fn the_settings_file_can_come_from_the_env() {
+        let file = settings_file("port = 9000\n");
+        let env: HashMap<String, String> = vec![("MYMAP_CONFIG".to_string(), file.to_string())].into_iter().collect();
+
+        let settings = Settings::load_with_env(None, |var| env.get(var).cloned(), &[]).unwrap();
+        assert_eq!(settings.port, 9000);
+        assert_eq!(line_of(&settings, "port"), format!("port = 9000 # file {}", file));
+    }
+
+    #[test]
+    fn invalid_settings_are_errors() {
+        assert!(Settings::load_with_env(Some("/nonexistent/mymap.toml"), |_| None, &[]).is_err());
+        assert!(load(&settings_file("port = \"eighty\"\n"), &[], &[]).is_err());
+        assert!(load(&settings_file("colour = \"blue\"\n"), &[], &[]).is_err());
+        assert!(load(&settings_file("port = \n"), &[], &[]).is_err());
+
+        let error = load(&settings_file(""), &[("MYMAP_TOTP_WINDOW", "-1")], &[]).err().unwrap();
+        assert!(error.starts_with("MYMAP_TOTP_WINDOW: "), "{}", error);
+
+        assert!(load(&settings_file(""), &[], &[("cookie_same_site", "sometimes")]).is_err());
+        assert!(load(&settings_file(""), &[], &[("password_min_classes", "5")]).is_err());
+        assert!(load(&settings_file(""), &[], &[("session_idle_secs", "0")]).is_err());
+        assert!(load(&settings_file(""), &[], &[("session_max_age_secs", "-60")]).is_err());
+        assert_eq!(load(&settings_file(""), &[], &[("session_idle_secs", "3600")]).unwrap().session_idle_secs, 3600);
+    }
+}
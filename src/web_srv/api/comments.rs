@@ -1,11 +1,12 @@
-use actix_web::{get, post, web, Error, HttpResponse};
-use actix_identity::Identity;
+use actix_web::{get, post, web, HttpResponse};
 use serde::{Deserialize, Serialize};
 
 use crate::web_srv::AppState;
-use crate::web_srv::user;
-use crate::web_srv::response::JSONResponse;
+use crate::web_srv::user::guard::AuthUser;
+use crate::web_srv::error::ApiError;
 use crate::db::comments::CommentDataForClient;
+use crate::db::locations::Viewer;
+use crate::db::permissions::Permission;
 
 #[derive(Deserialize)]
 struct AddCommentPost {
@@ -40,32 +41,49 @@ struct GetCommentsResp {
 }
 
 #[post("/addComment/")]
-async fn add_comment(id: Identity, state: web::Data<AppState>, json: web::Json<AddCommentPost>) -> Result<HttpResponse, Error> {
+async fn add_comment(user: AuthUser, state: web::Data<AppState>, json: web::Json<AddCommentPost>) -> Result<HttpResponse, ApiError> {
     // Permission check
-    if !user::login::does_this_user_have_permission(&id, &state, "addComment").await {
-        return JSONResponse::new_error("you do not have permission").to_ok();
+    if !user.has_permission(Permission::AddComment) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
     }
 
-    let user_id = user::login::get_this_user_id(&id, &state).await;
+    let user_id = user.user_id;
 
     let file_id     = json.file_id.unwrap_or(-1);
     let location_id = json.location_id.unwrap_or(-1);
     let reply_to_id = json.reply_to_id.unwrap_or(-1);
 
     if file_id == -1 && location_id == -1 {
-        return JSONResponse::new_error("Comment must be posted on either a file or location").to_ok();
+        return Err(ApiError::ValidationFailed("Comment must be posted on either a file or location".to_string()));
     }
     else if file_id != -1 && location_id != -1 {
-        return JSONResponse::new_error("Comment cannot be on both a file and location").to_ok();
+        return Err(ApiError::ValidationFailed("Comment cannot be on both a file and location".to_string()));
     }
 
-    // TODO: verify location/file before adding comment!
+    // Only locations and files the user can see can be commented on
+    let viewer = user.viewer();
+
+    if location_id != -1 && state.db.get_location(location_id, &viewer).await?.is_none() {
+        return Err(ApiError::NotFound("not a valid location id".to_string()));
+    }
+
+    if file_id != -1 && state.db.get_file_by_id(file_id, &viewer).await?.is_none() {
+        return Err(ApiError::NotFound("not a valid file id".to_string()));
+    }
+
+    // A reply must be on the same location or file as what it replies to
+    if reply_to_id != -1 {
+        match state.db.get_comment(reply_to_id).await? {
+            Some(reply_to) if reply_to.location_id == location_id && reply_to.file_id == file_id => (),
+            _ => return Err(ApiError::NotFound("not a valid reply_to_id".to_string())),
+        }
+    }
 
     let comment_id = if reply_to_id == -1 {
-        state.db.add_comment(&json.comment, location_id, file_id, user_id).await
+        state.db.add_comment(&json.comment, location_id, file_id, user_id).await?
     }
     else {
-        state.db.add_reply(&json.comment, location_id, file_id, user_id, reply_to_id).await
+        state.db.add_reply(&json.comment, location_id, file_id, user_id, reply_to_id).await?
     };
     
     Ok(HttpResponse::Ok().json(AddCommentResp {
@@ -75,24 +93,24 @@ async fn add_comment(id: Identity, state: web::Data<AppState>, json: web::Json<A
 }
 
 #[post("/editComment/")]
-async fn edit_comment(id: Identity, state: web::Data<AppState>, json: web::Json<EditCommentPost>) -> Result<HttpResponse, Error> {
+async fn edit_comment(user: AuthUser, state: web::Data<AppState>, json: web::Json<EditCommentPost>) -> Result<HttpResponse, ApiError> {
     // Permission check
-    if !user::login::does_this_user_have_permission(&id, &state, "editComment").await {
-        return JSONResponse::new_error("you do not have permission").to_ok();
+    if !user.has_permission(Permission::EditComment) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
     }
 
-    let user_id = user::login::get_this_user_id(&id, &state).await;
-    let comment = state.db.get_comment(json.id).await;
+    let user_id = user.user_id;
+    let comment = state.db.get_comment(json.id).await?;
 
     if comment.is_none() {
-        return JSONResponse::new_error("not a valid comment id").to_ok();
+        return Err(ApiError::NotFound("not a valid comment id".to_string()));
     }
 
-    if comment.unwrap().owner_id != user_id && !user::login::does_this_user_have_permission(&id, &state, "editOtherComment").await {
-        return JSONResponse::new_error("you cannot edit other user comments").to_ok();
+    if comment.unwrap().owner_id != user_id && !user.has_permission(Permission::EditOtherComment) {
+        return Err(ApiError::Forbidden("you cannot edit other user comments".to_string()));
     }
 
-    state.db.edit_comment(json.id, &json.comment).await;
+    state.db.edit_comment(json.id, &json.comment).await?;
     
     Ok(HttpResponse::Ok().json(EditCommentResp {
         status:         "OK".to_string(), 
@@ -100,8 +118,8 @@ async fn edit_comment(id: Identity, state: web::Data<AppState>, json: web::Json<
 }
 
 #[get("/getCommentsOnLocation/{location_id}/")]
-async fn get_comments_on_location(web::Path(location_id): web::Path<i64>, state: web::Data<AppState>,) -> Result<HttpResponse, Error> {
-    let comments = state.db.get_comments_on_location(location_id).await;
+async fn get_comments_on_location(web::Path(location_id): web::Path<i64>, viewer: Viewer, state: web::Data<AppState>,) -> Result<HttpResponse, ApiError> {
+    let comments = state.db.get_comments_on_location(location_id, &viewer).await?;
 
     Ok(HttpResponse::Ok().json(GetCommentsResp {
         status:         "OK".to_string(),
@@ -110,8 +128,8 @@ async fn get_comments_on_location(web::Path(location_id): web::Path<i64>, state:
 }
 
 #[get("/getCommentsOnFile/{file_id}/")]
-async fn get_comments_on_file(web::Path(file_id): web::Path<i64>, state: web::Data<AppState>,) -> Result<HttpResponse, Error> {
-    let comments = state.db.get_comments_on_file(file_id).await;
+async fn get_comments_on_file(web::Path(file_id): web::Path<i64>, viewer: Viewer, state: web::Data<AppState>,) -> Result<HttpResponse, ApiError> {
+    let comments = state.db.get_comments_on_file(file_id, &viewer).await?;
 
     Ok(HttpResponse::Ok().json(GetCommentsResp {
         status:         "OK".to_string(),
@@ -120,8 +138,8 @@ async fn get_comments_on_file(web::Path(file_id): web::Path<i64>, state: web::Da
 }
 
 #[get("/getReplies/{reply_to_id}/")]
-async fn get_replies(web::Path(reply_to_id): web::Path<i64>, state: web::Data<AppState>,) -> Result<HttpResponse, Error> {
-    let comments = state.db.get_replies(reply_to_id).await;
+async fn get_replies(web::Path(reply_to_id): web::Path<i64>, viewer: Viewer, state: web::Data<AppState>,) -> Result<HttpResponse, ApiError> {
+    let comments = state.db.get_replies(reply_to_id, &viewer).await?;
 
     Ok(HttpResponse::Ok().json(GetCommentsResp {
         status:         "OK".to_string(),
@@ -1,7 +1,9 @@
-use actix_web::{post, web, Error, HttpResponse};
+use actix_web::{post, web, HttpResponse};
 use serde::{Deserialize, Serialize};
 
+use crate::db::locations::Viewer;
 use crate::web_srv::AppState;
+use crate::web_srv::error::ApiError;
 
 #[derive(Deserialize)]
 struct GetFileInfoReq {
@@ -15,13 +17,25 @@ struct GetFileInfoResp {
     filename: String,
     title: String,
     description: String,
+    taken_date: Option<f64>,
+    camera_model: Option<String>,
+    orientation: Option<i64>,
+    gps_lat: Option<f64>,
+    gps_lon: Option<f64>,
+    gps_alt: Option<f64>,
 }
 
 #[post("/getFileInfo/")]
-async fn get_file_info(json: web::Json<GetFileInfoReq>, state: web::Data<AppState>,) -> Result<HttpResponse, Error> {
+async fn get_file_info(json: web::Json<GetFileInfoReq>, viewer: Viewer, state: web::Data<AppState>,) -> Result<HttpResponse, ApiError> {
     println!("getting file {}", &json.filename);
 
-    let file = state.db.get_file(&json.filename).await;
+    let file = state.db.get_file(&json.filename, &viewer).await?;
+
+    if file.is_none() {
+        return Err(ApiError::NotFound("no such file".to_string()));
+    }
+
+    let file = file.unwrap();
 
     Ok(HttpResponse::Ok().json(GetFileInfoResp {
         status:         "OK".to_string(),
@@ -29,5 +43,11 @@ async fn get_file_info(json: web::Json<GetFileInfoReq>, state: web::Data<AppStat
         filename:       file.filename,
         title:          file.title,
         description:    file.description,
+        taken_date:     file.taken_date,
+        camera_model:   file.camera_model,
+        orientation:    file.orientation,
+        gps_lat:        file.gps_lat,
+        gps_lon:        file.gps_lon,
+        gps_alt:        file.gps_alt,
     }))
 }
\ No newline at end of file
@@ -0,0 +1,97 @@
+use actix_web::{get, post, web, HttpResponse};
+use serde::Deserialize;
+
+use crate::db::locations::{new_visibility, Viewer};
+use crate::db::permissions::Permission;
+use crate::formats;
+use crate::web_srv::AppState;
+use crate::web_srv::user::guard::AuthUser;
+use crate::web_srv::error::ApiError;
+
+// Imported locations are private unless asked otherwise, a whole file is rarely meant for everyone
+const DEFAULT_IMPORT_VISIBILITY: &str = "private";
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    dry_run: Option<bool>,
+    visibility: Option<String>,
+}
+
+// Content type and download name for each export format
+fn export_file_info(format: &str) -> Option<(&'static str, &'static str)> {
+    match format {
+        "geojson" => Some(("application/geo+json", "locations.geojson")),
+        "gpx" => Some(("application/gpx+xml", "locations.gpx")),
+        "kml" => Some(("application/vnd.google-earth.kml+xml", "locations.kml")),
+        _ => None,
+    }
+}
+
+async fn export_format(format: &str, viewer: &Viewer, state: &web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let file_info = export_file_info(format);
+
+    if file_info.is_none() {
+        return Err(ApiError::NotFound("unknown export format".to_string()));
+    }
+
+    let (content_type, filename) = file_info.unwrap();
+
+    match formats::export(format, &state.db, viewer).await {
+        Ok(body) => Ok(HttpResponse::Ok()
+            .content_type(content_type)
+            .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+            .body(body)),
+        Err(e) => {
+            println!("Export failed: {}", e);
+            Err(ApiError::Internal("Export failed".to_string()))
+        }
+    }
+}
+
+async fn import_format(format: &str, user: &AuthUser, state: &web::Data<AppState>, query: &ImportQuery, body: &web::Bytes) -> Result<HttpResponse, ApiError> {
+    // Permission check
+    if !user.has_permission(Permission::AddLocation) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
+    }
+
+    let visibility = new_visibility(query.visibility.as_deref(), DEFAULT_IMPORT_VISIBILITY).map_err(ApiError::ValidationFailed)?;
+
+    let data = match formats::parse_import(format, &body) {
+        Ok(data) => data,
+        Err(e) => return Err(ApiError::ValidationFailed(e)),
+    };
+
+    let report = formats::import(&state.db, &data, user.user_id, visibility, query.dry_run.unwrap_or(false)).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[get("/export/locations.geojson")]
+async fn export_geojson(viewer: Viewer, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    export_format("geojson", &viewer, &state).await
+}
+
+// GPX or KML export of all locations and tracks
+#[get("/export/{format}")]
+async fn export(web::Path(format): web::Path<String>, viewer: Viewer, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if format != "gpx" && format != "kml" {
+        return Err(ApiError::NotFound("format must be gpx or kml".to_string()));
+    }
+
+    export_format(&format, &viewer, &state).await
+}
+
+#[post("/import/geojson")]
+async fn import_geojson(user: AuthUser, state: web::Data<AppState>, query: web::Query<ImportQuery>, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    import_format("geojson", &user, &state, &query, &body).await
+}
+
+// GPX or KML import, waypoints/points become locations and tracks/lines become tracks
+#[post("/import/{format}/")]
+async fn import(web::Path(format): web::Path<String>, user: AuthUser, state: web::Data<AppState>, query: web::Query<ImportQuery>, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    if format != "gpx" && format != "kml" {
+        return Err(ApiError::NotFound("format must be gpx or kml".to_string()));
+    }
+
+    import_format(&format, &user, &state, &query, &body).await
+}
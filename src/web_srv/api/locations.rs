@@ -1,10 +1,11 @@
-use actix_web::{get, post, web, Error, HttpResponse};
-use actix_identity::Identity;
+use actix_web::{get, post, web, HttpResponse};
 use serde::{Deserialize, Serialize};
 
-use crate::db::locations::LocationData;
-use crate::web_srv;
+use crate::db::locations::{is_valid_lat_lon, new_visibility, LocationData, Viewer, VISIBILITIES};
+use crate::db::permissions::Permission;
 use crate::web_srv::AppState;
+use crate::web_srv::user::guard::AuthUser;
+use crate::web_srv::error::ApiError;
 use crate::web_srv::response::JSONResponse;
 
 #[derive(Deserialize)]
@@ -22,11 +23,10 @@ struct JSONGetLocationFilesResp {
 #[post("/getLocationFiles/")]
 async fn get_location_files(
     json: web::Json<JSONGetLocationFilesParams>,
+    viewer: Viewer,
     state: web::Data<AppState>,
-) -> actix_web::Result<web::Json<JSONGetLocationFilesResp>> {
-    println!("getting location {}", json.id);
-    let filenames = state.db.get_location_filenames(json.id).await;
-    //let filenames: Vec<String> = Vec::new();
+) -> Result<web::Json<JSONGetLocationFilesResp>, ApiError> {
+    let filenames = state.db.get_location_filenames(json.id, &viewer).await?;
 
     Ok(web::Json(JSONGetLocationFilesResp {
         status: String::from("OK"),
@@ -41,6 +41,7 @@ struct JSONSaveLocationData {
     lat: f64,
     lon: f64,
     location_type: String,
+    visibility: Option<String>, // Of a new location, public if not given
 }
 
 #[derive(Serialize)]
@@ -50,21 +51,20 @@ struct JSONSaveLocationResp {
 }
 
 #[post("/saveLocation/")]
-async fn save_location(id: Identity, state: web::Data<AppState>, json: web::Json<JSONSaveLocationData>) -> Result<HttpResponse, Error> {
+async fn save_location(user: AuthUser, state: web::Data<AppState>, json: web::Json<JSONSaveLocationData>) -> Result<HttpResponse, ApiError> {
 
     // Permission check
-    if !web_srv::user::login::does_this_user_have_permission(&id, &state, "addLocation").await {
-        return JSONResponse::new_error("you do not have permission").to_ok();
+    if !user.has_permission(Permission::AddLocation) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
     }
 
-    println!("/saveLocation/ :: {}: {}, {}, {}", json.label, json.lat, json.lon, json.location_type);
+    if !is_valid_lat_lon(json.lat, json.lon) {
+        return Err(ApiError::ValidationFailed("lat/lon out of range".to_string()));
+    }
 
-    let username = web_srv::user::login::get_this_username(&id).unwrap();
-    let user_id = state.db.get_user_id(&username).await;
-    let id = state.db.get_location_id(&json.label, json.lat, json.lon, &json.location_type, user_id).await;
-    println!("added location");
+    let visibility = new_visibility(json.visibility.as_deref(), "public").map_err(ApiError::ValidationFailed)?;
 
-    //let id = -1;
+    let id = state.db.get_location_id(&json.label, json.lat, json.lon, &json.location_type, visibility, user.user_id).await?;
 
     Ok(HttpResponse::Ok().json(JSONSaveLocationResp {
         status: String::from("OK"),
@@ -72,6 +72,170 @@ async fn save_location(id: Identity, state: web::Data<AppState>, json: web::Json
     }))
 }
 
+#[derive(Deserialize)]
+struct JSONEditLocationData {
+    id: i64,
+    label: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    location_type: Option<String>,
+}
+
+#[post("/editLocation/")]
+async fn edit_location(user: AuthUser, state: web::Data<AppState>, json: web::Json<JSONEditLocationData>) -> Result<HttpResponse, ApiError> {
+    // Permission check
+    if !user.has_permission(Permission::EditLocation) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
+    }
+
+    let location = state.db.get_location(json.id, &user.viewer()).await?;
+
+    if location.is_none() {
+        return Err(ApiError::NotFound("not a valid location id".to_string()));
+    }
+
+    let location = location.unwrap();
+
+    if location.owner_id != user.user_id && !user.has_permission(Permission::EditOtherLocation) {
+        return Err(ApiError::Forbidden("you cannot edit other user locations".to_string()));
+    }
+
+    // Only change the fields that were sent
+    let label = json.label.as_ref().unwrap_or(&location.label);
+    let lat = json.lat.unwrap_or(location.lat as f64);
+    let lon = json.lon.unwrap_or(location.lon as f64);
+    let kind = json.location_type.as_ref().unwrap_or(&location.kind);
+
+    if !is_valid_lat_lon(lat, lon) {
+        return Err(ApiError::ValidationFailed("lat/lon out of range".to_string()));
+    }
+
+    state.db.edit_location(json.id, label, lat, lon, kind).await?;
+
+    JSONResponse::new_ok().to_ok()
+}
+
+#[derive(Deserialize)]
+struct JSONDeleteLocationData {
+    id: i64,
+    // What to do with the location's files and comments:
+    // "reject" (default) refuses if there are any, "reassign" moves them to reassign_to,
+    // "soft_delete" hides the location and keeps everything in the db
+    cascade: Option<String>,
+    reassign_to: Option<i64>,
+}
+
+#[post("/deleteLocation/")]
+async fn delete_location(user: AuthUser, state: web::Data<AppState>, json: web::Json<JSONDeleteLocationData>) -> Result<HttpResponse, ApiError> {
+    // Permission check
+    if !user.has_permission(Permission::DeleteLocation) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
+    }
+
+    let location = state.db.get_location(json.id, &user.viewer()).await?;
+
+    if location.is_none() {
+        return Err(ApiError::NotFound("not a valid location id".to_string()));
+    }
+
+    if location.unwrap().owner_id != user.user_id && !user.has_permission(Permission::DeleteOtherLocation) {
+        return Err(ApiError::Forbidden("you cannot delete other user locations".to_string()));
+    }
+
+    match json.cascade.as_deref().unwrap_or("reject") {
+        "reject" => {
+            if state.db.get_location_file_count(json.id).await? > 0 || state.db.get_comment_count_on_location(json.id).await? > 0 {
+                return Err(ApiError::Conflict("location still has files or comments".to_string()));
+            }
+
+            state.db.delete_location(json.id).await?;
+        }
+        "reassign" => {
+            let reassign_to = json.reassign_to.unwrap_or(-1);
+            let target = if reassign_to == json.id { None } else { state.db.get_location(reassign_to, &user.viewer()).await? };
+
+            if target.is_none() {
+                return Err(ApiError::ValidationFailed("reassign_to must be another valid location id".to_string()));
+            }
+
+            // Moving files and comments onto a location edits it
+            if target.unwrap().owner_id != user.user_id && !user.has_permission(Permission::EditOtherLocation) {
+                return Err(ApiError::Forbidden("you cannot reassign to other user locations".to_string()));
+            }
+
+            state.db.reassign_and_delete_location(json.id, reassign_to).await?;
+        }
+        "soft_delete" => {
+            state.db.soft_delete_location(json.id).await?;
+        }
+        _ => return Err(ApiError::ValidationFailed("cascade must be reject, reassign or soft_delete".to_string())),
+    }
+
+    JSONResponse::new_ok().to_ok()
+}
+
+#[derive(Deserialize)]
+struct JSONSetLocationVisibilityData {
+    id: i64,
+    visibility: String,
+    // Who a "group" or "link" location is shared with
+    users: Option<Vec<String>>,
+    groups: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct JSONSetLocationVisibilityResp {
+    status: String,
+    share_token: Option<String>, // Only for "link", add as ?share= to read the location
+}
+
+#[post("/setLocationVisibility/")]
+async fn set_location_visibility(user: AuthUser, state: web::Data<AppState>, json: web::Json<JSONSetLocationVisibilityData>) -> Result<HttpResponse, ApiError> {
+    // Permission check
+    if !user.has_permission(Permission::EditLocation) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
+    }
+
+    let location = state.db.get_location(json.id, &user.viewer()).await?;
+
+    if location.is_none() {
+        return Err(ApiError::NotFound("not a valid location id".to_string()));
+    }
+
+    if location.unwrap().owner_id != user.user_id && !user.has_permission(Permission::EditOtherLocation) {
+        return Err(ApiError::Forbidden("you cannot edit other user locations".to_string()));
+    }
+
+    if !VISIBILITIES.contains(&json.visibility.as_str()) {
+        return Err(ApiError::ValidationFailed("visibility must be private, group, link or public".to_string()));
+    }
+
+    let mut user_ids = Vec::new();
+    let mut group_ids = Vec::new();
+
+    for username in json.users.as_ref().unwrap_or(&Vec::new()) {
+        if !state.db.is_user(username).await? {
+            return Err(ApiError::ValidationFailed(format!("no such user '{}'", username)));
+        }
+        user_ids.push(state.db.get_user_id(username).await?);
+    }
+
+    for group_name in json.groups.as_ref().unwrap_or(&Vec::new()) {
+        if !state.db.is_user_group(group_name).await? {
+            return Err(ApiError::ValidationFailed(format!("no such group '{}'", group_name)));
+        }
+        group_ids.push(state.db.get_user_group_id(group_name).await?);
+    }
+
+    let share_token = state.db.set_location_visibility(json.id, &json.visibility).await?;
+    state.db.set_location_shares(json.id, &user_ids, &group_ids).await?;
+
+    Ok(HttpResponse::Ok().json(JSONSetLocationVisibilityResp {
+        status: String::from("OK"),
+        share_token,
+    }))
+}
+
 #[derive(Serialize)]
 struct JSONGetLocationsResp {
     status: String,
@@ -79,9 +243,98 @@ struct JSONGetLocationsResp {
 }
 
 #[get("/getAllLocations/")]
-async fn get_all_locations(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+async fn get_all_locations(viewer: Viewer, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
     Ok(HttpResponse::Ok().json(JSONGetLocationsResp {
         status: String::from("OK"),
-        locations: state.db.get_all_locations().await
+        locations: state.db.get_all_locations(&viewer).await?
     }))
 }
+
+#[derive(Deserialize)]
+struct LocationsBboxQuery {
+    bbox: String,
+}
+
+// Parses "minLon,minLat,maxLon,maxLat" into its four values
+fn parse_bbox(bbox: &str) -> Option<(f64, f64, f64, f64)> {
+    let values: Vec<f64> = bbox
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .ok()?;
+
+    if values.len() != 4 {
+        return None;
+    }
+
+    let (min_lon, min_lat, max_lon, max_lat) = (values[0], values[1], values[2], values[3]);
+
+    if !is_valid_lat_lon(min_lat, min_lon) || !is_valid_lat_lon(max_lat, max_lon) || min_lat > max_lat {
+        return None;
+    }
+
+    Some((min_lon, min_lat, max_lon, max_lat))
+}
+
+#[get("/locations")]
+async fn get_locations_in_bbox(query: web::Query<LocationsBboxQuery>, viewer: Viewer, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let bbox = parse_bbox(&query.bbox);
+
+    if bbox.is_none() {
+        return Err(ApiError::ValidationFailed("bbox must be minLon,minLat,maxLon,maxLat".to_string()));
+    }
+
+    let (min_lon, min_lat, max_lon, max_lat) = bbox.unwrap();
+
+    Ok(HttpResponse::Ok().json(JSONGetLocationsResp {
+        status: String::from("OK"),
+        locations: state.db.get_locations_in_bbox(min_lon, min_lat, max_lon, max_lat, &viewer).await?
+    }))
+}
+
+#[derive(Deserialize)]
+struct LocationsNearQuery {
+    lat: f64,
+    lon: f64,
+    radius_m: f64,
+}
+
+#[get("/locations/near")]
+async fn get_locations_near(query: web::Query<LocationsNearQuery>, viewer: Viewer, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if !is_valid_lat_lon(query.lat, query.lon) {
+        return Err(ApiError::ValidationFailed("lat/lon out of range".to_string()));
+    }
+
+    if !(query.radius_m > 0.0 && query.radius_m.is_finite()) {
+        return Err(ApiError::ValidationFailed("radius_m must be positive".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(JSONGetLocationsResp {
+        status: String::from("OK"),
+        locations: state.db.get_locations_near(query.lat, query.lon, query.radius_m, &viewer).await?
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bbox_is_min_lon_min_lat_max_lon_max_lat() {
+        assert_eq!(parse_bbox("13.3,52.4,13.5,52.6"), Some((13.3, 52.4, 13.5, 52.6)));
+        assert_eq!(parse_bbox(" -180 , -90 , 180 , 90 "), Some((-180.0, -90.0, 180.0, 90.0)));
+    }
+
+    #[test]
+    fn bbox_may_cross_the_antimeridian() {
+        assert_eq!(parse_bbox("170,-10,-170,10"), Some((170.0, -10.0, -170.0, 10.0)));
+    }
+
+    #[test]
+    fn invalid_bboxes_are_rejected() {
+        for bbox in &["", "1,2,3", "1,2,3,4,5", "1,2,,4", "a,b,c,d", "NaN,0,1,1", "0,0,inf,1",
+                      "0,-91,1,1", "0,0,181,1", "0,10,1,5"] {
+            assert_eq!(parse_bbox(bbox), None, "accepted '{}'", bbox);
+        }
+    }
+}
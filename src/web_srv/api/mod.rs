@@ -1,3 +1,4 @@
 pub mod comments;
+pub mod formats;
 pub mod locations;
 pub mod files;
\ No newline at end of file
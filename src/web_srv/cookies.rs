@@ -0,0 +1,272 @@
+use std::io;
+use std::io::Write;
+
+use actix_identity::CookieIdentityPolicy;
+use actix_session::CookieSession;
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, COOKIE};
+use rand::RngCore;
+
+use crate::settings::Settings;
+
+// Length of generated keys, cookie signing needs at least 32 bytes
+const KEY_LENGTH: usize = 64;
+
+// Old keys kept in the key file after a rotation
+const MAX_OLD_KEYS: usize = 3;
+
+const IDENTITY_COOKIE_NAME: &str = "auth-cookie";
+
+// Name CookieSession gives the session cookie
+const SESSION_COOKIE_NAME: &str = "actix-session";
+
+// Cookies signed with the current key, and re-signed when they arrive signed with an old one
+const SIGNED_COOKIE_NAMES: [&str; 2] = [SESSION_COOKIE_NAME, IDENTITY_COOKIE_NAME];
+
+// Keys and attributes of the session and identity cookies, from the cookie_* settings
+// The cookie_key setting overrides the key file
+#[derive(Clone)]
+pub struct CookieSettings {
+    pub key: Vec<u8>,
+    pub old_keys: Vec<Vec<u8>>,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub max_age: Option<i64>,
+    pub domain: Option<String>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn decode_key(hex_key: &str) -> io::Result<Vec<u8>> {
+    let key = hex::decode(hex_key.trim()).map_err(|e| invalid_data(format!("Cookie key is not hex: {}", e)))?;
+
+    if key.len() < 32 {
+        return Err(invalid_data("Cookie keys must be at least 32 bytes".to_string()));
+    }
+
+    Ok(key)
+}
+
+fn gen_key() -> Vec<u8> {
+    let mut key = vec![0u8; KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+// Reads all keys from the key file, newest first
+fn read_key_file(key_file: &str) -> io::Result<Vec<Vec<u8>>> {
+    std::fs::read_to_string(key_file)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(decode_key)
+        .collect()
+}
+
+// Writes the key file, readable only by us
+fn write_key_file(key_file: &str, keys: &Vec<Vec<u8>>) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut f = options.open(key_file)?;
+
+    for key in keys {
+        writeln!(f, "{}", hex::encode(key))?;
+    }
+
+    Ok(())
+}
+
+// Adds a new current key to the key file, keeping the previous ones as old keys
+pub fn rotate_key_file(key_file: &str) -> io::Result<()> {
+    let mut keys = match read_key_file(key_file) {
+        Ok(keys) => keys,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    keys.insert(0, gen_key());
+    keys.truncate(MAX_OLD_KEYS + 1);
+
+    write_key_file(key_file, &keys)
+}
+
+impl CookieSettings {
+    // Loads the keys from the settings or the key file, generating and saving a key if there is none
+    pub fn load(settings: &Settings) -> io::Result<CookieSettings> {
+        let key_file = &settings.cookie_key_file;
+
+        let mut keys = if !settings.cookie_key.is_empty() {
+            vec![decode_key(&settings.cookie_key)?]
+        } else {
+            match read_key_file(key_file) {
+                Ok(keys) if keys.len() > 0 => keys,
+                Ok(_) => return Err(invalid_data(format!("'{}' has no keys", key_file))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    println!("No cookie key found, generating one in '{}'", key_file);
+                    let keys = vec![gen_key()];
+                    write_key_file(key_file, &keys)?;
+                    keys
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        for key in settings.cookie_old_keys.split(',').filter(|k| !k.trim().is_empty()) {
+            keys.push(decode_key(key)?);
+        }
+
+        // Checked to be one of these when the setting was read
+        let same_site = match settings.cookie_same_site.as_str() {
+            "strict" => SameSite::Strict,
+            "none" => SameSite::None,
+            _ => SameSite::Lax,
+        };
+
+        Ok(CookieSettings {
+            key: keys.remove(0),
+            old_keys: keys,
+            secure: settings.cookie_secure,
+            same_site,
+            max_age: if settings.cookie_max_age == -1 { None } else { Some(settings.cookie_max_age) },
+            domain: if settings.cookie_domain.is_empty() { None } else { Some(settings.cookie_domain.to_string()) },
+        })
+    }
+
+    pub fn session(&self) -> CookieSession {
+        let mut session = CookieSession::signed(&self.key)
+            .name(SESSION_COOKIE_NAME)
+            .secure(self.secure)
+            .same_site(self.same_site);
+
+        if let Some(max_age) = self.max_age {
+            session = session.max_age(max_age);
+        }
+
+        if let Some(domain) = &self.domain {
+            session = session.domain(domain.to_string());
+        }
+
+        session
+    }
+
+    pub fn identity_policy(&self) -> CookieIdentityPolicy {
+        let mut policy = CookieIdentityPolicy::new(&self.key)
+            .name(IDENTITY_COOKIE_NAME)
+            .path("/")
+            .secure(self.secure)
+            .same_site(self.same_site);
+
+        if let Some(max_age) = self.max_age {
+            policy = policy.max_age(max_age);
+        }
+
+        if let Some(domain) = &self.domain {
+            policy = policy.domain(domain.to_string());
+        }
+
+        policy
+    }
+
+    // The cookie signed with the current key instead, if it is only valid under one of the old keys
+    // Signed the same way CookieSession and CookieIdentityPolicy sign their cookies
+    fn resign(&self, cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie.clone());
+
+        let current = Key::derive_from(&self.key);
+
+        if jar.signed(&current).get(cookie.name()).is_some() {
+            return None;
+        }
+
+        let verified = self.old_keys
+            .iter()
+            .find_map(|old_key| jar.signed(&Key::derive_from(old_key)).get(cookie.name()))?;
+
+        let mut resigned_jar = CookieJar::new();
+        resigned_jar.signed(&current).add(verified);
+
+        resigned_jar.get(cookie.name()).map(|resigned| Cookie::new(resigned.name().to_string(), resigned.value().to_string()))
+    }
+
+    // Swaps session and identity cookies signed with an old key in the request for ones signed
+    // with the current key, before the session and identity middleware read them
+    // Returns the swapped cookies, to send back with reissue so old keys can be retired
+    pub fn resign_request_cookies(&self, req: &mut ServiceRequest) -> Vec<Cookie<'static>> {
+        let mut cookies = Vec::new();
+        let mut resigned = Vec::new();
+
+        for header in req.headers().get_all(COOKIE) {
+            let header = match header.to_str() {
+                Ok(header) => header,
+                Err(_) => return Vec::new(), // Left for actix to reject
+            };
+
+            for part in header.split(';').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+                let cookie = match Cookie::parse_encoded(part.to_string()) {
+                    Ok(cookie) => cookie,
+                    Err(_) => return Vec::new(),
+                };
+
+                let new_cookie = if SIGNED_COOKIE_NAMES.contains(&cookie.name()) { self.resign(&cookie) } else { None };
+
+                match new_cookie {
+                    Some(new_cookie) => {
+                        cookies.push(new_cookie.clone());
+                        resigned.push(new_cookie);
+                    }
+                    None => cookies.push(cookie),
+                }
+            }
+        }
+
+        if resigned.is_empty() {
+            return resigned;
+        }
+
+        let header = cookies.iter().map(|cookie| cookie.encoded().to_string()).collect::<Vec<String>>().join("; ");
+
+        match HeaderValue::from_str(&header) {
+            Ok(header) => {
+                req.headers_mut().insert(COOKIE, header);
+                resigned
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Sends the re-signed cookies back to the browser, unless the response already sets them
+    pub fn reissue<B>(&self, res: &mut ServiceResponse<B>, resigned: Vec<Cookie<'static>>) {
+        for mut cookie in resigned {
+            if res.response().cookies().any(|set| set.name() == cookie.name()) {
+                continue;
+            }
+
+            cookie.set_path("/");
+            cookie.set_http_only(true);
+            cookie.set_secure(self.secure);
+            cookie.set_same_site(self.same_site);
+
+            if let Some(max_age) = self.max_age {
+                cookie.set_max_age(time::Duration::seconds(max_age));
+            }
+
+            if let Some(domain) = &self.domain {
+                cookie.set_domain(domain.to_string());
+            }
+
+            if let Err(e) = res.response_mut().add_cookie(&cookie) {
+                println!("Could not reissue cookie '{}': {}", cookie.name(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+use std::fmt;
+
+use crate::db::error::DbError;
+
+// Errors returned by the api, sent as {error: code, message} with a matching http status
+// code is machine readable, message is for showing to people
+#[derive(Debug)]
+pub enum ApiError {
+    NotLoggedIn(String),        // 401
+    TotpRequired,               // 401, logged in but this session has not passed TOTP yet
+    Forbidden(String),          // 403
+    NotFound(String),           // 404
+    Conflict(String),           // 409
+    PayloadTooLarge(String),    // 413
+    ValidationFailed(String),   // 422
+    TooManyAttempts(i64),       // 429, seconds until the next attempt is allowed
+    Internal(String),           // 500
+    Unavailable(String),        // 503, ie. the db is too busy
+}
+
+#[derive(Serialize)]
+struct ApiErrorResp<'a> {
+    error: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<i64>, // seconds
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotLoggedIn(_) => "not_logged_in",
+            ApiError::TotpRequired => "totp_required",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::ValidationFailed(_) => "validation_failed",
+            ApiError::TooManyAttempts(_) => "too_many_attempts",
+            ApiError::Internal(_) => "internal_error",
+            ApiError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::NotLoggedIn(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::ValidationFailed(message)
+            | ApiError::Internal(message)
+            | ApiError::Unavailable(message) => message.to_string(),
+            ApiError::TotpRequired => "TOTP has not been verified for this session".to_string(),
+            ApiError::TooManyAttempts(retry_after) => format!("Too many attempts, try again in {}s", retry_after),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotLoggedIn(_) | ApiError::TotpRequired => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let retry_after = match self {
+            ApiError::TooManyAttempts(retry_after) => Some(*retry_after),
+            _ => None,
+        };
+
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let Some(retry_after) = retry_after {
+            response.header("Retry-After", retry_after.to_string());
+        }
+
+        response.json(ApiErrorResp {
+            error: self.code(),
+            message: self.message(),
+            retry_after,
+        })
+    }
+}
+
+// Lets handlers use ? on db calls, details of unexpected errors are logged rather than sent
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> ApiError {
+        match e {
+            DbError::NotFound(what) => ApiError::NotFound(format!("{} not found", what)),
+            DbError::ConstraintViolation(_) => ApiError::Conflict("Conflicts with existing data".to_string()),
+            DbError::PoolTimeout => ApiError::Unavailable("Database is busy, try again".to_string()),
+            e => {
+                println!("Database error: {}", e);
+                ApiError::Internal("Database error".to_string())
+            }
+        }
+    }
+}
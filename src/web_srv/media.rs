@@ -0,0 +1,51 @@
+use actix_web::{get, web, HttpResponse};
+use uuid::Uuid;
+
+use crate::db::locations::Viewer;
+use crate::media::sniff::mime_from_filename;
+use crate::web_srv::AppState;
+use crate::web_srv::error::ApiError;
+
+// Serves an uploaded file from the media store by uuid, size is "256", "1024" or "full"
+// Falls back to the original while thumbnails are still being generated, without letting it be cached
+#[get("/media/{uuid}/{size}")]
+async fn get_media(web::Path((uuid, size)): web::Path<(String, String)>, viewer: Viewer, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if Uuid::parse_str(&uuid).is_err() {
+        return Err(ApiError::NotFound("not a valid file id".to_string()));
+    }
+
+    let file = state.db.get_file_by_uuid(&uuid, &viewer).await?;
+
+    if file.is_none() {
+        return Err(ApiError::NotFound("no such file".to_string()));
+    }
+
+    let file = file.unwrap();
+
+    // Files anyone can see may be kept by shared caches, others only by this viewer's browser
+    let is_public = state.db.get_location(file.location_id, &Viewer::public()).await?.is_some();
+
+    let (filename, is_final) = match size.as_str() {
+        "256" => file.thumb_256.map(|name| (name, true)).unwrap_or((file.filename, false)),
+        "1024" => file.thumb_1024.map(|name| (name, true)).unwrap_or((file.filename, false)),
+        "full" => (file.filename, true),
+        _ => return Err(ApiError::NotFound("size must be 256, 1024 or full".to_string())),
+    };
+
+    let cache_control = match (is_public, is_final) {
+        (false, _) => "private, no-store",
+        (true, false) => "no-cache", // The thumbnail will replace it at this url
+        (true, true) => "public, max-age=31536000, immutable",
+    };
+
+    match state.media_store.get(&filename).await {
+        Ok(data) => Ok(HttpResponse::Ok()
+            .content_type(mime_from_filename(&filename))
+            .header("Cache-Control", cache_control)
+            .body(data)),
+        Err(e) => {
+            println!("Could not read '{}' from media store: {}", filename, e);
+            Err(ApiError::Internal("file could not be read".to_string()))
+        }
+    }
+}
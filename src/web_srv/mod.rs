@@ -1,85 +1,110 @@
 use actix_files as fs;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_session::CookieSession;
+use actix_identity::IdentityService;
+use actix_web::dev::Service;
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpServer};
 use env_logger::Env;
+use std::sync::Arc;
 
 //use actix_cors::Cors;
 //use actix_web::http::header;
 
 use crate::db::MapDB;
+use crate::db::error::DbError;
+use crate::media::create_staging_dir;
+use crate::media::store::{LocalStore, SharedMediaStore};
+use crate::media::thumbnails::ThumbnailWorker;
+use crate::settings::Settings;
+use crate::web_srv::cookies::CookieSettings;
+use crate::web_srv::upload::UploadLimits;
+pub use crate::web_srv::user::limiter::LoginLimiter;
 
 mod api;
+mod media;
 mod upload;
 mod user;
 
+pub mod cookies;
+pub mod error;
 pub mod response;
 
-// Defaults
-const DEFAULT_WWW_PATH: &str = "./www/build/";
 const DEFAULT_INDEX: &str = "index.html";
-const DEFAULT_ADDRESS: &str = "0.0.0.0";
-const DEFAULT_PORT: u32 = 8080;
+
+// Startup db failures end the server like any other io error
+fn db_io_error(e: DbError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
 
 pub struct APIServer {
-    pub full_address:   String,
-    pub use_auth_api:   bool,
+    settings:       Settings,
+    media_store:    Option<SharedMediaStore>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    db:         MapDB, 
+    db:             MapDB, 
+    thumbnails:     ThumbnailWorker,
+    upload_limits:  UploadLimits,
+    staging_dir:    String,
+    media_store:    SharedMediaStore,
+    login_limiter:  LoginLimiter,
+    use_auth_api:   bool,
 }
 
 impl APIServer {
-    pub async fn new_from_full_address(full_address: &String) -> APIServer {
-        let api = APIServer {
-            full_address: full_address.to_string(),
-            use_auth_api: true,
-            //state: APIServer::new_app_state().await,
-        };
-
-        api
+    pub async fn new(settings: Settings) -> APIServer {
+        APIServer {
+            settings,
+            media_store: None,
+        }
     }
 
-    pub async fn new(address: &str, port: i32) -> APIServer {
-        let mut address_s = &address;
-        let mut port_s = format!("{}", port);
+    // Where uploads are kept, defaults to a LocalStore in the media_dir setting
+    pub fn set_media_store(&mut self, media_store: SharedMediaStore) {
+        self.media_store = Some(media_store);
+    }
 
-        if address == "" {
-            address_s = &DEFAULT_ADDRESS;
-        }
+    async fn new_app_state(&self) -> std::io::Result<AppState> {
+        let db = MapDB::new(&self.settings).await.map_err(db_io_error)?;
 
-        if port == -1 {
-            port_s = format!("{}", DEFAULT_PORT);
-        }
+        let media_store = match &self.media_store {
+            Some(media_store) => media_store.clone(),
+            None => Arc::new(LocalStore::new(&self.settings.media_dir)?),
+        };
 
-        let full_address = format!("{}:{}", address_s, port_s);
-        APIServer::new_from_full_address(&full_address).await
-    }
+        create_staging_dir(&self.settings.upload_dir)?;
 
-    pub fn disable_auth_api(&mut self) {
-        // Disables all authenticated api calls, user login, etc
-        self.use_auth_api = false;
-        println!("Disabled auth api, no writes will be possible.");
-    }
+        let login_limiter = LoginLimiter::new(&db, &self.settings).await.map_err(db_io_error)?;
 
-    async fn new_app_state() -> AppState {
-        AppState {
-            db:         MapDB::new().await,
-        }
+        Ok(AppState {
+            thumbnails:     ThumbnailWorker::start(db.clone(), media_store.clone()),
+            login_limiter,
+            use_auth_api:   self.settings.auth_api,
+            db,
+            upload_limits:  UploadLimits::new(&self.settings),
+            staging_dir:    self.settings.upload_dir.to_string(),
+            media_store,
+        })
     }
 
 
     pub async fn launch_server(&self) -> std::io::Result<()> {
-        println!("Launching server on: http://{}", self.full_address);
+        let full_address = self.settings.full_address();
+        println!("Launching server on: http://{}", full_address);
+
+        if !self.settings.auth_api {
+            // Disables all authenticated api calls, user login, etc
+            println!("Disabled auth api, no writes will be possible.");
+        }
 
         // Enable logging
         env_logger::init_from_env(Env::default().default_filter_or("info"));
 
-        let use_auth_api = self.use_auth_api;
-        let state = APIServer::new_app_state().await;
+        let use_auth_api = self.settings.auth_api;
+        let www_path = self.settings.www_path.to_string();
+        let max_import_size = self.settings.max_import_size_mb * 1024 * 1024;
+        let state = self.new_app_state().await?;
+        let cookie_settings = CookieSettings::load(&self.settings)?;
 
         HttpServer::new(move || {
             //let cors = Cors::permissive();// DEBUG MODE TODO: REMOVE
@@ -91,21 +116,34 @@ impl APIServer {
             .allowed_header(header::CONTENT_TYPE)
             .max_age(3600);*/
 
+            let rotation = cookie_settings.clone();
+
             let app = App::new()
                 //.wrap(cors)
                 .data(state.clone())
+                .app_data(web::PayloadConfig::new(max_import_size))
                 .wrap(Logger::default()) // Logging
                 .wrap(Logger::new("%a %{User-Agent}i"))
-                .wrap(CookieSession::signed(&[0; 32]).secure(false))
-                .wrap(IdentityService::new(
-                    CookieIdentityPolicy::new(&[0; 32]) // <- create cookie identity policy
-                        .name("auth-cookie")
-                        .secure(false),
-                ));
+                .wrap(cookie_settings.session())
+                .wrap(IdentityService::new(cookie_settings.identity_policy()))
+                // Outermost, so cookies signed with an old key are swapped before they are read
+                .wrap_fn(move |mut req, srv| {
+                    let resigned = rotation.resign_request_cookies(&mut req);
+                    let rotation = rotation.clone();
+                    let response = srv.call(req);
+
+                    async move {
+                        let mut res = response.await?;
+                        rotation.reissue(&mut res, resigned);
+                        Ok(res)
+                    }
+                });
 
             let app = match use_auth_api {
                 true => app
-                    .service(web::scope("/upload").service(upload::save_file::photo))
+                    .service(web::scope("/upload")
+                            .service(upload::save_file::photo)
+                            .service(upload::save_file::photos))
                     .service(
                         web::scope("/user")
                             .service(user::login::index)
@@ -113,8 +151,14 @@ impl APIServer {
                             .service(user::login::logout)
                             .service(user::login::register)
                             .service(user::login::check_totp)
+                            .service(user::login::reset_totp)
                             .service(user::login::is_user)
-                            .service(user::login::get_user),
+                            .service(user::login::get_user)
+                            .service(user::login::get_sessions)
+                            .service(user::login::revoke_session)
+                            .service(user::tokens::get_api_tokens)
+                            .service(user::tokens::add_api_token)
+                            .service(user::tokens::revoke_api_token),
                     ),
                 false => app,
             };
@@ -125,23 +169,33 @@ impl APIServer {
                             .service(api::comments::get_comments_on_location)
                             .service(api::comments::get_replies)
                             .service(api::locations::get_all_locations)
+                            .service(api::locations::get_locations_in_bbox)
+                            .service(api::locations::get_locations_near)
                             .service(api::locations::get_location_files)
-                            .service(api::files::get_file_info);
+                            .service(api::files::get_file_info)
+                            .service(api::formats::export_geojson)
+                            .service(api::formats::export);
 
             // Authenticated API calls
             let scope = match use_auth_api {
                 true => scope
                             .service(api::locations::save_location)
+                            .service(api::locations::edit_location)
+                            .service(api::locations::delete_location)
+                            .service(api::locations::set_location_visibility)
                             .service(api::comments::add_comment)
-                            .service(api::comments::edit_comment),
+                            .service(api::comments::edit_comment)
+                            .service(api::formats::import_geojson)
+                            .service(api::formats::import),
                 false => scope,
             };
 
             // Root webapp
             app.service(scope)
-                .service(fs::Files::new("/", DEFAULT_WWW_PATH).index_file(DEFAULT_INDEX))
+                .service(media::get_media)
+                .service(fs::Files::new("/", &www_path).index_file(DEFAULT_INDEX))
         })
-        .bind(&self.full_address)?
+        .bind(&full_address)?
         .run()
         .await
     }
@@ -1,7 +1,8 @@
 use serde::Serialize;
-use actix_web::{Error, HttpResponse};
+use actix_web::HttpResponse;
 
-use crate::db::users::UserInfo;
+use crate::db::users::{PublicUserInfo, UserInfo};
+use crate::web_srv::error::ApiError;
 
 // Simple status message to return
 #[derive(Serialize)]
@@ -9,19 +10,12 @@ pub struct StatusMsg {
     pub status: String,
 }
 
-// Error messages to return
-#[derive(Serialize)]
-pub struct ErrorMsg {
-    pub error: String,
-}
-
-
-
 // Various JSON responses to return to the web client
+// Errors are returned as an ApiError instead
 pub enum JSONResponse {
     StatusMsg(StatusMsg),
-    ErrorMsg(ErrorMsg),
-    UserInfo(UserInfo),
+    UserInfo(UserInfo),             // Only for the user themselves
+    PublicUserInfo(PublicUserInfo), // Any other user
 }
 
 
@@ -40,24 +34,17 @@ impl JSONResponse {
         })
     }
 
-    pub fn new_error(error: &str) -> JSONResponse {
-        // Error {error: "error message"}
-        JSONResponse::ErrorMsg(ErrorMsg {
-            error: error.to_string(),
-        })
-    }
-
     pub fn to_http_response(&self) -> HttpResponse {
         // Converts the JSONResponse to a HttpResponse to return to client
 
         match self {
-            JSONResponse::ErrorMsg(error_info) => HttpResponse::Ok().json(error_info),
             JSONResponse::StatusMsg(status) => HttpResponse::Ok().json(status),
             JSONResponse::UserInfo(user_info) => HttpResponse::Ok().json(user_info),
+            JSONResponse::PublicUserInfo(user_info) => HttpResponse::Ok().json(user_info),
         }
     }
 
-    pub fn to_ok(&self) -> Result<HttpResponse, Error> {
+    pub fn to_ok(&self) -> Result<HttpResponse, ApiError> {
         // Simple wrapper around HttpResponse to an Ok()
         Ok(self.to_http_response())
     }
@@ -1 +1,20 @@
+use crate::settings::Settings;
+
 pub mod save_file;
+
+// Size limits enforced while an upload is streamed in
+#[derive(Clone, Copy)]
+pub struct UploadLimits {
+    pub max_file_size:      usize,
+    pub max_request_size:   usize,
+}
+
+impl UploadLimits {
+    // Settings are in megabytes
+    pub fn new(settings: &Settings) -> UploadLimits {
+        UploadLimits {
+            max_file_size:      settings.max_file_size_mb * 1024 * 1024,
+            max_request_size:   settings.max_request_size_mb * 1024 * 1024,
+        }
+    }
+}
@@ -1,66 +1,262 @@
-use actix_identity::Identity;
-use actix_multipart::Multipart;
-use actix_session::Session;
-use actix_web::{post, web, Error, HttpResponse};
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::{post, web, HttpResponse};
 
 use futures_util::TryStreamExt as _;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use uuid::Uuid;
 
 use std::io::Write;
-use std::str;
 
-use crate::web_srv::user;
+use crate::db::error::DbResult;
+use crate::db::locations::new_visibility;
+use crate::db::permissions::Permission;
+use crate::media::sniff::{self, MediaType, SNIFF_LEN};
+use crate::media::{metadata, staging_path};
+use crate::web_srv::error::ApiError;
+use crate::web_srv::upload::UploadLimits;
+use crate::web_srv::user::guard::AuthUser;
 use crate::web_srv::AppState;
 
-async fn get_multipart_field(mut field: actix_multipart::Field) -> String {
-    // Returns field value from multipart form
+// Photos of a roll upload are snapped to existing locations this close to them
+const DEFAULT_SNAP_RADIUS_M: f64 = 50.0;
 
-    let mut s = String::from("");
+// Kind of the locations created for photos that matched nothing
+const NEW_LOCATION_KIND: &str = "photo";
 
-    while let Some(chunk) = field.try_next().await.ok().unwrap() {
-        s = format!(
-            "{:?}",
-            match str::from_utf8(&chunk) {
-                Ok(v) => v,
-                Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-            }
-        );
+// Visibility of those locations unless asked otherwise, photos can give away where someone lives
+const DEFAULT_NEW_LOCATION_VISIBILITY: &str = "private";
+
+// Why an uploaded file was not accepted, listed per file for photo rolls or turned into an ApiError
+#[derive(Serialize)]
+pub struct UploadRejection {
+    code: String, // "file_too_large", "request_too_large", "unsupported_type", "missing_file", "missing_title", "no_match" or "save_failed"
+    message: String,
+    filename: String, // As named by the client
+}
+
+impl UploadRejection {
+    fn new(code: &str, message: &str, filename: &str) -> UploadRejection {
+        UploadRejection {
+            code: code.to_string(),
+            message: message.to_string(),
+            filename: filename.to_string(),
+        }
     }
 
-    if s.len() < 3 {
-        return s;
+    fn save_failed(filename: &str) -> UploadRejection {
+        UploadRejection::new("save_failed", "File could not be saved", filename)
     }
+}
 
-    String::from(&s[1..s.len() - 1]) // Strip trailing quotes, "...", TODO: find a better way
+// A single file upload that was rejected fails the whole request
+impl From<UploadRejection> for ApiError {
+    fn from(rejection: UploadRejection) -> ApiError {
+        match rejection.code.as_str() {
+            "file_too_large" | "request_too_large" => ApiError::PayloadTooLarge(rejection.message),
+            "save_failed" => ApiError::Internal(rejection.message),
+            _ => ApiError::ValidationFailed(rejection.message),
+        }
+    }
 }
 
-async fn save_multipart_field(
-    mut field: actix_multipart::Field,
-    filename: &str,
-) -> Result<bool, Error> {
-    // Saves a file stored in this field of a multipart form
+impl From<MultipartError> for ApiError {
+    fn from(_: MultipartError) -> ApiError {
+        ApiError::ValidationFailed("Upload could not be read".to_string())
+    }
+}
 
-    let content_disposition = field
-        .content_disposition()
-        .ok_or_else(|| HttpResponse::BadRequest().finish())?;
+// Bytes received so far over the whole request
+struct UploadBudget {
+    limits: UploadLimits,
+    request_bytes: usize,
+}
 
-    let original_filename = content_disposition.get_filename().unwrap_or("");
+// Counts a chunk of an uploaded file toward the file and request limits
+// Returns the rejection code and message of the first limit it goes over
+fn count_file_chunk(limits: &UploadLimits, file_bytes: &mut usize, request_bytes: &mut usize, chunk_len: usize) -> Result<(), (&'static str, String)> {
+    *file_bytes += chunk_len;
+    *request_bytes += chunk_len;
 
-    let filepath = format!("./www/build/img/tmp/{}", filename);
-    println!("Saving file '{}' to: {}", original_filename, filepath);
+    if *file_bytes > limits.max_file_size {
+        return Err(("file_too_large", format!("Files can be at most {} bytes", limits.max_file_size)));
+    }
+
+    if *request_bytes > limits.max_request_size {
+        return Err(("request_too_large", format!("Uploads can be at most {} bytes in total", limits.max_request_size)));
+    }
+
+    Ok(())
+}
+
+// Returns the text value of a field of a multipart form, counted toward the request size limit
+async fn get_multipart_field(mut field: actix_multipart::Field, budget: &mut UploadBudget) -> Result<String, ApiError> {
+    let mut data = Vec::new();
+
+    while let Some(chunk) = field.try_next().await? {
+        budget.request_bytes += chunk.len();
+
+        if budget.request_bytes > budget.limits.max_request_size {
+            return Err(ApiError::PayloadTooLarge(format!("Uploads can be at most {} bytes in total", budget.limits.max_request_size)));
+        }
+
+        data.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(data).map_err(|_| ApiError::ValidationFailed("Form fields must be UTF-8 text".to_string()))
+}
+
+// An uploaded file that passed validation, still in staging until store_file
+struct SavedFile {
+    save_name: String,
+    staged_path: String,
+    original_filename: String,
+    media_type: MediaType,
+}
+
+// Deletes a (partially) written upload from staging
+async fn remove_upload(filepath: &str) {
+    let filepath = filepath.to_string();
+    web::block(move || std::fs::remove_file(filepath)).await.ok();
+}
+
+// Streams the field into filepath, checking the size limits on every chunk
+// and the file type as soon as enough bytes have arrived
+async fn stream_to_file(
+    field: &mut actix_multipart::Field,
+    filepath: &str,
+    original_filename: &str,
+    budget: &mut UploadBudget,
+) -> Result<MediaType, UploadRejection> {
+    let path = filepath.to_string();
 
     // File::create is blocking operation, use threadpool
-    let mut f = web::block(|| std::fs::File::create(filepath)).await?;
+    let mut f = web::block(|| std::fs::File::create(path))
+        .await
+        .map_err(|_| UploadRejection::save_failed(original_filename))?;
+
+    let mut head: Vec<u8> = Vec::new();
+    let mut media_type: Option<MediaType> = None;
+    let mut file_bytes: usize = 0;
 
     // Field in turn is stream of *Bytes* object
-    while let Some(chunk) = field.try_next().await? {
+    while let Some(chunk) = field.try_next().await.map_err(|_| UploadRejection::save_failed(original_filename))? {
+        if let Err((code, message)) = count_file_chunk(&budget.limits, &mut file_bytes, &mut budget.request_bytes, chunk.len()) {
+            return Err(UploadRejection::new(code, &message, original_filename));
+        }
+
+        // Hold back the first bytes until the type is known
+        let data = if media_type.is_none() {
+            head.extend_from_slice(&chunk);
+
+            if head.len() < SNIFF_LEN {
+                continue;
+            }
+
+            media_type = Some(sniff::sniff(&head).ok_or(UploadRejection::new("unsupported_type",
+                "Only JPEG, PNG, WebP, HEIC, MP4 and PDF files can be uploaded", original_filename))?);
+
+            web::Bytes::from(std::mem::take(&mut head))
+        } else {
+            chunk
+        };
+
         // filesystem operations are blocking, we have to use threadpool
-        f = web::block(move || f.write_all(&chunk).map(|_| f)).await?;
+        f = web::block(move || f.write_all(&data).map(|_| f))
+            .await
+            .map_err(|_| UploadRejection::save_failed(original_filename))?;
     }
 
-    return Ok(true);
+    // Whole file was shorter than SNIFF_LEN
+    if media_type.is_none() {
+        media_type = Some(sniff::sniff(&head).ok_or(UploadRejection::new("unsupported_type",
+            "Only JPEG, PNG, WebP, HEIC, MP4 and PDF files can be uploaded", original_filename))?);
+
+        web::block(move || f.write_all(&head))
+            .await
+            .map_err(|_| UploadRejection::save_failed(original_filename))?;
+    }
+
+    Ok(media_type.unwrap())
+}
+
+async fn save_multipart_field(
+    mut field: actix_multipart::Field,
+    save_stem: &str,
+    staging_dir: &str,
+    budget: &mut UploadBudget,
+) -> Result<SavedFile, UploadRejection> {
+    // Stages a file stored in this field of a multipart form as <save_stem>.<extension>
+    // Nothing is left on disk if it is rejected
+
+    let original_filename = field
+        .content_disposition()
+        .and_then(|cd| cd.get_filename().map(|f| f.to_string()))
+        .unwrap_or("".to_string());
+
+    let part_path = staging_path(staging_dir, &format!("{}.part", save_stem));
+
+    let media_type = match stream_to_file(&mut field, &part_path, &original_filename, budget).await {
+        Ok(media_type) => media_type,
+        Err(rejection) => {
+            remove_upload(&part_path).await;
+            return Err(rejection);
+        }
+    };
+
+    // Only now is the right extension known
+    let save_name = format!("{}.{}", save_stem, media_type.extension);
+    let staged_path = staging_path(staging_dir, &save_name);
+    let (from, to) = (part_path.to_string(), staged_path.to_string());
+
+    if web::block(move || std::fs::rename(from, to)).await.is_err() {
+        remove_upload(&part_path).await;
+        return Err(UploadRejection::save_failed(&original_filename));
+    }
+
+    Ok(SavedFile {
+        save_name,
+        staged_path,
+        original_filename,
+        media_type,
+    })
+}
+
+// Moves a staged file into the media store
+async fn store_file(state: &web::Data<AppState>, saved: &SavedFile) -> Result<(), UploadRejection> {
+    if let Err(e) = state.media_store.put_file(&saved.save_name, &saved.staged_path).await {
+        println!("Could not store '{}': {}", saved.save_name, e);
+        remove_upload(&saved.staged_path).await;
+        return Err(UploadRejection::save_failed(&saved.original_filename));
+    }
+
+    Ok(())
+}
+
+// Removes a file from the media store again when it could not be recorded in the db
+async fn unstore_file(state: &web::Data<AppState>, saved: &SavedFile) {
+    if let Err(e) = state.media_store.delete(&saved.save_name).await {
+        println!("Could not remove '{}' from media store: {}", saved.save_name, e);
+    }
+}
+
+// Reads the EXIF of a staged file
+async fn read_file_metadata(saved: &SavedFile) -> metadata::PhotoMetadata {
+    let filepath = saved.staged_path.to_string();
+
+    web::block(move || Ok::<_, ()>(metadata::read_metadata(&filepath)))
+        .await
+        .unwrap_or_default()
+}
+
+// Stores the EXIF of a saved image and queues its thumbnails
+async fn process_saved_image(state: &web::Data<AppState>, file_id: i64, saved: &SavedFile, photo_metadata: &metadata::PhotoMetadata) -> DbResult<()> {
+    state.db.set_file_metadata(file_id, photo_metadata).await?;
+
+    if saved.media_type.has_thumbnails {
+        state.thumbnails.queue(file_id, &saved.save_name, photo_metadata.orientation);
+    }
+    Ok(())
 }
 
 // Response for saving a new file
@@ -78,94 +274,381 @@ impl JSONSaveFileResp {
         }
     }
 
-    pub fn new_error(error: &str) -> JSONSaveFileResp {
-        JSONSaveFileResp::new(error, "")
-    }
-
     pub fn to_http_response(&self) -> HttpResponse {
         HttpResponse::Ok().json(self)
     }
 
-    pub fn to_ok(&self) -> Result<HttpResponse, Error> {
+    pub fn to_ok(&self) -> Result<HttpResponse, ApiError> {
         Ok(self.to_http_response())
     }
 }
 
+// Reads the fields of a single file upload, staging the file as soon as it arrives
+async fn read_photo_form(
+    payload: &mut Multipart,
+    state: &web::Data<AppState>,
+    budget: &mut UploadBudget,
+    saved: &mut Option<SavedFile>,
+    title: &mut String,
+    description: &mut String,
+) -> Result<(), ApiError> {
+    let save_stem = Uuid::new_v4().to_string(); // Generate a UUID for the filename for safe storage
+
+    // iterate over multipart stream
+    while let Some(field) = payload.try_next().await? {
+        // A multipart/form-data stream has to contain `content_disposition`
+        let content_disposition = field
+            .content_disposition()
+            .ok_or_else(|| ApiError::ValidationFailed("Upload is missing a content disposition".to_string()))?;
+
+        let name = content_disposition.get_name().unwrap_or("");
+
+        if name == "file" && saved.is_none() {
+            *saved = Some(save_multipart_field(field, &save_stem, &state.staging_dir, budget).await?);
+        } else if name == "title" {
+            *title = get_multipart_field(field, budget).await?;
+        } else if name == "description" {
+            *description = get_multipart_field(field, budget).await?;
+        }
+    }
+
+    Ok(())
+}
+
 #[post("/photo/{location_id}/")]
 pub async fn photo(
     web::Path(location_id): web::Path<i64>,
     mut payload: Multipart,
-    id: Identity,
+    user: AuthUser,
     state: web::Data<AppState>,
-    _session: Session,
-) -> Result<HttpResponse, Error> {
-    // TODO: Add verification to determine is photo
-    // TODO: limit file size
-    // TODO: only save file if title is also provided
-
-    if !user::login::does_this_user_have_permission(&id, &state, "saveFile").await {
-        return JSONSaveFileResp::new_error("you do not have permission").to_ok();
+) -> Result<HttpResponse, ApiError> {
+    if !user.has_permission(Permission::SaveFile) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
     }
 
     // Uploads a file and saves its name, location id, and metadata to db
-    println!("Location: {}", location_id);
+    if state.db.get_location(location_id, &user.viewer()).await?.is_none() {
+        return Err(ApiError::NotFound("not a valid location id".to_string()));
+    }
 
-    let save_name = Uuid::new_v4().to_string() + &".jpg".to_string(); // Generate a UUID for the filename for safe storage
+    let mut budget = UploadBudget {
+        limits: state.upload_limits,
+        request_bytes: 0,
+    };
+    let mut saved: Option<SavedFile> = None;
     let mut title = String::from("");
     let mut description = String::from("");
-    let mut error = "";
 
-    // iterate over multipart stream
+    // The file may already be staged when a later field fails, don't leave it behind
+    if let Err(e) = read_photo_form(&mut payload, &state, &mut budget, &mut saved, &mut title, &mut description).await {
+        if let Some(saved) = &saved {
+            remove_upload(&saved.staged_path).await;
+        }
+        return Err(e);
+    }
+
+    if saved.is_none() {
+        return Err(UploadRejection::new("missing_file", "No file was uploaded", "").into());
+    }
+
+    let saved = saved.unwrap();
+
+    if title == "" {
+        remove_upload(&saved.staged_path).await;
+        return Err(UploadRejection::new("missing_title", "No title provided for file", &saved.original_filename).into());
+    }
+
+    // EXIF is read while the file is still local
+    let photo_metadata = if saved.media_type.is_image {
+        Some(read_file_metadata(&saved).await) // capture time, camera, GPS
+    } else {
+        None
+    };
+
+    if let Err(rejection) = store_file(&state, &saved).await {
+        return Err(rejection.into());
+    }
+
+    let file_id = match state.db.add_file(location_id, &saved.save_name, &title, &description, user.user_id).await {
+        Ok(file_id) => file_id,
+        Err(e) => {
+            unstore_file(&state, &saved).await;
+            return Err(e.into());
+        }
+    };
+
+    if let Some(photo_metadata) = photo_metadata {
+        process_saved_image(&state, file_id, &saved, &photo_metadata).await?;
+    }
+
+    return JSONSaveFileResp::new("OK", &saved.save_name).to_ok();
+}
+
+#[derive(Deserialize)]
+pub struct PhotoRollQuery {
+    radius_m: Option<f64>,
+    visibility: Option<String>, // Of the locations created for the photos
+}
+
+// What happened to one file of a photo roll upload
+#[derive(Serialize)]
+pub struct JSONPhotoPlacement {
+    original_filename: String,
+    filename: String,
+    action: String, // "matched", "created", "no_gps" or "rejected"
+    location_id: i64,
+    error: Option<UploadRejection>,
+}
+
+// Response for uploading a photo roll
+#[derive(Serialize)]
+pub struct JSONPhotoRollResp {
+    status: String,
+    files: Vec<JSONPhotoPlacement>,
+}
+
+// Where a photo with GPS goes, decided before anything is written
+#[derive(Debug, PartialEq)]
+enum PhotoPlace {
+    Matched(i64), // Id of the nearest location within the radius
+    Create,       // Nothing nearby, a new location is made for the photo
+    NoMatch,      // Nothing nearby and the uploader may not add locations
+}
+
+// Snaps to the nearest location if there is one, otherwise a location is only created with AddLocation
+fn choose_photo_place(nearest_location_id: Option<i64>, can_add_location: bool) -> PhotoPlace {
+    match nearest_location_id {
+        Some(location_id) => PhotoPlace::Matched(location_id),
+        None if can_add_location => PhotoPlace::Create,
+        None => PhotoPlace::NoMatch,
+    }
+}
+
+// Records a stored photo at its place, creating the location first if needed
+// Returns "matched" or "created", the location id and the file id
+async fn place_photo(
+    state: &web::Data<AppState>,
+    saved: &SavedFile,
+    original_filename: &String,
+    photo_metadata: &metadata::PhotoMetadata,
+    place: &PhotoPlace,
+    visibility: &str,
+    user_id: i64,
+) -> DbResult<(&'static str, i64, i64)> {
+    let (lat, lon) = (photo_metadata.gps_lat.unwrap(), photo_metadata.gps_lon.unwrap());
+
+    let (action, location_id) = match place {
+        PhotoPlace::Matched(location_id) => ("matched", *location_id),
+        _ => ("created", state.db.add_location(original_filename, lat, lon, &NEW_LOCATION_KIND.to_string(), visibility, user_id).await?),
+    };
+
+    let file_id = state.db.add_file(location_id, &saved.save_name, original_filename, &"".to_string(), user_id).await?;
+
+    Ok((action, location_id, file_id))
+}
+
+// Uploads any number of photos without a location, each one is placed using its GPS EXIF:
+// snapped to the nearest existing location within radius_m, or a new "photo" location is created if the
+// uploader has AddLocation. A file that cannot be placed or saved is reported as rejected, the others are still kept
+#[post("/photos/")]
+pub async fn photos(
+    query: web::Query<PhotoRollQuery>,
+    mut payload: Multipart,
+    user: AuthUser,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    if !user.has_permission(Permission::SaveFile) {
+        return Err(ApiError::Forbidden("you do not have permission".to_string()));
+    }
+
+    let radius_m = query.radius_m.unwrap_or(DEFAULT_SNAP_RADIUS_M);
+    if !(radius_m >= 0.0 && radius_m.is_finite()) {
+        return Err(ApiError::ValidationFailed("radius_m must not be negative".to_string()));
+    }
+
+    let visibility = new_visibility(query.visibility.as_deref(), DEFAULT_NEW_LOCATION_VISIBILITY).map_err(ApiError::ValidationFailed)?;
+
+    let can_add_location = user.has_permission(Permission::AddLocation);
+    let user_id = user.user_id;
+    let viewer = user.viewer(); // Only snap to locations the uploader can see
+    let mut budget = UploadBudget {
+        limits: state.upload_limits,
+        request_bytes: 0,
+    };
+    let mut placements = Vec::new();
+
     while let Some(field) = payload.try_next().await? {
-        // A multipart/form-data stream has to contain `content_disposition`
         let content_disposition = field
             .content_disposition()
-            .ok_or_else(|| HttpResponse::BadRequest().finish())?;
+            .ok_or_else(|| ApiError::ValidationFailed("Upload is missing a content disposition".to_string()))?;
 
-        let name = content_disposition.get_name().unwrap_or("");
+        if content_disposition.get_name().unwrap_or("") != "file" {
+            continue;
+        }
 
-        if name == "file" {
-            let save_result = save_multipart_field(field, &save_name)
-                .await
-                .ok()
-                .unwrap_or(false);
-            if save_result {
-                println!("File saved successfully");
-            } else {
-                error = "Error: File could not be saved.";
-                break;
+        let mut placement = JSONPhotoPlacement {
+            original_filename: content_disposition.get_filename().unwrap_or("").to_string(),
+            filename: "".to_string(),
+            action: "rejected".to_string(),
+            location_id: -1,
+            error: None,
+        };
+
+        let saved = match save_multipart_field(field, &Uuid::new_v4().to_string(), &state.staging_dir, &mut budget).await {
+            Ok(saved) => saved,
+            Err(rejection) => {
+                // Nothing more can be read once the request is over its limit
+                let stop = rejection.code == "request_too_large";
+                placement.error = Some(rejection);
+                placements.push(placement);
+
+                if stop {
+                    break;
+                }
+                continue;
             }
-        } else if name == "title" {
-            title = get_multipart_field(field).await;
-            println!("filename title '{}'", title);
-        } else if name == "description" {
-            description = get_multipart_field(field).await;
+        };
+
+        if !saved.media_type.is_image {
+            remove_upload(&saved.staged_path).await;
+            placement.error = Some(UploadRejection::new("unsupported_type", "Only photos can be placed by GPS", &saved.original_filename));
+            placements.push(placement);
+            continue;
         }
-    }
 
-    let filename: &str;
-    let status: &str;
+        let photo_metadata = read_file_metadata(&saved).await;
 
-    // Save filename & data into database
-    if error.eq("") {
-        if title != "" {
-            println!("Inserting into database");
-            println!("{}:\n{}", title, description);
- 
-            let username = user::login::get_this_username(&id).unwrap(); // Already know this is valid user from permission guard if abov
-            let user_id = state.db.get_user_id(&username).await;
-            state.db.add_file(location_id, &save_name, &title, &description, user_id).await;
+        if !photo_metadata.has_gps() {
+            // Nowhere to put it, don't keep the file around
+            remove_upload(&saved.staged_path).await;
 
-            filename = &save_name;
-            status = "OK";
-        } else {
-            status = "Error: No title provided for file";
-            filename = "";
+            placement.action = "no_gps".to_string();
+            placements.push(placement);
+            continue;
         }
-    } else {
-        status = error;
-        filename = "";
+
+        let (lat, lon) = (photo_metadata.gps_lat.unwrap(), photo_metadata.gps_lon.unwrap());
+        let nearest = match state.db.get_nearest_location(lat, lon, radius_m, &viewer).await {
+            Ok(nearest) => nearest,
+            Err(e) => {
+                println!("Could not place '{}': {}", saved.save_name, e);
+                remove_upload(&saved.staged_path).await;
+                placement.error = Some(UploadRejection::save_failed(&saved.original_filename));
+                placements.push(placement);
+                continue;
+            }
+        };
+
+        let place = choose_photo_place(nearest.map(|location| location.id), can_add_location);
+
+        if place == PhotoPlace::NoMatch {
+            remove_upload(&saved.staged_path).await;
+            placement.error = Some(UploadRejection::new("no_match",
+                "No location is near enough and you do not have permission to add one", &saved.original_filename));
+            placements.push(placement);
+            continue;
+        }
+
+        if let Err(rejection) = store_file(&state, &saved).await {
+            placement.error = Some(rejection);
+            placements.push(placement);
+            continue;
+        }
+
+        let (action, location_id, file_id) = match place_photo(&state, &saved, &placement.original_filename, &photo_metadata, &place, visibility, user_id).await {
+            Ok(placed) => placed,
+            Err(e) => {
+                println!("Could not place '{}': {}", saved.save_name, e);
+                unstore_file(&state, &saved).await;
+                placement.error = Some(UploadRejection::save_failed(&saved.original_filename));
+                placements.push(placement);
+                continue;
+            }
+        };
+
+        if let Err(e) = process_saved_image(&state, file_id, &saved, &photo_metadata).await {
+            println!("Could not save metadata of '{}': {}", saved.save_name, e);
+            state.db.delete_file(file_id).await.ok();
+            unstore_file(&state, &saved).await;
+            placement.error = Some(UploadRejection::save_failed(&saved.original_filename));
+            placements.push(placement);
+            continue;
+        }
+
+        placement.action = action.to_string();
+        placement.location_id = location_id;
+        placement.filename = saved.save_name;
+        placements.push(placement);
+    }
+
+    Ok(HttpResponse::Ok().json(JSONPhotoRollResp {
+        status: "OK".to_string(),
+        files: placements,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: UploadLimits = UploadLimits {
+        max_file_size: 10,
+        max_request_size: 25,
+    };
+
+    #[test]
+    fn chunks_within_the_limits_are_counted() {
+        let (mut file_bytes, mut request_bytes) = (0, 5);
+
+        assert!(count_file_chunk(&LIMITS, &mut file_bytes, &mut request_bytes, 4).is_ok());
+        assert!(count_file_chunk(&LIMITS, &mut file_bytes, &mut request_bytes, 6).is_ok());
+        assert_eq!((file_bytes, request_bytes), (10, 15));
+    }
+
+    #[test]
+    fn files_over_the_limit_are_too_large() {
+        let (mut file_bytes, mut request_bytes) = (0, 0);
+
+        assert!(count_file_chunk(&LIMITS, &mut file_bytes, &mut request_bytes, 8).is_ok());
+        let (code, _) = count_file_chunk(&LIMITS, &mut file_bytes, &mut request_bytes, 3).unwrap_err();
+        assert_eq!(code, "file_too_large");
     }
 
-    return JSONSaveFileResp::new(status, filename).to_ok();
+    #[test]
+    fn requests_over_the_limit_are_too_large() {
+        // Earlier files and fields already used most of the request
+        let (mut file_bytes, mut request_bytes) = (0, 20);
+
+        let (code, _) = count_file_chunk(&LIMITS, &mut file_bytes, &mut request_bytes, 6).unwrap_err();
+        assert_eq!(code, "request_too_large");
+
+        // Going over both, the file limit is reported
+        let (mut file_bytes, mut request_bytes) = (0, 20);
+        let (code, _) = count_file_chunk(&LIMITS, &mut file_bytes, &mut request_bytes, 11).unwrap_err();
+        assert_eq!(code, "file_too_large");
+    }
+
+    #[test]
+    fn rejections_map_to_api_errors() {
+        let error: ApiError = UploadRejection::new("request_too_large", "", "a.jpg").into();
+        assert!(matches!(error, ApiError::PayloadTooLarge(_)));
+
+        let error: ApiError = UploadRejection::new("unsupported_type", "", "a.exe").into();
+        assert!(matches!(error, ApiError::ValidationFailed(_)));
+
+        let error: ApiError = UploadRejection::save_failed("a.jpg").into();
+        assert!(matches!(error, ApiError::Internal(_)));
+    }
+
+    #[test]
+    fn photos_snap_to_the_nearest_location() {
+        assert_eq!(choose_photo_place(Some(7), true), PhotoPlace::Matched(7));
+        assert_eq!(choose_photo_place(Some(7), false), PhotoPlace::Matched(7));
+    }
+
+    #[test]
+    fn locations_are_only_created_with_add_location() {
+        assert_eq!(choose_photo_place(None, true), PhotoPlace::Create);
+        assert_eq!(choose_photo_place(None, false), PhotoPlace::NoMatch);
+    }
 }
@@ -0,0 +1,169 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::dev::Payload;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::db::locations::Viewer;
+use crate::db::permissions::Permission;
+use crate::db::users::UserInfo;
+use crate::web_srv::error::ApiError;
+use crate::web_srv::user::login::UserClientData;
+use crate::web_srv::AppState;
+
+// Only these routes accept API tokens, the rest (ie. /user/tokens/) need a real login
+const BEARER_PATHS: &[&str] = &["/api/", "/upload/"];
+
+// A logged in user whose session has passed both password and TOTP checks,
+// or who sent a valid API token
+// Taking this as a handler argument is what guards every authenticated write
+pub struct AuthUser {
+    pub user_id:    i64,
+    pub username:   String,
+    pub user:       UserInfo,
+    pub scopes:     Option<Vec<Permission>>, // Set when logged in with an API token
+}
+
+impl AuthUser {
+    // Return true if user has this permission or "*"
+    // An API token also needs the permission (or "*") in its scopes
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        if let Some(scopes) = &self.scopes {
+            if !scopes.iter().any(|scope| *scope == permission || *scope == Permission::All) {
+                return false;
+            }
+        }
+
+        self.user.has_permission(permission)
+    }
+
+    // What this user can read, "*" sees every location
+    pub fn viewer(&self) -> Viewer {
+        Viewer::user(self.user_id, self.has_permission(Permission::All))
+    }
+}
+
+#[derive(Deserialize)]
+struct ShareQuery {
+    share: Option<String>,
+}
+
+// Returns the token of an "Authorization: Bearer <token>" header
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let token = header.strip_prefix("Bearer ")?.trim();
+
+    Some(token.to_string())
+}
+
+async fn from_api_token(token: &str, req: &HttpRequest) -> Result<AuthUser, Error> {
+    let state = web::Data::<AppState>::extract(req).await?;
+
+    if !BEARER_PATHS.iter().any(|path| req.path().starts_with(path)) {
+        return Err(not_logged_in());
+    }
+
+    let token_user = match state.db.get_api_token_user(token).await.map_err(ApiError::from)? {
+        Some(token_user) => token_user,
+        None => return Err(not_logged_in()),
+    };
+
+    let user = match state.db.get_user_by_username(&token_user.username).await.map_err(ApiError::from)? {
+        Some(user) => user,
+        None => return Err(not_logged_in()),
+    };
+
+    Ok(AuthUser {
+        user_id:    token_user.user_id,
+        username:   token_user.username,
+        user,
+        scopes:     Some(token_user.scopes),
+    })
+}
+
+// Rejects the request with a 401 not_logged_in
+fn not_logged_in() -> Error {
+    ApiError::NotLoggedIn("Not logged in".to_string()).into()
+}
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            if let Some(token) = bearer_token(&req) {
+                return from_api_token(&token, &req).await;
+            }
+
+            let id = Identity::extract(&req).await?;
+            let session = Session::extract(&req).await?;
+            let state = web::Data::<AppState>::extract(&req).await?;
+
+            let username = match super::login::get_this_username(&id, &state).await.map_err(ApiError::from)? {
+                Some(username) => username,
+                None => return Err(not_logged_in()),
+            };
+
+            // User is required to verify TOTP before they can do things
+            let totp_verified = session
+                .get::<UserClientData>("user")
+                .ok()
+                .flatten()
+                .map(|user| user.totp_verified)
+                .unwrap_or(false);
+
+            if !totp_verified {
+                return Err(ApiError::TotpRequired.into());
+            }
+
+            let user = match state.db.get_user_by_username(&username).await.map_err(ApiError::from)? {
+                Some(user) => user,
+                None => return Err(not_logged_in()),
+            };
+
+            Ok(AuthUser {
+                user_id: state.db.get_user_id(&username).await.map_err(ApiError::from)?,
+                username,
+                user,
+                scopes: None,
+            })
+        })
+    }
+}
+
+// Whoever is reading, logged in or not, plus a ?share= token for "link" locations
+// Never fails, anyone not (fully) logged in only sees public locations
+impl FromRequest for Viewer {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let state = web::Data::<AppState>::extract(&req).await?;
+
+            // Nobody can log in without the auth api, so only public data is served
+            if !state.use_auth_api {
+                return Ok(Viewer::public());
+            }
+
+            let share_token = web::Query::<ShareQuery>::from_query(req.query_string())
+                .ok()
+                .and_then(|query| query.into_inner().share);
+
+            let viewer = match AuthUser::from_request(&req, &mut Payload::None).await {
+                Ok(user) => user.viewer(),
+                Err(_) => Viewer::public(),
+            };
+
+            Ok(viewer.with_share_token(share_token))
+        })
+    }
+}
@@ -0,0 +1,325 @@
+use actix_web::HttpRequest;
+use chrono::Utc;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::db::MapDB;
+use crate::db::error::DbResult;
+use crate::db::login_attempts::LoginAttempts;
+use crate::settings::Settings;
+use crate::web_srv::error::ApiError;
+
+// Failures allowed before any lockout
+const FREE_FAILURES: i64        = 3;
+
+// First lockout, doubled for every failure after that
+const BASE_LOCKOUT_SECS: i64    = 2;
+const MAX_LOCKOUT_SECS: i64     = 15 * 60;
+
+// Failures are forgotten once there have been none for this long
+const FAILURE_RESET_SECS: i64   = 60 * 60;
+
+// Tracks failed logins per ip and per account, with exponential backoff between attempts
+// Kept in memory, and also written to the db when persist is on so lockouts survive restarts
+#[derive(Clone)]
+pub struct LoginLimiter {
+    attempts:       Arc<Mutex<HashMap<String, LoginAttempts>>>,
+    db:             Option<MapDB>,
+    trust_proxy:    bool,
+}
+
+impl LoginLimiter {
+    pub async fn new(db: &MapDB, settings: &Settings) -> DbResult<LoginLimiter> {
+        let persist = settings.persist_lockouts;
+        let mut attempts = HashMap::new();
+
+        if persist {
+            for row in db.get_all_login_attempts().await? {
+                attempts.insert(row.key.clone(), row);
+            }
+        }
+
+        Ok(LoginLimiter {
+            attempts:       Arc::new(Mutex::new(attempts)),
+            db:             if persist { Some(db.clone()) } else { None },
+            trust_proxy:    settings.trust_proxy,
+        })
+    }
+
+    // Address a request came from, without the port so it is the same for every connection
+    // Forwarded headers can be set by any client, so are only used with the trust_proxy setting
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        if self.trust_proxy {
+            if let Some(addr) = req.connection_info().realip_remote_addr() {
+                // Falls back to the peer's "ip:port" when there is no forwarded header
+                return match addr.parse::<SocketAddr>() {
+                    Ok(addr) => addr.ip().to_string(),
+                    Err(_) => addr.to_string(),
+                };
+            }
+        }
+
+        req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+    }
+
+    // Key for the address a request came from, the same address sessions record
+    pub fn ip_key(&self, req: &HttpRequest) -> String {
+        format!("ip:{}", self.client_ip(req))
+    }
+
+    pub fn account_key(username: &str) -> String {
+        format!("user:{}", username)
+    }
+
+    // Returns the seconds left if any of keys is locked out
+    pub async fn check(&self, keys: &[String]) -> DbResult<Option<i64>> {
+        self.check_at(keys, Utc::now().timestamp()).await
+    }
+
+    async fn check_at(&self, keys: &[String], now: i64) -> DbResult<Option<i64>> {
+        let mut retry_after = None;
+
+        self.prune(now).await?;
+
+        for key in keys {
+            let locked_until = match self.attempts.lock().unwrap().get(key) {
+                Some(attempts) if attempts.locked_until > now => attempts.locked_until,
+                _ => continue,
+            };
+
+            // Lockouts cleared from the CLI are gone from the db, forget them here too
+            if let Some(db) = &self.db {
+                if db.get_login_attempts(key).await?.is_none() {
+                    self.attempts.lock().unwrap().remove(key);
+                    continue;
+                }
+            }
+
+            retry_after = retry_after.max(Some(locked_until - now));
+        }
+
+        Ok(retry_after)
+    }
+
+    pub async fn record_failure(&self, keys: &[String]) -> DbResult<()> {
+        self.record_failure_at(keys, Utc::now().timestamp()).await
+    }
+
+    async fn record_failure_at(&self, keys: &[String], now: i64) -> DbResult<()> {
+        for key in keys {
+            let attempts = {
+                let mut all_attempts = self.attempts.lock().unwrap();
+                let attempts = all_attempts.entry(key.to_string()).or_insert(LoginAttempts {
+                    key:                key.to_string(),
+                    failures:           0,
+                    last_failure_date:  now,
+                    locked_until:       -1,
+                });
+
+                if now - attempts.last_failure_date > FAILURE_RESET_SECS {
+                    attempts.failures = 0;
+                }
+
+                attempts.failures += 1;
+                attempts.last_failure_date = now;
+
+                if attempts.failures > FREE_FAILURES {
+                    attempts.locked_until = now + LoginLimiter::lockout_secs(attempts.failures);
+                }
+
+                attempts.clone()
+            };
+
+            if let Some(db) = &self.db {
+                db.save_login_attempts(&attempts).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Forgets keys that are not locked out and would have their failures reset anyway,
+    // so addresses that stop trying don't stay in memory forever
+    async fn prune(&self, now: i64) -> DbResult<()> {
+        let pruned = {
+            let mut all_attempts = self.attempts.lock().unwrap();
+            let count = all_attempts.len();
+
+            all_attempts.retain(|_, attempts| {
+                attempts.locked_until > now || now - attempts.last_failure_date <= FAILURE_RESET_SECS
+            });
+
+            all_attempts.len() < count
+        };
+
+        if pruned {
+            if let Some(db) = &self.db {
+                db.delete_expired_login_attempts(now, now - FAILURE_RESET_SECS).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // A successful login forgets earlier failures
+    pub async fn record_success(&self, keys: &[String]) -> DbResult<()> {
+        for key in keys {
+            let removed = self.attempts.lock().unwrap().remove(key).is_some();
+
+            if removed {
+                if let Some(db) = &self.db {
+                    db.delete_login_attempts(key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 429 too_many_attempts, with retry_after
+    pub fn locked_out(retry_after: i64) -> ApiError {
+        ApiError::TooManyAttempts(retry_after)
+    }
+
+    // 2s, 4s, 8s, ... up to MAX_LOCKOUT_SECS
+    fn lockout_secs(failures: i64) -> i64 {
+        let doublings = (failures - FREE_FAILURES - 1).min(20) as u32;
+        (BASE_LOCKOUT_SECS << doublings).min(MAX_LOCKOUT_SECS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::block_on;
+
+    const NOW: i64 = 1_600_000_000;
+
+    // Limiter that only keeps attempts in memory
+    fn limiter() -> LoginLimiter {
+        LoginLimiter {
+            attempts:       Arc::new(Mutex::new(HashMap::new())),
+            db:             None,
+            trust_proxy:    false,
+        }
+    }
+
+    fn keys() -> Vec<String> {
+        vec!["ip:192.0.2.1".to_string(), LoginLimiter::account_key("alice")]
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let lockouts: Vec<i64> = (FREE_FAILURES + 1..=FREE_FAILURES + 12).map(LoginLimiter::lockout_secs).collect();
+
+        assert_eq!(lockouts, vec![2, 4, 8, 16, 32, 64, 128, 256, 512, MAX_LOCKOUT_SECS, MAX_LOCKOUT_SECS, MAX_LOCKOUT_SECS]);
+        assert_eq!(LoginLimiter::lockout_secs(1_000_000), MAX_LOCKOUT_SECS);
+    }
+
+    #[test]
+    fn free_failures_are_not_locked_out() {
+        block_on(async {
+            let limiter = limiter();
+
+            for _ in 0..FREE_FAILURES {
+                limiter.record_failure_at(&keys(), NOW).await.unwrap();
+                assert_eq!(limiter.check_at(&keys(), NOW).await.unwrap(), None);
+            }
+
+            limiter.record_failure_at(&keys(), NOW).await.unwrap();
+            assert_eq!(limiter.check_at(&keys(), NOW).await.unwrap(), Some(BASE_LOCKOUT_SECS));
+            assert_eq!(limiter.check_at(&keys(), NOW + 1).await.unwrap(), Some(BASE_LOCKOUT_SECS - 1));
+            assert_eq!(limiter.check_at(&keys(), NOW + BASE_LOCKOUT_SECS).await.unwrap(), None);
+
+            limiter.record_failure_at(&keys(), NOW + BASE_LOCKOUT_SECS).await.unwrap();
+            assert_eq!(limiter.check_at(&keys(), NOW + BASE_LOCKOUT_SECS).await.unwrap(), Some(2 * BASE_LOCKOUT_SECS));
+        });
+    }
+
+    #[test]
+    fn any_locked_key_locks_the_login() {
+        block_on(async {
+            let limiter = limiter();
+            let account = vec![LoginLimiter::account_key("alice")];
+
+            for _ in 0..=FREE_FAILURES {
+                limiter.record_failure_at(&account, NOW).await.unwrap();
+            }
+
+            // The same account from another address
+            let other_ip = vec!["ip:198.51.100.7".to_string(), LoginLimiter::account_key("alice")];
+            assert_eq!(limiter.check_at(&other_ip, NOW).await.unwrap(), Some(BASE_LOCKOUT_SECS));
+
+            let other_account = vec!["ip:198.51.100.7".to_string(), LoginLimiter::account_key("bob")];
+            assert_eq!(limiter.check_at(&other_account, NOW).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn failures_reset_after_a_quiet_period() {
+        block_on(async {
+            let limiter = limiter();
+
+            for _ in 0..FREE_FAILURES {
+                limiter.record_failure_at(&keys(), NOW).await.unwrap();
+            }
+
+            // One more failure within the reset period is locked out, after it starts over
+            let later = NOW + FAILURE_RESET_SECS + 1;
+            limiter.record_failure_at(&keys(), later).await.unwrap();
+            assert_eq!(limiter.check_at(&keys(), later).await.unwrap(), None);
+
+            let attempts = limiter.attempts.lock().unwrap();
+            assert_eq!(attempts.get(&keys()[0]).unwrap().failures, 1);
+        });
+    }
+
+    #[test]
+    fn success_clears_the_keys() {
+        block_on(async {
+            let limiter = limiter();
+
+            for _ in 0..=FREE_FAILURES {
+                limiter.record_failure_at(&keys(), NOW).await.unwrap();
+            }
+            assert!(limiter.check_at(&keys(), NOW).await.unwrap().is_some());
+
+            limiter.record_success(&keys()).await.unwrap();
+            assert_eq!(limiter.check_at(&keys(), NOW).await.unwrap(), None);
+            assert!(limiter.attempts.lock().unwrap().is_empty());
+
+            // Failures count from zero again
+            limiter.record_failure_at(&keys(), NOW).await.unwrap();
+            assert_eq!(limiter.check_at(&keys(), NOW).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn pruning_keeps_keys_that_are_still_locked() {
+        block_on(async {
+            let limiter = limiter();
+            let quiet = vec!["ip:192.0.2.2".to_string()];
+            let locked = vec!["ip:192.0.2.3".to_string()];
+            let recent = vec!["ip:192.0.2.4".to_string()];
+
+            limiter.record_failure_at(&quiet, NOW).await.unwrap();
+
+            // Locked for longer than FAILURE_RESET_SECS after its last failure
+            limiter.attempts.lock().unwrap().insert(locked[0].to_string(), LoginAttempts {
+                key:                locked[0].to_string(),
+                failures:           30,
+                last_failure_date:  NOW,
+                locked_until:       NOW + FAILURE_RESET_SECS + 60,
+            });
+
+            limiter.record_failure_at(&recent, NOW + FAILURE_RESET_SECS).await.unwrap();
+
+            let now = NOW + FAILURE_RESET_SECS + 1;
+            assert_eq!(limiter.check_at(&locked, now).await.unwrap(), Some(59));
+
+            let attempts = limiter.attempts.lock().unwrap();
+            assert!(!attempts.contains_key(&quiet[0]));
+            assert!(attempts.contains_key(&locked[0]));
+            assert!(attempts.contains_key(&recent[0]));
+        });
+    }
+}
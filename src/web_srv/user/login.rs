@@ -1,11 +1,16 @@
 use actix_identity::Identity;
 use actix_session::Session;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
 
 use serde::{Deserialize, Serialize};
 
+use crate::web_srv::error::ApiError;
 use crate::web_srv::response::JSONResponse;
+use crate::db::error::DbResult;
+use crate::db::sessions::SessionInfo;
 use crate::db::users::UserInfo;
+use crate::web_srv::user::guard::AuthUser;
+use crate::web_srv::user::limiter::LoginLimiter;
 use crate::web_srv::AppState;
 
 #[derive(Deserialize)]
@@ -13,6 +18,8 @@ struct LoginJSONIn {
     username:   String,
     password:   String,
     totp_code:  Option<String>,
+    // Used in place of totp_code when the TOTP device is lost
+    recovery_code:  Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -20,11 +27,19 @@ struct LoginTOTPReq {
     totp_code:  String,
 }
 
+#[derive(Deserialize)]
+struct TOTPResetReq {
+    username:       String,
+    password:       String,
+    recovery_code:  String,
+}
+
 // QR code in BASE64 String
 #[derive(Serialize)]
 pub struct QrResp {
     pub status: String,
     pub qr_code: String,
+    pub recovery_codes: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -33,83 +48,73 @@ pub struct UserClientData {
 }
 
 
-pub async fn validate_identity(id: &Identity, state: &web::Data<AppState>) -> bool {
-    // Returns true if there is a login identity, false otherwise
-
-    if let Some(id) = id.identity() {
-        return state.db.is_user(&id).await; // Make sure user exists in DB, TODO: add check for is_login, valid_session?
-    }
-
-    false
-}
-
-pub async fn validate_session(id: &Identity, session: &Session, state: &web::Data<AppState>) -> bool {
-    if !validate_identity(id, state).await {
-        return false;
-    }
-
-    // User is required to verify TOTP before they can do things
-    if let Some(user) = session.get::<UserClientData>("user").unwrap() {
-        return user.totp_verified;
-    }
-
-    false
+pub async fn validate_identity(id: &Identity, state: &web::Data<AppState>) -> DbResult<bool> {
+    // Returns true if there is a login identity with a live session, false otherwise
+    Ok(get_this_username(id, state).await?.is_some())
 }
 
 // Gets the user profile of the current logged in user
-pub async fn get_this_user(id: &Identity, state: &web::Data<AppState>) -> Option<UserInfo> {
-    if let Some(id) = id.identity() {
-        return state.db.get_user_by_username(&id).await
+pub async fn get_this_user(id: &Identity, state: &web::Data<AppState>) -> DbResult<Option<UserInfo>> {
+    if let Some(username) = get_this_username(id, state).await? {
+        return state.db.get_user_by_username(&username).await
     }
 
-    None
+    Ok(None)
 }
 
 // Gets the username of the current logged in user
-pub fn get_this_username(id: &Identity) -> Option<String> {
-    if let Some(id) = id.identity() {
-        return Some(id)
+// The identity is a session token, it is only valid while the session exists
+pub async fn get_this_username(id: &Identity, state: &web::Data<AppState>) -> DbResult<Option<String>> {
+    if let Some(token) = id.identity() {
+        return state.db.get_session_username(&token).await
     }
 
-    None
+    Ok(None)
 }
 
 // Gets the user_id of the current logged in user
-pub async fn get_this_user_id(id: &Identity, state: &web::Data<AppState>) -> i64 {
-    if let Some(id) = id.identity() {
-        return state.db.get_user_id(&id).await;
+pub async fn get_this_user_id(id: &Identity, state: &web::Data<AppState>) -> DbResult<i64> {
+    if let Some(username) = get_this_username(id, state).await? {
+        return state.db.get_user_id(&username).await;
     }
 
-    -1
+    Ok(-1)
 }
 
-// Return true if user has this permission or "*"
-pub async fn does_this_user_have_permission(id: &Identity, state: &web::Data<AppState>, permission: &str) -> bool {
-    if let Some(user) = get_this_user(&id, &state).await {
-        // * has all permissions
-        if user.group.permissions == "*" {
-            return true;
-        }
-        if user.group.permissions.contains(permission) {
-            return true;
-        }
-    }
-
-    false
+// Starts a server-side session for user_id and remembers its token in the identity cookie
+async fn start_session(id: &Identity, req: &HttpRequest, state: &web::Data<AppState>, user_id: i64) -> DbResult<()> {
+    let ip = state.login_limiter.client_ip(req);
+    let user_agent = req.headers()
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    let token = state.db.add_session(user_id, &ip, user_agent).await?;
+    id.remember(token);
+    Ok(())
 }
 
 // Returns the user_id if the credentials in json_login are correct, otherwise -1
-async fn get_login_id(json_login: &web::Json<LoginJSONIn>, state: &web::Data<AppState>) -> i64 {
+async fn get_login_id(json_login: &web::Json<LoginJSONIn>, state: &web::Data<AppState>) -> DbResult<i64> {
     // Checks if the provided login credentials are valid
-    if json_login.totp_code.is_none() {
-        return -1;
+    if let Some(totp_code) = &json_login.totp_code {
+        return state.db.is_user_login(
+                &json_login.username, 
+                &json_login.password, 
+                &totp_code
+        ).await;
+    }
+
+    // Password is checked first so a wrong one does not use up the recovery code
+    if let Some(recovery_code) = &json_login.recovery_code {
+        let user_id = state.db.check_user_password(&json_login.username, &json_login.password).await?;
+
+        if user_id != -1 && state.db.use_recovery_code(user_id, &recovery_code).await? {
+            return Ok(user_id);
+        }
     }
 
-    state.db.is_user_login(
-            &json_login.username, 
-            &json_login.password, 
-            &json_login.totp_code.as_ref().unwrap()
-    ).await
+    Ok(-1)
 }
 
 // Set session information
@@ -126,12 +131,12 @@ fn set_session(session: &Session, totp_verified: bool) {
 
 // Returns the user profile of the currently logged in user to the client
 #[get("/")]
-async fn index(id: Identity, state: web::Data<AppState>, _session: Session) -> Result<HttpResponse, Error> {
-    let user = get_this_user(&id, &state).await;
+async fn index(id: Identity, state: web::Data<AppState>, _session: Session) -> Result<HttpResponse, ApiError> {
+    let user = get_this_user(&id, &state).await?;
 
     // access request identity
     if user.is_none() {
-        JSONResponse::new_error("Not logged in").to_ok()
+        Err(ApiError::NotLoggedIn("Not logged in".to_string()))
     } else {
         JSONResponse::UserInfo(user.unwrap()).to_ok()
     }
@@ -139,31 +144,35 @@ async fn index(id: Identity, state: web::Data<AppState>, _session: Session) -> R
 
 // Returns the user profile of another user (if logged in and allowed)
 #[get("/users/{username}/")]
-async fn get_user(web::Path(username): web::Path<String>, id: Identity, state: web::Data<AppState>, session: Session) -> Result<HttpResponse, Error> 
+async fn get_user(web::Path(username): web::Path<String>, _user: AuthUser, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> 
 {
-    if !validate_session(&id, &session, &state).await {
-        return JSONResponse::new_error("Must be logged in").to_ok();
-    }
-
-    let user = state.db.get_user_by_username(&username).await;
+    let user = state.db.get_user_by_username(&username).await?;
 
     // access request identity
     if user.is_none() {
-        JSONResponse::new_error("No such user").to_ok()
+        Err(ApiError::NotFound("No such user".to_string()))
     } else {
-        JSONResponse::UserInfo(user.unwrap()).to_ok()
+        JSONResponse::PublicUserInfo(user.unwrap().public()).to_ok()
     }
 }
 
 // Returns OK if username exists, used to check if user should login/register
 #[get("/isUser/{username}/")]
-async fn is_user(web::Path(username): web::Path<String>, state: web::Data<AppState>) -> Result<HttpResponse, Error> 
+async fn is_user(web::Path(username): web::Path<String>, req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> 
 {
-    let user = state.db.get_user_by_username(&username).await;
+    // Misses count as failures, so usernames cannot be enumerated quickly
+    let keys = [state.login_limiter.ip_key(&req)];
+
+    if let Some(retry_after) = state.login_limiter.check(&keys).await? {
+        return Err(LoginLimiter::locked_out(retry_after));
+    }
+
+    let user = state.db.get_user_by_username(&username).await?;
 
     // Returns OK if user, otherwise error
     if user.is_none() {
-        JSONResponse::new_error("No such user").to_ok()
+        state.login_limiter.record_failure(&keys).await?;
+        Err(ApiError::NotFound("No such user".to_string()))
     } else {
         JSONResponse::new_ok().to_ok()
     }
@@ -171,82 +180,196 @@ async fn is_user(web::Path(username): web::Path<String>, state: web::Data<AppSta
 
 // Logs in the user (if possible), using json_login, will set identity and session on success
 #[post("/login/")]
-async fn login(id: Identity, json_login: web::Json<LoginJSONIn>, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+async fn login(id: Identity, req: HttpRequest, json_login: web::Json<LoginJSONIn>, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
     // Don't bother if already logged in
-    if validate_identity(&id, &state).await {
-        return JSONResponse::new_error("Already logged in").to_ok();
+    if validate_identity(&id, &state).await? {
+        return Err(ApiError::Conflict("Already logged in".to_string()));
+    }
+
+    let keys = [state.login_limiter.ip_key(&req), LoginLimiter::account_key(&json_login.username)];
+
+    if let Some(retry_after) = state.login_limiter.check(&keys).await? {
+        return Err(LoginLimiter::locked_out(retry_after));
     }
 
-    if get_login_id(&json_login, &state).await != -1 {
+    let user_id = get_login_id(&json_login, &state).await?;
+
+    if user_id != -1 {
+        state.login_limiter.record_success(&keys).await?;
+
         // Remember identity and save session
-        id.remember(json_login.username.to_owned());
-        set_session(&session, state.db.is_user_totp_verified(&json_login.username).await);
+        start_session(&id, &req, &state, user_id).await?;
+
+        // A valid TOTP code was required to get here, so this session is verified
+        if !state.db.is_user_totp_verified(&json_login.username).await? {
+            state.db.verified_totp(&json_login.username).await?;
+        }
+        set_session(&session, true);
 
         println!("login success");
         return JSONResponse::new_ok().to_ok()
     }
 
-    JSONResponse::new_error("Bad login").to_ok()
+    state.login_limiter.record_failure(&keys).await?;
+    Err(ApiError::NotLoggedIn("Bad login".to_string()))
 }
 
 // Logs out a user and clears their session (if they are actually logged in)
 #[get("/logout/")]
-async fn logout(id: Identity, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    if validate_identity(&id, &state).await {
-        // Forget identity and clear session
+async fn logout(id: Identity, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if validate_identity(&id, &state).await? {
+        // End the server-side session, forget identity and clear session
+        state.db.delete_session(&id.identity().unwrap()).await?;
         id.forget();
         session.clear();
         return JSONResponse::new_ok().to_ok()
     }
-    JSONResponse::new_error("Not logged in").to_ok()
+    Err(ApiError::NotLoggedIn("Not logged in".to_string()))
 }
 
 // Registers a new user if username does not exist and we are not currently logged in
 #[post("/register/")]
-async fn register(id: Identity, json_login: web::Json<LoginJSONIn>, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    if validate_identity(&id, &state).await {
-        return JSONResponse::new_error("Already logged in").to_ok();
+async fn register(id: Identity, req: HttpRequest, json_login: web::Json<LoginJSONIn>, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if validate_identity(&id, &state).await? {
+        return Err(ApiError::Conflict("Already logged in".to_string()));
     }
 
     // Do not create a new user if this username exists in the db
-    if state.db.is_user(&json_login.username).await {
-        return JSONResponse::new_error("User already exists").to_ok();
+    if state.db.is_user(&json_login.username).await? {
+        return Err(ApiError::Conflict("User already exists".to_string()));
     } 
 
-    let res = state.db.add_user(&json_login.username, &json_login.password).await;
-    let qr_code = res.1; // Only retrievable one-time during user creation
+    state.db.password_policy.check(&json_login.password).map_err(ApiError::ValidationFailed)?;
+
+    let res = state.db.add_user(&json_login.username, &json_login.password).await?;
+    let qr_code = res.1.qr_code; // Only retrievable one-time during user creation
+    let recovery_codes = state.db.add_recovery_codes(res.0).await?; // Also one-time
 
-    id.remember(json_login.username.to_owned());
+    start_session(&id, &req, &state, res.0).await?;
 
     set_session(&session, false);
 
     Ok(HttpResponse::Ok().json(QrResp {
         status:         "OK".to_string(),
         qr_code,
+        recovery_codes,
     }))
 }
 
-// Verifies the TOTP provided and sets the totp_verified for the user on success
-// Only used once, after /register/
+// Verifies the TOTP provided and marks this session as verified on success
+// Used after /register/, or whenever an action is rejected with totp_required
 #[post("/totp/")]
-async fn check_totp(id: Identity, json_login: web::Json<LoginTOTPReq>, state: web::Data<AppState>, session: Session) -> Result<HttpResponse, Error> {
+async fn check_totp(id: Identity, req: HttpRequest, json_login: web::Json<LoginTOTPReq>, state: web::Data<AppState>, session: Session) -> Result<HttpResponse, ApiError> {
 
-    let username = get_this_username(&id);
+    let username = get_this_username(&id, &state).await?;
 
     if username.is_none() {
-        return JSONResponse::new_error("Not logged in").to_ok();
+        return Err(ApiError::NotLoggedIn("Not logged in".to_string()));
     }
 
     let username = username.unwrap();
     let totp_code = json_login.totp_code.as_ref();
+    let keys = [state.login_limiter.ip_key(&req), LoginLimiter::account_key(&username)];
+
+    if let Some(retry_after) = state.login_limiter.check(&keys).await? {
+        return Err(LoginLimiter::locked_out(retry_after));
+    }
+
+    if state.db.is_user_totp(&username, &totp_code).await? {
+        state.login_limiter.record_success(&keys).await?;
+        if !state.db.is_user_totp_verified(&username).await? {
+            state.db.verified_totp(&username).await?;
+        }
+        set_session(&session, true);
+        return JSONResponse::new_ok().to_ok();
+    }
+
+    state.login_limiter.record_failure(&keys).await?;
+    Err(ApiError::NotLoggedIn("Invalid TOTP".to_string()))
+}
+
+// Replaces a lost TOTP device: password + a recovery code gets a new secret and recovery codes
+// Every existing session and API token is ended, the new session must verify the new secret with /totp/
+#[post("/totp/reset/")]
+async fn reset_totp(id: Identity, req: HttpRequest, json: web::Json<TOTPResetReq>, session: Session, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let keys = [state.login_limiter.ip_key(&req), LoginLimiter::account_key(&json.username)];
+
+    if let Some(retry_after) = state.login_limiter.check(&keys).await? {
+        return Err(LoginLimiter::locked_out(retry_after));
+    }
+
+    let user_id = state.db.check_user_password(&json.username, &json.password).await?;
+
+    if user_id == -1 || !state.db.use_recovery_code(user_id, &json.recovery_code).await? {
+        state.login_limiter.record_failure(&keys).await?;
+        return Err(ApiError::NotLoggedIn("Bad login".to_string()));
+    }
+
+    state.login_limiter.record_success(&keys).await?;
+
+    state.db.delete_user_sessions(user_id).await?;
+    state.db.delete_user_api_tokens(user_id).await?;
+
+    let qr_code = state.db.reset_user_totp(user_id, &json.username).await?.qr_code;
+    let recovery_codes = state.db.add_recovery_codes(user_id).await?;
+
+    start_session(&id, &req, &state, user_id).await?;
+    set_session(&session, false);
+
+    Ok(HttpResponse::Ok().json(QrResp {
+        status:         "OK".to_string(),
+        qr_code,
+        recovery_codes,
+    }))
+}
+
+#[derive(Serialize)]
+struct SessionsResp {
+    status: String,
+    current_id: i64,
+    sessions: Vec<SessionInfo>,
+}
+
+#[derive(Deserialize)]
+struct RevokeSessionReq {
+    id: Option<i64>,
+    all_others: Option<bool>,
+}
+
+// Lists the logged in user's sessions, current_id is the one making this request
+#[get("/sessions/")]
+async fn get_sessions(id: Identity, user: AuthUser, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let user_id = user.user_id;
+
+    let current_id = state.db.get_session_id(&id.identity().unwrap()).await?.unwrap_or(-1);
 
-    if state.db.is_user_totp(&username, &totp_code).await {
-        if !state.db.is_user_totp_verified(&username).await {
-            state.db.verified_totp(&username).await;
-            set_session(&session, true);
+    Ok(HttpResponse::Ok().json(SessionsResp {
+        status:         "OK".to_string(),
+        current_id,
+        sessions:       state.db.get_user_sessions(user_id).await?,
+    }))
+}
+
+// Revokes one of the logged in user's sessions by id, or all but the current one
+#[post("/sessions/revoke/")]
+async fn revoke_session(id: Identity, user: AuthUser, json: web::Json<RevokeSessionReq>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let user_id = user.user_id;
+
+    if json.all_others.unwrap_or(false) {
+        let current_id = state.db.get_session_id(&id.identity().unwrap()).await?.unwrap_or(-1);
+
+        for session in state.db.get_user_sessions(user_id).await? {
+            if session.id != current_id {
+                state.db.delete_user_session(user_id, session.id).await?;
+            }
         }
+
         return JSONResponse::new_ok().to_ok();
     }
 
-    JSONResponse::new_error("Invalid TOTP").to_ok()
+    if json.id.is_none() || !state.db.delete_user_session(user_id, json.id.unwrap()).await? {
+        return Err(ApiError::NotFound("No such session".to_string()));
+    }
+
+    JSONResponse::new_ok().to_ok()
 }
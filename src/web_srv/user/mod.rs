@@ -1 +1,4 @@
+pub mod guard;
+pub mod limiter;
 pub mod login;
+pub mod tokens;
@@ -0,0 +1,100 @@
+use actix_web::{get, post, web, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::db::api_tokens::ApiTokenInfo;
+use crate::db::permissions::Permission;
+use crate::web_srv::error::ApiError;
+use crate::web_srv::response::JSONResponse;
+use crate::web_srv::user::guard::AuthUser;
+use crate::web_srv::AppState;
+
+// Longest a token can be made to last
+const MAX_TOKEN_DAYS: i64 = 365;
+
+#[derive(Deserialize)]
+struct AddApiTokenReq {
+    name:               String,
+    scopes:             Vec<String>,
+    expires_in_days:    Option<i64>, // Never expires if not set
+}
+
+#[derive(Serialize)]
+struct AddApiTokenResp {
+    status: String,
+    id: i64,
+    token: String, // Only retrievable here
+}
+
+#[derive(Serialize)]
+struct ApiTokensResp {
+    status: String,
+    tokens: Vec<ApiTokenInfo>,
+}
+
+#[derive(Deserialize)]
+struct RevokeApiTokenReq {
+    id: i64,
+}
+
+// Lists the logged in user's API tokens
+#[get("/tokens/")]
+async fn get_api_tokens(user: AuthUser, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(ApiTokensResp {
+        status: "OK".to_string(),
+        tokens: state.db.get_user_api_tokens(user.user_id).await?,
+    }))
+}
+
+// Creates a named API token limited to scopes, which must be permissions the user has
+#[post("/tokens/")]
+async fn add_api_token(user: AuthUser, json: web::Json<AddApiTokenReq>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if json.name.trim() == "" {
+        return Err(ApiError::ValidationFailed("token must have a name".to_string()));
+    }
+
+    if json.scopes.is_empty() {
+        return Err(ApiError::ValidationFailed("token must have at least one scope".to_string()));
+    }
+
+    let mut scopes = Vec::new();
+
+    for scope in &json.scopes {
+        let scope = match scope.parse::<Permission>() {
+            Ok(scope) => scope,
+            Err(e) => return Err(ApiError::ValidationFailed(e)),
+        };
+
+        if !user.has_permission(scope) {
+            return Err(ApiError::Forbidden(format!("you do not have permission '{}'", scope)));
+        }
+
+        scopes.push(scope);
+    }
+
+    let expires_date = match json.expires_in_days {
+        Some(days) if days < 1 || days > MAX_TOKEN_DAYS => {
+            return Err(ApiError::ValidationFailed(format!("expires_in_days must be 1 to {}", MAX_TOKEN_DAYS)));
+        }
+        Some(days) => Utc::now().timestamp() + days * 24 * 60 * 60,
+        None => -1,
+    };
+
+    let (id, token) = state.db.add_api_token(user.user_id, json.name.trim(), &scopes, expires_date).await?;
+
+    Ok(HttpResponse::Ok().json(AddApiTokenResp {
+        status: "OK".to_string(),
+        id,
+        token,
+    }))
+}
+
+// Revokes one of the logged in user's API tokens by id
+#[post("/tokens/revoke/")]
+async fn revoke_api_token(user: AuthUser, json: web::Json<RevokeApiTokenReq>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if !state.db.delete_user_api_token(user.user_id, json.id).await? {
+        return Err(ApiError::NotFound("No such token".to_string()));
+    }
+
+    JSONResponse::new_ok().to_ok()
+}